@@ -0,0 +1,38 @@
+use cc_sdk::{StreamExt, client::ClaudeClient, types::ClaudeCodeOptions};
+use tracing::Level;
+use tracing_subscriber::FmtSubscriber;
+
+fn set_tracing() {
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    set_tracing();
+    let options = ClaudeCodeOptions {
+        cwd: Some(std::env::current_dir()?),
+        ..Default::default()
+    };
+    let mut client = ClaudeClient::connect(options).await?;
+
+    let prompts = [
+        "What is th answer to the Ultimate Question of Life, the Universe, and Everything",
+        "What is the source of your answer?",
+    ];
+    for prompt in prompts {
+        client.send(prompt)?;
+        let mut response = client.receive_response();
+        while let Some(msg) = response.next().await {
+            println!("{}", serde_json::to_string_pretty(&msg?)?);
+        }
+    }
+
+    client.disconnect().await?;
+    println!("chat done");
+
+    Ok(())
+}
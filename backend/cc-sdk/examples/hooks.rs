@@ -0,0 +1,119 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    task::{Poll, ready},
+};
+
+use cc_sdk::{
+    cli::PromptGenerator,
+    query,
+    types::{
+        APIUserMessage, ClaudeCodeOptions, HookCallbackMatcher, HookEvent, HookEventInput,
+        HookOutput, HookPermissionDecision, SDKMessageTyped, SDKUserMessage, ToolUseParams,
+    },
+};
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+use tokio_stream::StreamExt;
+use tracing::Level;
+use tracing_subscriber::FmtSubscriber;
+
+fn cwd() -> PathBuf {
+    std::env::current_dir().unwrap()
+}
+
+fn hooks() -> HashMap<HookEvent, Vec<HookCallbackMatcher>> {
+    let pre_tool_use = HookCallbackMatcher::new(Some("Bash")).hook_fn(|input, _| async move {
+        if let Some(ToolUseParams::Bash { input }) = input.event.tool_use() {
+            if input.command.contains("rm ") {
+                return Ok(HookOutput::permission(
+                    HookPermissionDecision::Deny,
+                    "rm is not allowed in this project",
+                ));
+            }
+        }
+        Ok(HookOutput::pass())
+    });
+
+    let post_tool_use = HookCallbackMatcher::new(None).hook_fn(|input, tool_use_id| async move {
+        if let HookEventInput::PostToolUse { tool_name, .. } = &input.event {
+            println!("AUDIT: tool {tool_name} finished, tool_use_id: {tool_use_id:?}");
+        }
+        Ok(HookOutput::pass())
+    });
+
+    HashMap::from([
+        (HookEvent::PreToolUse, vec![pre_tool_use]),
+        (HookEvent::PostToolUse, vec![post_tool_use]),
+    ])
+}
+
+fn options() -> ClaudeCodeOptions {
+    ClaudeCodeOptions {
+        cwd: Some(cwd()),
+        hooks: Some(hooks()),
+        max_turns: Some(10),
+        ..Default::default()
+    }
+}
+
+fn set_tracing() {
+    // a builder for `FmtSubscriber`.
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::DEBUG)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+}
+
+struct PromptGen {
+    receiver: UnboundedReceiver<String>,
+}
+
+impl PromptGenerator for PromptGen {
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<SDKUserMessage>> {
+        let prompt = ready!(self.get_mut().receiver.poll_recv(cx));
+        let prompt = match prompt {
+            Some(p) => p,
+            None => return Poll::Ready(None),
+        };
+        let msg = SDKUserMessage {
+            uuid: None,
+            message: APIUserMessage {
+                content: Arc::new(prompt.into()),
+                role: cc_sdk::types::APIUserMessageRole::User,
+            },
+            parent_tool_use_id: None,
+        };
+
+        Poll::Ready(Some(msg))
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    set_tracing();
+    let (tx, rx) = unbounded_channel();
+    let prompt = PromptGen { receiver: rx };
+
+    let mut claude = query(prompt, options()).await?;
+
+    let prompt = "Create a file named test-output.txt with bash, then remove it with rm.";
+    tx.send(prompt.to_owned()).unwrap();
+
+    while let Some(msg) = claude.next().await {
+        let msg = msg.unwrap();
+        println!("{}", serde_json::to_string_pretty(&msg).unwrap());
+
+        if matches!(msg.typed, SDKMessageTyped::Result(..)) {
+            break;
+        }
+    }
+
+    println!("chat done");
+
+    Ok(())
+}
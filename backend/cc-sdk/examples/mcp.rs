@@ -0,0 +1,115 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    task::{Poll, ready},
+};
+
+use cc_sdk::{
+    cli::PromptGenerator,
+    mcp::{CallToolResult, SdkMcpServer, SdkMcpTool},
+    query,
+    types::{APIUserMessage, ClaudeCodeOptions, SDKMessageTyped, SDKUserMessage},
+};
+use serde_json::{Value, json};
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+use tokio_stream::StreamExt;
+use tracing::Level;
+use tracing_subscriber::FmtSubscriber;
+
+fn cwd() -> PathBuf {
+    std::env::current_dir().unwrap()
+}
+
+fn niu_server() -> SdkMcpServer {
+    let add = SdkMcpTool::new(
+        "add",
+        "Add two numbers",
+        json!({
+            "type": "object",
+            "properties": {
+                "a": { "type": "number" },
+                "b": { "type": "number" }
+            },
+            "required": ["a", "b"]
+        }),
+        |args: Value| async move {
+            let a = args["a"].as_f64().unwrap_or_default();
+            let b = args["b"].as_f64().unwrap_or_default();
+            Ok(CallToolResult::text(format!("{}", a + b)))
+        },
+    );
+
+    SdkMcpServer::new("niu", "0.1.0").tool(add)
+}
+
+fn options() -> ClaudeCodeOptions {
+    ClaudeCodeOptions {
+        cwd: Some(cwd()),
+        mcp_servers: Some(HashMap::from([("niu".to_string(), niu_server().into())])),
+        allowed_tools: Some(vec!["mcp__niu__add".to_string()]),
+        max_turns: Some(10),
+        ..Default::default()
+    }
+}
+
+fn set_tracing() {
+    // a builder for `FmtSubscriber`.
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::DEBUG)
+        .finish();
+
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+}
+
+struct PromptGen {
+    receiver: UnboundedReceiver<String>,
+}
+
+impl PromptGenerator for PromptGen {
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<SDKUserMessage>> {
+        let prompt = ready!(self.get_mut().receiver.poll_recv(cx));
+        let prompt = match prompt {
+            Some(p) => p,
+            None => return Poll::Ready(None),
+        };
+        let msg = SDKUserMessage {
+            uuid: None,
+            message: APIUserMessage {
+                content: Arc::new(prompt.into()),
+                role: cc_sdk::types::APIUserMessageRole::User,
+            },
+            parent_tool_use_id: None,
+        };
+
+        Poll::Ready(Some(msg))
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    set_tracing();
+    let (tx, rx) = unbounded_channel();
+    let prompt = PromptGen { receiver: rx };
+
+    let mut claude = query(prompt, options()).await?;
+
+    let prompt = "Use the add tool to calculate 1234 + 4321.";
+    tx.send(prompt.to_owned()).unwrap();
+
+    while let Some(msg) = claude.next().await {
+        let msg = msg.unwrap();
+        println!("{}", serde_json::to_string_pretty(&msg).unwrap());
+
+        if matches!(msg.typed, SDKMessageTyped::Result(..)) {
+            break;
+        }
+    }
+
+    println!("chat done");
+
+    Ok(())
+}
@@ -31,11 +31,14 @@ impl DebugCallBack for StderrCallBack {
 fn options() -> ClaudeCodeOptions {
     ClaudeCodeOptions {
         abort_controller: None,
+        abort_grace_period: None,
         additional_directories: None,
         allowed_tools: None,
         append_system_prompt: None,
         can_use_tool: None,
+        config_dir: None,
         r#continue: None,
+        control_request_timeout: None,
         custom_system_prompt: None,
         cwd: Some(cwd()),
         disallowed_tools: None,
@@ -53,16 +56,26 @@ fn options() -> ClaudeCodeOptions {
         executable_args: None,
         extra_args: None,
         fallback_model: None,
+        fork_session: None,
         hooks: None,
         include_partial_messages: None,
+        init_timeout: None,
+        max_budget_usd: None,
         max_thinking_tokens: None,
+        max_total_tokens: None,
         max_turns: Some(100),
         mcp_servers: None,
         model: None,
+        output_capacity: None,
+        parse_mode: None,
         path_to_claude_code_executable: Some("claude".into()),
         permission_mode: None,
         permission_prompt_tool_name: None,
         resume: None,
+        resume_session_at: None,
+        runtime_preference: None,
+        settings: None,
+        shutdown_timeout: None,
         stderr: Some(Box::new(StderrCallBack {})),
         strict_mcp_config: None,
     }
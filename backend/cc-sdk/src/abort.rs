@@ -0,0 +1,57 @@
+use tokio_util::sync::CancellationToken;
+
+/// Cancellation handle of a query, like `AbortController` in the typescript sdk.
+///
+/// Clones share the same state, so it can be handed to other tasks. Aborting interrupts the
+/// current turn, kills the CLI after a grace period and ends the stream with
+/// [`ClaudeStreamError::Aborted`](crate::cli::ClaudeStreamError::Aborted).
+#[derive(Debug, Clone, Default)]
+pub struct AbortController {
+    token: CancellationToken,
+}
+
+impl AbortController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn abort(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Completes when the controller is aborted.
+    pub async fn aborted(&self) {
+        self.token.cancelled().await
+    }
+
+    /// A controller that is aborted together with this one, but can also be aborted alone.
+    pub fn child(&self) -> Self {
+        Self {
+            token: self.token.child_token(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_child_abort() {
+        let parent = AbortController::new();
+        let child = parent.child();
+        let other = parent.child();
+
+        child.abort();
+        assert!(!parent.is_aborted());
+        assert!(!other.is_aborted());
+
+        parent.clone().abort();
+        assert!(parent.is_aborted());
+        assert!(other.is_aborted());
+    }
+}
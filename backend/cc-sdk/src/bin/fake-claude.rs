@@ -0,0 +1,12 @@
+//! Scripted stand-in of the Claude Code CLI, see `cc_sdk::testing`.
+
+#[tokio::main]
+async fn main() {
+    match cc_sdk::testing::run_fake_cli().await {
+        Ok(code) => std::process::exit(code),
+        Err(err) => {
+            eprintln!("fake-claude: {err:?}");
+            std::process::exit(2);
+        }
+    }
+}
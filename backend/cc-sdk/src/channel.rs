@@ -0,0 +1,299 @@
+//! Message queues that are either bounded or unbounded, with queue depth metrics.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicIsize, AtomicU64, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+};
+
+use serde::Serialize;
+use tokio::sync::mpsc::{
+    self,
+    error::{SendError, TrySendError},
+};
+
+/// Creates a queue. It's unbounded if `capacity` is `None`.
+///
+/// # Panics
+///
+/// Panics if `capacity` is `Some(0)`.
+pub fn channel<T>(capacity: Option<usize>) -> (QueueSender<T>, QueueReceiver<T>) {
+    let metrics = QueueMetrics::new(capacity);
+    let (tx, rx) = match capacity {
+        Some(capacity) => {
+            let (tx, rx) = mpsc::channel(capacity);
+            (SenderInner::Bounded(tx), ReceiverInner::Bounded(rx))
+        }
+        None => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            (SenderInner::Unbounded(tx), ReceiverInner::Unbounded(rx))
+        }
+    };
+
+    let tx = QueueSender {
+        inner: tx,
+        metrics: metrics.clone(),
+    };
+    let rx = QueueReceiver { inner: rx, metrics };
+    (tx, rx)
+}
+
+pub struct QueueSender<T> {
+    inner: SenderInner<T>,
+    metrics: QueueMetrics,
+}
+
+enum SenderInner<T> {
+    Bounded(mpsc::Sender<T>),
+    Unbounded(mpsc::UnboundedSender<T>),
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        let inner = match &self.inner {
+            SenderInner::Bounded(tx) => SenderInner::Bounded(tx.clone()),
+            SenderInner::Unbounded(tx) => SenderInner::Unbounded(tx.clone()),
+        };
+        Self {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl<T> QueueSender<T> {
+    /// Waits for room if the queue is bounded and full.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match &self.inner {
+            SenderInner::Bounded(tx) => tx.send(value).await?,
+            SenderInner::Unbounded(tx) => tx.send(value)?,
+        }
+        self.metrics.on_send();
+        Ok(())
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match &self.inner {
+            SenderInner::Bounded(tx) => tx.try_send(value)?,
+            SenderInner::Unbounded(tx) => {
+                tx.send(value).map_err(|err| TrySendError::Closed(err.0))?
+            }
+        }
+        self.metrics.on_send();
+        Ok(())
+    }
+
+    /// Waits for room if the queue is bounded and full. Unlike [`send`](Self::send), it's
+    /// cancel safe, as no value is given up while waiting.
+    pub async fn reserve(&self) -> Result<Permit<'_, T>, SendError<()>> {
+        let inner = match &self.inner {
+            SenderInner::Bounded(tx) => PermitInner::Bounded(tx.reserve().await?),
+            SenderInner::Unbounded(tx) if tx.is_closed() => return Err(SendError(())),
+            SenderInner::Unbounded(tx) => PermitInner::Unbounded(tx),
+        };
+        Ok(Permit {
+            inner,
+            metrics: &self.metrics,
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        match &self.inner {
+            SenderInner::Bounded(tx) => tx.is_closed(),
+            SenderInner::Unbounded(tx) => tx.is_closed(),
+        }
+    }
+
+    pub fn metrics(&self) -> &QueueMetrics {
+        &self.metrics
+    }
+}
+
+/// Room in a queue, returned by [`QueueSender::reserve`].
+pub struct Permit<'a, T> {
+    inner: PermitInner<'a, T>,
+    metrics: &'a QueueMetrics,
+}
+
+enum PermitInner<'a, T> {
+    Bounded(mpsc::Permit<'a, T>),
+    Unbounded(&'a mpsc::UnboundedSender<T>),
+}
+
+impl<T> Permit<'_, T> {
+    pub fn send(self, value: T) {
+        match self.inner {
+            PermitInner::Bounded(permit) => permit.send(value),
+            // The receiver may have been dropped since, which drops the value like `send` would
+            PermitInner::Unbounded(tx) => {
+                let _ = tx.send(value);
+            }
+        }
+        self.metrics.on_send();
+    }
+}
+
+pub struct QueueReceiver<T> {
+    inner: ReceiverInner<T>,
+    metrics: QueueMetrics,
+}
+
+enum ReceiverInner<T> {
+    Bounded(mpsc::Receiver<T>),
+    Unbounded(mpsc::UnboundedReceiver<T>),
+}
+
+impl<T> QueueReceiver<T> {
+    pub async fn recv(&mut self) -> Option<T> {
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let poll = match &mut self.inner {
+            ReceiverInner::Bounded(rx) => rx.poll_recv(cx),
+            ReceiverInner::Unbounded(rx) => rx.poll_recv(cx),
+        };
+        if let Poll::Ready(Some(_)) = &poll {
+            self.metrics.on_recv();
+        }
+        poll
+    }
+
+    pub fn metrics(&self) -> &QueueMetrics {
+        &self.metrics
+    }
+}
+
+/// Shared counters of a queue, updated by both ends.
+#[derive(Debug, Clone)]
+pub struct QueueMetrics {
+    inner: Arc<MetricsInner>,
+}
+
+#[derive(Debug)]
+struct MetricsInner {
+    capacity: Option<usize>,
+    // The two ends don't update it atomically with the queue, so it may be off by one for a
+    // moment. It's clamped to `0..=capacity` when read.
+    depth: AtomicIsize,
+    high_water_mark: AtomicUsize,
+    total: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct QueueStats {
+    /// `None` if the queue is unbounded.
+    pub capacity: Option<usize>,
+    /// Messages waiting to be received.
+    pub depth: usize,
+    /// The largest depth seen.
+    pub high_water_mark: usize,
+    /// Messages sent in total.
+    pub total: u64,
+}
+
+impl QueueMetrics {
+    fn new(capacity: Option<usize>) -> Self {
+        Self {
+            inner: Arc::new(MetricsInner {
+                capacity,
+                depth: AtomicIsize::new(0),
+                high_water_mark: AtomicUsize::new(0),
+                total: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    fn on_send(&self) {
+        let depth = self.inner.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.inner
+            .high_water_mark
+            .fetch_max(self.clamp(depth), Ordering::Relaxed);
+        self.inner.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn clamp(&self, depth: isize) -> usize {
+        let depth = depth.max(0) as usize;
+        match self.inner.capacity {
+            Some(capacity) => depth.min(capacity),
+            None => depth,
+        }
+    }
+
+    fn on_recv(&self) {
+        self.inner.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            capacity: self.inner.capacity,
+            depth: self.clamp(self.inner.depth.load(Ordering::Relaxed)),
+            high_water_mark: self.inner.high_water_mark.load(Ordering::Relaxed),
+            total: self.inner.total.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bounded_metrics() {
+        let (tx, mut rx) = channel(Some(2));
+        tx.send(1).await.unwrap();
+        tx.try_send(2).unwrap();
+        assert!(matches!(tx.try_send(3), Err(TrySendError::Full(3))));
+        assert_eq!(
+            tx.metrics().stats(),
+            QueueStats {
+                capacity: Some(2),
+                depth: 2,
+                high_water_mark: 2,
+                total: 2,
+            }
+        );
+
+        assert_eq!(rx.recv().await, Some(1));
+        tx.reserve().await.unwrap().send(3);
+        assert_eq!(rx.recv().await, Some(2));
+        let stats = rx.metrics().stats();
+        assert_eq!(stats.depth, 1);
+        assert_eq!(stats.high_water_mark, 2);
+        assert_eq!(stats.total, 3);
+
+        drop(rx);
+        assert!(tx.is_closed());
+        assert!(tx.send(4).await.is_err());
+        assert!(tx.reserve().await.is_err());
+        assert_eq!(tx.metrics().stats().total, 3);
+    }
+
+    #[tokio::test]
+    async fn test_unbounded_metrics() {
+        let (tx, mut rx) = channel(None);
+        for i in 0..5 {
+            tx.try_send(i).unwrap();
+        }
+        assert_eq!(
+            rx.metrics().stats(),
+            QueueStats {
+                capacity: None,
+                depth: 5,
+                high_water_mark: 5,
+                total: 5,
+            }
+        );
+
+        for i in 0..5 {
+            assert_eq!(rx.recv().await, Some(i));
+        }
+        tx.send(5).await.unwrap();
+        let stats = tx.metrics().stats();
+        assert_eq!(stats.depth, 1);
+        assert_eq!(stats.high_water_mark, 5);
+        assert_eq!(stats.total, 6);
+    }
+}
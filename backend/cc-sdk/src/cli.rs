@@ -1,15 +1,16 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     ffi::OsStr,
     fmt::Debug,
     ops::ControlFlow,
-    path::{Path, PathBuf},
     pin::Pin,
-    process::Stdio,
-    task::{Context, Poll},
+    process::{ExitStatus, Stdio},
+    sync::Arc,
+    task::{Context, Poll, ready},
+    time::Duration,
 };
 
-use anyhow::{Context as _, Result, bail};
+use anyhow::{Context as _, Result, anyhow, bail};
 use derive_more::Display;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
@@ -18,18 +19,31 @@ use tokio::{
     process::{Child, ChildStdin, Command},
     select,
     sync::{
-        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+        Mutex,
+        mpsc::{UnboundedReceiver, UnboundedSender, error::TrySendError, unbounded_channel},
         oneshot, watch,
     },
+    task::{AbortHandle, JoinHandle},
 };
 use tokio_stream::{Stream, StreamExt};
-use tokio_util::codec::{FramedRead, LinesCodec};
+use tokio_util::{
+    codec::{FramedRead, LinesCodec},
+    sync::CancellationToken,
+};
 use tracing::{debug, error, info, warn};
-use which::which;
 
-use crate::types::{
-    BoxedCanUseTollCallback, ClaudeCodeOptions, DebugCallBack, Executable, PermissionMode,
-    PermissionUpdate, SDKMessage, SDKUserMessage, ToolUseParams, UntaggedToolUseParams,
+use crate::{
+    abort::AbortController,
+    channel::{self, QueueReceiver, QueueSender, QueueStats},
+    cost::{BudgetExceeded, BudgetGuard, unreadable_result},
+    mcp::{McpServerConfig, SdkMcpServer},
+    runtime::{ClaudeLauncher, ClaudeVersion, SUPPORTED_CLAUDE_VERSIONS, find_claude_launcher},
+    types::{
+        BoxedCanUseTollCallback, BoxedHookCallBack, ClaudeCodeOptions, DebugCallBack,
+        HookCallbackMatcher, HookEvent, HookInput, MCPServerConnectionStatus, MCPServerStatus,
+        ParseMode, PermissionMode, PermissionUpdate, SDKMessage, SDKMessageTyped, SDKSystemMessage,
+        SDKUserMessage, ToolUseParams,
+    },
 };
 
 pub trait PromptGenerator: Send + Unpin + 'static {
@@ -113,19 +127,94 @@ impl From<String> for Prompt {
 pub type QueryStreamItem = Result<SDKMessage, ClaudeStreamError>;
 
 pub struct QueryStream {
-    receiver: UnboundedReceiver<QueryStreamItem>,
-    writer_chan: Option<UnboundedSender<ClaudeWriterMessage>>,
+    receiver: QueueReceiver<QueryStreamItem>,
+    controller: QueryController,
     claude_sys_info: Option<ClaudeSysInfo>,
     session_id: Option<String>,
+    mcp_status: watch::Receiver<Option<Vec<MCPServerStatus>>>,
+    control_events: Option<UnboundedReceiver<ControlEvent>>,
+    abort_controller: AbortController,
+    shutdown: CancellationToken,
+    process_exit: watch::Receiver<Option<ProcessExit>>,
     stop_notify: StopNotify,
 }
 
+tokio::task_local! {
+    static CONTROL_REQUEST_ID: String;
+}
+
+/// ID of the control request being answered, when called from a `can_use_tool` or hook
+/// callback. It's the `request_id` of [`ControlEvent::RequestCancelled`] if the CLI withdraws it.
+pub fn control_request_id() -> Option<String> {
+    CONTROL_REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+#[derive(Debug, Clone)]
+pub enum ControlEvent {
+    /// The CLI withdrew a control request, e.g. a permission prompt after an interrupt.
+    /// The pending callback future has been dropped.
+    RequestCancelled {
+        request_id: String,
+        kind: ControlRequestKind,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlRequestKind {
+    CanUseTool,
+    HookCallback,
+    McpMessage,
+}
+
+pub const DEFAULT_INIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Why the `initialize` handshake of streaming mode failed. The CLI is killed.
+/// [`QueryStream::new`] returns it in an [`anyhow::Error`], get it back with `downcast_ref`.
+#[derive(Display, Debug)]
+pub enum InitError {
+    #[display("Claude didn't answer initialize in {timeout:?}. stderr: {stderr_tail}")]
+    Timeout {
+        timeout: Duration,
+        stderr_tail: String,
+    },
+    #[display(
+        "Claude exited during initialize (code: {code:?}, signal: {signal:?}). stderr: {stderr_tail}"
+    )]
+    ProcessExited {
+        code: Option<i32>,
+        signal: Option<i32>,
+        stderr_tail: String,
+    },
+    /// The CLI answered with an error, or with a response this crate doesn't understand.
+    #[display("Claude initialize failed: {error}. stderr: {stderr_tail}")]
+    Failed { error: String, stderr_tail: String },
+    /// The [`AbortController`] was aborted before the CLI answered.
+    #[display("Aborted during initialize")]
+    Aborted,
+}
+
+impl std::error::Error for InitError {}
+
 #[derive(Display, Debug)]
 pub enum ClaudeStreamError {
     CannotDeserialize(String),
     CannotWriteToClaude(String),
+    /// The query was cancelled through its [`AbortController`].
+    Aborted,
+    /// The query went over `max_budget_usd` or `max_total_tokens` and was interrupted.
+    BudgetExceeded(BudgetExceeded),
+    /// The CLI process died, e.g. a bad API key, a crash or killed by the OOM killer.
+    #[display("Claude exited unexpectedly (code: {code:?}, signal: {signal:?}): {stderr_tail}")]
+    ProcessExited {
+        code: Option<i32>,
+        signal: Option<i32>,
+        /// The last lines the CLI wrote to stderr.
+        stderr_tail: String,
+    },
 }
 
+impl std::error::Error for ClaudeStreamError {}
+
 impl Stream for QueryStream {
     type Item = QueryStreamItem;
 
@@ -133,14 +222,30 @@ impl Stream for QueryStream {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.receiver.poll_recv(cx)
+        let item = ready!(self.receiver.poll_recv(cx));
+        if let Some(Ok(msg)) = &item
+            && !msg.session_id.is_empty()
+            && self.session_id.as_deref() != Some(msg.session_id.as_str())
+        {
+            self.session_id = Some(msg.session_id.clone());
+        }
+        Poll::Ready(item)
     }
 }
 
 struct ClaudeReader {
     claude_stream: FramedRead<tokio::process::ChildStdout, LinesCodec>,
     ctrl_chan: UnboundedSender<ControlMessage>,
-    output_chan: UnboundedSender<QueryStreamItem>,
+    output_chan: QueueSender<QueryStreamItem>,
+    /// Messages read while the output queue is full, so that control lines behind them are
+    /// still routed. Reading stdout pauses once it holds `pending_limit` messages.
+    pending: VecDeque<QueryStreamItem>,
+    pending_limit: usize,
+    mcp_status: watch::Sender<Option<Vec<MCPServerStatus>>>,
+    parse_mode: ParseMode,
+    process_exit: watch::Receiver<Option<ProcessExit>>,
+    budget: Option<BudgetGuard>,
+    writer_chan: Option<UnboundedSender<ClaudeWriterMessage>>,
     stop_notify: StopNotify,
 }
 
@@ -154,22 +259,64 @@ struct ClaudeWriter {
 struct ControlHandler {
     receiver: UnboundedReceiver<ControlMessage>,
     wirter_chan: Option<UnboundedSender<ClaudeWriterMessage>>,
-    can_use_cb: Option<BoxedCanUseTollCallback>,
+    callbacks: ControlCallbacks,
+    inflight: HashMap<String, InflightRequest>,
+    done_tx: UnboundedSender<ControlRequestDone>,
+    done_rx: UnboundedReceiver<ControlRequestDone>,
+    event_chan: UnboundedSender<ControlEvent>,
     resp_chans: HashMap<String, oneshot::Sender<Value>>,
     stop_notify: StopNotify,
 }
 
+#[derive(Clone, Default)]
+struct ControlCallbacks {
+    can_use_cb: Option<Arc<Mutex<BoxedCanUseTollCallback>>>,
+    hook_callbacks: Arc<HookCallbacks>,
+    sdk_mcp_servers: Arc<HashMap<String, Arc<SdkMcpServer>>>,
+}
+
+type HookCallbacks = HashMap<String, Arc<Mutex<BoxedHookCallBack>>>;
+
+struct InflightRequest {
+    kind: ControlRequestKind,
+    task: AbortHandle,
+}
+
+struct ControlRequestDone {
+    request_id: String,
+    result: Result<Value>,
+}
+
 pub enum ClaudeWriterMessage {
     Write(Value),
+    /// Close stdin, which tells the CLI to exit once the current turn ends.
+    CloseStdin,
 }
 
 pub enum ControlMessage {
     ControlRequest(Value),
     ControlResponse(Value),
+    ControlCancelRequest(Value),
     RegisterResponseChan {
         id: String,
         chan: oneshot::Sender<Value>,
     },
+    /// The request was abandoned, e.g. after a timeout.
+    UnregisterResponseChan {
+        id: String,
+    },
+}
+
+/// Sends the first pending item once the queue has room. Cancel safe.
+async fn send_pending(
+    output_chan: &QueueSender<QueryStreamItem>,
+    pending: &mut VecDeque<QueryStreamItem>,
+) -> Result<()> {
+    let permit = output_chan.reserve().await.context("output chan closed")?;
+    if let Some(item) = pending.pop_front() {
+        permit.send(item);
+    }
+    Ok(())
 }
 
 impl ClaudeReader {
@@ -186,10 +333,15 @@ impl ClaudeReader {
     async fn run(mut self) -> Result<()> {
         loop {
             select! {
-                line = self.claude_stream.next() => {
-                    if self.handle_claude_msg(line)?.is_break() {
+                sent = send_pending(&self.output_chan, &mut self.pending), if !self.pending.is_empty() => {
+                    sent?;
+                }
+                line = self.claude_stream.next(), if self.pending.len() < self.pending_limit => {
+                    let Some(line) = line else {
+                        self.handle_eof().await?;
                         break;
-                    }
+                    };
+                    self.handle_claude_msg(line).await?;
                 }
                 Some(notify) = self.stop_notify.wait_notify() => {
                     self.handle_stop(notify).await?;
@@ -203,35 +355,69 @@ impl ClaudeReader {
 
     async fn handle_stop(&mut self, reason: StopReason) -> Result<()> {
         info!(?reason, "Claude Reader exiting...");
+        if !matches!(reason, StopReason::OutStreamDropped) {
+            self.flush_output().await?;
+        }
         match reason {
             StopReason::NoMoreClaudeMsg => unreachable!(),
             StopReason::InvalidClaudeOutput => unreachable!(),
             StopReason::ParseClaudeSDKMessage => unreachable!(),
             StopReason::User => {}
             StopReason::OutStreamDropped => {}
+            StopReason::InitFailed => {}
+            StopReason::BudgetExceeded => {}
+            StopReason::Aborted => {
+                self.output_chan
+                    .send(Err(ClaudeStreamError::Aborted))
+                    .await?;
+            }
             StopReason::CannotWriteToClaude(err) => {
                 self.output_chan
-                    .send(Err(ClaudeStreamError::CannotWriteToClaude(err)))?;
+                    .send(Err(ClaudeStreamError::CannotWriteToClaude(err)))
+                    .await?;
             }
             StopReason::ParseClaudeControlRequest(err) => {
                 self.output_chan
-                    .send(Err(ClaudeStreamError::CannotDeserialize(err)))?;
+                    .send(Err(ClaudeStreamError::CannotDeserialize(err)))
+                    .await?;
             }
         }
 
         Ok(())
     }
 
-    fn handle_claude_msg(
-        &mut self,
-        line: Option<std::result::Result<String, tokio_util::codec::LinesCodecError>>,
-    ) -> Result<ControlFlow<()>, anyhow::Error> {
-        let Some(line) = line else {
-            info!("claude exited. reader exiting...");
-            self.stop_notify.notify(StopReason::NoMoreClaudeMsg);
-            return Ok(ControlFlow::Break(()));
-        };
+    /// Stdout is closed, the CLI is exiting. Report how it exited if it's not a normal exit.
+    async fn handle_eof(&mut self) -> Result<()> {
+        info!("claude exited. reader exiting...");
+        self.flush_output().await?;
+        let process_exit = &mut self.process_exit;
+        let exit = tokio::time::timeout(EXIT_STATUS_TIMEOUT, async move {
+            let exit = process_exit.wait_for(Option::is_some).await.ok()?;
+            exit.clone()
+        })
+        .await;
+        match exit {
+            Ok(Some(exit)) if !exit.success && !exit.shutdown => {
+                self.output_chan
+                    .send(Err(ClaudeStreamError::ProcessExited {
+                        code: exit.code,
+                        signal: exit.signal,
+                        stderr_tail: exit.stderr_tail,
+                    }))
+                    .await?;
+            }
+            Ok(_) => {}
+            Err(_) => warn!("Claude closed stdout but didn't exit"),
+        }
+        self.stop_notify.notify(StopReason::NoMoreClaudeMsg);
 
+        Ok(())
+    }
+
+    async fn handle_claude_msg(
+        &mut self,
+        line: std::result::Result<String, tokio_util::codec::LinesCodecError>,
+    ) -> Result<()> {
         let line = line.context("Failed to read claude code stdout")?;
         debug!(msg = %line, "received CLAUDE output msg");
         let msg: Value = serde_json::from_str(&line)?;
@@ -250,36 +436,128 @@ impl ClaudeReader {
                 self.send_ctrl_req(msg);
             }
             "control_cancel_request" => {
-                warn!(msg = line, "control_cancel_request is unsupported");
+                self.send_ctrl_cancel(msg);
             }
             _ => {
-                self.send_out_message(msg)?;
+                self.send_out_message(msg).await?;
             }
         }
 
-        Ok(ControlFlow::Continue(()))
+        Ok(())
     }
 
-    fn send_out_message(&self, msg: Value) -> Result<()> {
+    async fn send_out_message(&mut self, msg: Value) -> Result<()> {
         debug!("send claude msg to output chan");
-        match serde_json::from_value(msg) {
+        match self.parse_message(msg) {
             Ok(msg) => {
-                self.output_chan
-                    .send(Ok(msg))
-                    .context("output chan closed")?;
+                self.record_mcp_status(&msg);
+                let exceeded = self
+                    .budget
+                    .as_mut()
+                    .and_then(|budget| budget.push_message(&msg));
+                self.queue_output(Ok(msg))?;
+                if let Some(exceeded) = exceeded {
+                    self.stop_for_budget(exceeded)?;
+                }
             }
-            Err(err) => {
+            Err(err) if self.parse_mode == ParseMode::Strict => {
                 self.stop_notify.notify(StopReason::ParseClaudeSDKMessage);
 
-                let msg = Err(ClaudeStreamError::CannotDeserialize(format!("{err}")));
-                self.output_chan.send(msg).context("output chan closed")?;
+                self.queue_output(Err(ClaudeStreamError::CannotDeserialize(err)))?;
+                self.flush_output().await?;
                 bail!("Parse ClaudeSDKMessage failed")
             }
+            Err(err) => {
+                warn!(%err, "Parse ClaudeSDKMessage failed, skip it");
+                self.queue_output(Err(ClaudeStreamError::CannotDeserialize(err)))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends to the output queue, or keeps the item in `pending` if the queue is full.
+    fn queue_output(&mut self, item: QueryStreamItem) -> Result<()> {
+        if !self.pending.is_empty() {
+            self.pending.push_back(item);
+            return Ok(());
+        }
+        match self.output_chan.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) => {
+                debug!("output chan is full, keep reading control messages");
+                self.pending.push_back(item);
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => bail!("output chan closed"),
+        }
+    }
+
+    /// Waits until all pending items are in the output queue.
+    async fn flush_output(&mut self) -> Result<()> {
+        while let Some(item) = self.pending.pop_front() {
+            self.output_chan
+                .send(item)
+                .await
+                .context("output chan closed")?;
+        }
+        Ok(())
+    }
+
+    fn parse_message(&self, raw: Value) -> Result<SDKMessage, String> {
+        let strict_raw = match self.parse_mode {
+            ParseMode::Strict => Some(raw.clone()),
+            ParseMode::Tolerant => None,
+        };
+        let msg: SDKMessage = serde_json::from_value(raw).map_err(|err| format!("{err}"))?;
+        let mut unknown = msg.unknown_fields();
+        if let Some(raw) = &strict_raw {
+            unknown.extend(msg.dropped_fields(raw));
+        }
+        if unknown.is_empty() {
+            return Ok(msg);
+        }
+
+        match self.parse_mode {
+            ParseMode::Tolerant => {
+                match unreadable_result(&msg) {
+                    Some(reason) => warn!(reason, "Cannot parse the result message"),
+                    None => debug!(?unknown, "Unknown fields in claude msg"),
+                }
+                Ok(msg)
+            }
+            ParseMode::Strict => Err(format!("Unknown fields: {}", unknown.join(", "))),
+        }
+    }
+
+    /// Interrupts the turn and closes stdin, so the CLI exits after saving the session.
+    /// Without stdin, i.e. a oneshot prompt, the CLI is stopped.
+    fn stop_for_budget(&mut self, exceeded: BudgetExceeded) -> Result<()> {
+        warn!(%exceeded, "Query is over budget, stopping it");
+        self.queue_output(Err(ClaudeStreamError::BudgetExceeded(exceeded)))?;
+        match &self.writer_chan {
+            Some(writer_tx) => {
+                send_control_request(writer_tx, QueryCommand::Interrupt {});
+                let _ = writer_tx.send(ClaudeWriterMessage::CloseStdin);
+            }
+            None => self.stop_notify.notify(StopReason::BudgetExceeded),
         }
 
         Ok(())
     }
 
+    fn record_mcp_status(&self, msg: &SDKMessage) {
+        let SDKMessageTyped::System(SDKSystemMessage::Init(init)) = &msg.typed else {
+            return;
+        };
+        for server in &init.mcp_servers {
+            if server.status != MCPServerConnectionStatus::Connected {
+                warn!(name = server.name, status = ?server.status, "MCP server is not connected");
+            }
+        }
+        self.mcp_status.send_replace(Some(init.mcp_servers.clone()));
+    }
+
     fn send_ctrl_resp(&self, msg: Value) {
         self.ctrl_chan
             .send(ControlMessage::ControlResponse(msg))
@@ -291,6 +569,12 @@ impl ClaudeReader {
             .send(ControlMessage::ControlRequest(msg))
             .expect("ctrl chan closed before reader");
     }
+
+    fn send_ctrl_cancel(&self, msg: Value) {
+        self.ctrl_chan
+            .send(ControlMessage::ControlCancelRequest(msg))
+            .expect("ctrl chan closed before reader");
+    }
 }
 
 impl ClaudeWriter {
@@ -313,7 +597,9 @@ impl ClaudeWriter {
                     }
                 }
                 Some(msg) = self.receiver.recv() => {
-                    self.handle_msg(msg).await?;
+                    if self.handle_msg(msg).await?.is_break() {
+                        break;
+                    }
                 }
                 Some(notify) = self.stop_notify.wait_notify() => {
                     info!("notify writer");
@@ -347,14 +633,19 @@ impl ClaudeWriter {
         Ok(())
     }
 
-    async fn handle_msg(&mut self, msg: ClaudeWriterMessage) -> anyhow::Result<()> {
+    async fn handle_msg(&mut self, msg: ClaudeWriterMessage) -> Result<ControlFlow<()>> {
         match msg {
             ClaudeWriterMessage::Write(value) => {
                 debug!("send direct msg to claude cli");
                 self.write_msg(value).await?;
             }
+            ClaudeWriterMessage::CloseStdin => {
+                info!("close claude stdin");
+                // Stdin is closed when the writer is dropped.
+                return Ok(ControlFlow::Break(()));
+            }
         }
-        Ok(())
+        Ok(ControlFlow::Continue(()))
     }
 
     async fn write_msg<T: Serialize + Debug>(&mut self, msg: T) -> Result<()> {
@@ -388,26 +679,33 @@ pub struct ControlRequstMessageWrapper {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "subtype")]
 #[serde(rename_all = "snake_case")]
-#[serde(deny_unknown_fields)]
 pub enum ControlRequstMessage {
     CanUseTool(CanUseToolRequest),
     HookCallback(HookCallbackRequest),
     McpMessage(McpMessageRequest),
 }
 
+impl ControlRequstMessage {
+    pub fn kind(&self) -> ControlRequestKind {
+        match self {
+            ControlRequstMessage::CanUseTool(..) => ControlRequestKind::CanUseTool,
+            ControlRequstMessage::HookCallback(..) => ControlRequestKind::HookCallback,
+            ControlRequstMessage::McpMessage(..) => ControlRequestKind::McpMessage,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
 pub struct McpMessageRequest {
     server_name: String,
     message: Value,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
 pub struct HookCallbackRequest {
     callback_id: String,
-    input: UntaggedToolUseParams,
-    tool_use_id: String,
+    input: HookInput,
+    tool_use_id: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -437,6 +735,9 @@ impl ControlHandler {
                     };
                     self.handle_msg(msg).await?;
                 }
+                Some(done) = self.done_rx.recv() => {
+                    self.handle_request_done(done).await?;
+                }
                 Some(notify) = self.stop_notify.wait_notify() => {
                     self.handle_stop(notify).await?;
                     return Ok(());
@@ -447,6 +748,9 @@ impl ControlHandler {
 
     async fn handle_stop(&mut self, reason: StopReason) -> Result<()> {
         info!(?reason, "ControlHandler exiting...");
+        for (_, req) in self.inflight.drain() {
+            req.task.abort();
+        }
 
         Ok(())
     }
@@ -459,9 +763,15 @@ impl ControlHandler {
             ControlMessage::ControlResponse(value) => {
                 self.handle_ctrl_resp(value).await;
             }
+            ControlMessage::ControlCancelRequest(value) => {
+                self.handle_ctrl_cancel(value).await?;
+            }
             ControlMessage::RegisterResponseChan { id, chan } => {
                 self.register_resp_chan(id, chan);
             }
+            ControlMessage::UnregisterResponseChan { id } => {
+                self.resp_chans.remove(&id);
+            }
         }
 
         Ok(())
@@ -479,20 +789,89 @@ impl ControlHandler {
         let msg: ControlRequstMessageWrapper = match msg {
             Ok(m) => m,
             Err(err) => {
+                // The CLI waits for the answer, e.g. of a new subtype. An error lets it carry on.
+                if let Some(request_id) = value["request_id"].as_str() {
+                    warn!(?err, request_id, "Cannot parse control request");
+                    return self.send_ctrl_resp(request_id, Err(err)).await;
+                }
                 let reason = StopReason::ParseClaudeControlRequest(format!("{err:?}"));
                 self.stop_notify.notify(reason);
                 return Err(err);
             }
         };
 
-        let res = self.process_control_request(msg.request).await;
-        match res {
+        let ControlRequstMessageWrapper {
+            request_id,
+            request,
+        } = msg;
+        let kind = request.kind();
+        let callbacks = self.callbacks.clone();
+        let done_tx = self.done_tx.clone();
+        let id = request_id.clone();
+        let task = tokio::spawn(async move {
+            let result = CONTROL_REQUEST_ID
+                .scope(id.clone(), callbacks.process_control_request(request))
+                .await;
+            let _ = done_tx.send(ControlRequestDone {
+                request_id: id,
+                result,
+            });
+        });
+        self.inflight.insert(
+            request_id,
+            InflightRequest {
+                kind,
+                task: task.abort_handle(),
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn handle_ctrl_cancel(&mut self, value: Value) -> Result<()> {
+        let Some(request_id) = value["request_id"].as_str() else {
+            warn!(?value, "Unkown control_cancel_request from claude");
+            return Ok(());
+        };
+        let Some(req) = self.inflight.remove(request_id) else {
+            debug!(request_id, "control request is already done before cancel");
+            return Ok(());
+        };
+        info!(request_id, kind = ?req.kind, "control request cancelled by claude");
+        req.task.abort();
+
+        let request_id = request_id.to_owned();
+        self.send_ctrl_resp(&request_id, Err(anyhow!("Request cancelled")))
+            .await?;
+        let _ = self.event_chan.send(ControlEvent::RequestCancelled {
+            request_id,
+            kind: req.kind,
+        });
+
+        Ok(())
+    }
+
+    async fn handle_request_done(&mut self, done: ControlRequestDone) -> Result<()> {
+        let ControlRequestDone { request_id, result } = done;
+        if self.inflight.remove(&request_id).is_none() {
+            debug!(
+                request_id,
+                "control request is cancelled, drop the response"
+            );
+            return Ok(());
+        }
+
+        self.send_ctrl_resp(&request_id, result).await
+    }
+
+    async fn send_ctrl_resp(&self, request_id: &str, result: Result<Value>) -> Result<()> {
+        match result {
             Ok(resp) => {
                 let resp = json!({
                   "type": "control_response",
                   "response": {
                     "subtype": "success",
-                    "request_id": msg.request_id,
+                    "request_id": request_id,
                     "response": resp
                   }
                 });
@@ -507,7 +886,7 @@ impl ControlHandler {
                   "type": "control_response",
                   "response": {
                     "subtype": "error",
-                    "request_id": msg.request_id,
+                    "request_id": request_id,
                     "error": format!("{err:?}")
                   }
                 });
@@ -520,57 +899,93 @@ impl ControlHandler {
 
     async fn send_write(&self, msg: Value) -> Result<()> {
         if let Some(tx) = &self.wirter_chan {
-            tx.send(ClaudeWriterMessage::Write(msg))?;
+            // The writer is gone after stdin is closed by a shutdown, the CLI can't read it.
+            if tx.send(ClaudeWriterMessage::Write(msg)).is_err() {
+                debug!("claude stdin is closed, drop the message");
+            }
             Ok(())
         } else {
             bail!("No writer chan in ControlHandler")
         }
     }
 
-    async fn process_control_request(&mut self, msg: ControlRequstMessage) -> Result<Value> {
+    async fn handle_ctrl_resp(&mut self, mut msg: Value) {
+        if let Value::String(id) = &msg["response"]["request_id"] {
+            let Some(chan) = self.resp_chans.remove(id) else {
+                debug!("no response listener for {id}");
+                return;
+            };
+            let resp = msg.as_object_mut().unwrap().remove("response").unwrap();
+            let _ = chan.send(resp); // ignore error
+        } else {
+            warn!(?msg, "Unkown control_response from claude");
+        }
+    }
+
+    fn register_resp_chan(&mut self, id: String, chan: oneshot::Sender<Value>) {
+        // Requests whose caller has gone, e.g. a dropped future, never get unregistered.
+        self.resp_chans.retain(|_, chan| !chan.is_closed());
+        self.resp_chans.insert(id, chan);
+    }
+}
+
+impl ControlCallbacks {
+    async fn process_control_request(self, msg: ControlRequstMessage) -> Result<Value> {
         match msg {
             ControlRequstMessage::CanUseTool(req) => {
-                let Some(cb) = &mut self.can_use_cb else {
+                let Some(cb) = &self.can_use_cb else {
                     bail!("canUseTool callback is not provided.")
                 };
-
                 let CanUseToolRequest {
                     tool_use,
                     permission_suggestions,
                 } = req;
 
                 let resp = cb
+                    .lock()
+                    .await
                     .call(tool_use, permission_suggestions)
                     .await
                     .context("CanUseTool call error")?;
 
                 Ok(serde_json::to_value(resp).unwrap())
             }
-            ControlRequstMessage::HookCallback(..) => {
-                bail!("unsupported HookCallback")
-            }
-            ControlRequstMessage::McpMessage(..) => {
-                bail!("unsupported McpMessage")
+            ControlRequstMessage::HookCallback(req) => {
+                let HookCallbackRequest {
+                    callback_id,
+                    input,
+                    tool_use_id,
+                } = req;
+                let Some(cb) = self.hook_callbacks.get(&callback_id) else {
+                    bail!("No hook callback found for ID: {callback_id}")
+                };
+
+                let resp = cb
+                    .lock()
+                    .await
+                    .call(input, tool_use_id)
+                    .await
+                    .context("Hook callback error")?;
+
+                Ok(serde_json::to_value(resp).unwrap())
             }
-        }
-    }
+            ControlRequstMessage::McpMessage(req) => {
+                let McpMessageRequest {
+                    server_name,
+                    message,
+                } = req;
+                let Some(server) = self.sdk_mcp_servers.get(&server_name) else {
+                    bail!("SDK MCP server not found: {server_name}")
+                };
 
-    async fn handle_ctrl_resp(&mut self, mut msg: Value) {
-        if let Value::String(id) = &msg["response"]["request_id"] {
-            let Some(chan) = self.resp_chans.remove(id) else {
-                debug!("no response listener for {id}");
-                return;
-            };
-            let resp = msg.as_object_mut().unwrap().remove("response").unwrap();
-            let _ = chan.send(resp); // ignore error
-        } else {
-            warn!(?msg, "Unkown control_response from claude");
+                match server.handle_message(message).await {
+                    Some(resp) => Ok(json!({ "mcp_response": resp })),
+                    // A notification, the CLI only needs the control response.
+                    None => Ok(json!({})),
+                }
+            }
         }
     }
-
-    fn register_resp_chan(&mut self, id: String, chan: oneshot::Sender<Value>) {
-        self.resp_chans.insert(id, chan);
-    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -592,6 +1007,8 @@ pub struct ModelInfo {
 pub struct ClaudeSysInfo {
     pub supported_commands: Vec<SlashCommand>,
     pub models: Vec<ModelInfo>,
+    /// `None` if `claude --version` failed.
+    pub version: Option<ClaudeVersion>,
 }
 
 impl Drop for QueryStream {
@@ -610,27 +1027,49 @@ impl QueryStream {
         Prompt: MyFrom<T>,
     {
         let prompt = Prompt::my_from(prompt);
-        let mut child = spawn_cc_cli(&prompt, &options)?;
+        if options.output_capacity == Some(0) {
+            bail!("output_capacity must be greater than 0");
+        }
+        let launcher = find_claude_launcher(
+            options.path_to_claude_code_executable.clone(),
+            options.executable,
+            options.runtime_preference.clone(),
+        )
+        .await?;
+        let version = match launcher.version().await {
+            Ok(version) => {
+                if !version.is_supported() {
+                    warn!(
+                        version = %version.version,
+                        supported = ?SUPPORTED_CLAUDE_VERSIONS,
+                        "Unsupported Claude Code version, the session may break"
+                    );
+                }
+                Some(version)
+            }
+            Err(err) => {
+                warn!(?err, "Failed to get Claude Code version");
+                None
+            }
+        };
+        let mut child = spawn_cc_cli(&prompt, &options, &launcher)?;
         debug!("claude code cli running");
 
         let stderr_db = options.stderr.take();
-        if child.has_stderr {
-            handle_stderr(stderr_db, child.inner.stderr.take().unwrap());
-        }
+        let stderr_tail = StderrTail::default();
+        let stderr_task = handle_stderr(
+            stderr_db,
+            child.inner.stderr.take().unwrap(),
+            stderr_tail.clone(),
+        );
+        let (exit_tx, exit_rx) = watch::channel(None);
 
         let (ctrl_tx, ctrl_rx) = unbounded_channel();
-        let (out_tx, out_rx) = unbounded_channel();
+        let (out_tx, out_rx) = channel::channel(options.output_capacity);
 
         let notify = StopNotify::new();
 
-        let claude_stream = FramedRead::new(child.inner.stdout.take().unwrap(), LinesCodec::new());
-        let reader = ClaudeReader {
-            claude_stream,
-            ctrl_chan: ctrl_tx.clone(),
-            output_chan: out_tx,
-            stop_notify: notify.clone(),
-        };
-        reader.spawn();
+        let (mcp_status_tx, mcp_status_rx) = watch::channel(None);
 
         let mut writer_tx = None;
         if let Prompt::Stream(stream) = prompt {
@@ -645,40 +1084,149 @@ impl QueryStream {
             writer.spawn();
         }
 
+        let claude_stream = FramedRead::new(child.inner.stdout.take().unwrap(), LinesCodec::new());
+        let reader = ClaudeReader {
+            claude_stream,
+            ctrl_chan: ctrl_tx.clone(),
+            output_chan: out_tx,
+            pending: VecDeque::new(),
+            pending_limit: options.output_capacity.unwrap_or(1),
+            mcp_status: mcp_status_tx,
+            parse_mode: options.parse_mode.unwrap_or_default(),
+            process_exit: exit_rx.clone(),
+            budget: BudgetGuard::new(options.max_budget_usd, options.max_total_tokens),
+            writer_chan: writer_tx.clone(),
+            stop_notify: notify.clone(),
+        };
+        reader.spawn();
+
         let can_use_tool_cb = options.can_use_tool.take();
+        let (hooks, hook_callbacks) = match options.hooks.take() {
+            Some(hooks) => {
+                let (config, callbacks) = register_hooks(hooks);
+                (Some(config), callbacks)
+            }
+            None => (None, HashMap::new()),
+        };
+        let sdk_mcp_servers = options
+            .mcp_servers
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(name, config)| match config {
+                McpServerConfig::Sdk(server) => Some((name, server)),
+                _ => None,
+            })
+            .collect();
+        let (done_tx, done_rx) = unbounded_channel();
+        let (event_tx, event_rx) = unbounded_channel();
         let ctrl_handler = ControlHandler {
             receiver: ctrl_rx,
             wirter_chan: writer_tx.clone(),
-            can_use_cb: can_use_tool_cb,
+            callbacks: ControlCallbacks {
+                can_use_cb: can_use_tool_cb.map(|cb| Arc::new(Mutex::new(cb))),
+                hook_callbacks: Arc::new(hook_callbacks),
+                sdk_mcp_servers: Arc::new(sdk_mcp_servers),
+            },
+            inflight: Default::default(),
+            done_tx,
+            done_rx,
+            event_chan: event_tx,
             resp_chans: Default::default(),
             stop_notify: notify.clone(),
         };
         ctrl_handler.spawn();
 
+        let control_timeout = options
+            .control_request_timeout
+            .unwrap_or(DEFAULT_CONTROL_REQUEST_TIMEOUT);
+        let shutdown = CancellationToken::new();
+        monitor_process(
+            child.inner,
+            stderr_task,
+            stderr_tail.clone(),
+            exit_tx,
+            shutdown.clone(),
+            options.shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            notify.clone(),
+        );
+
+        let abort_controller = options.abort_controller.take().unwrap_or_default();
         let mut sys_info = None;
         if let Some(write_tx) = &writer_tx {
-            let info = Self::get_init_info(write_tx, &ctrl_tx).await?;
-            sys_info = Some(info);
-        }
-
-        {
-            let mut notify = notify.clone();
-            tokio::spawn(async move {
-                debug!("waiting for stop notification");
-                if let Some(reason) = notify.wait_notify().await {
-                    info!(?reason, "Killing Claude cli");
-                    if let Err(err) = child.inner.kill().await {
-                        warn!(%err, "Failed to kill Claude Cli")
-                    }
+            let init_timeout = options.init_timeout.unwrap_or(DEFAULT_INIT_TIMEOUT);
+            let init = select! {
+                init = tokio::time::timeout(
+                    init_timeout,
+                    Self::get_init_info(write_tx, &ctrl_tx, hooks),
+                ) => init,
+                _ = abort_controller.aborted() => {
+                    notify.notify(StopReason::InitFailed);
+                    return Err(InitError::Aborted.into());
                 }
-            });
+            };
+            let err = match init {
+                Ok(Ok(info)) => {
+                    sys_info = Some(ClaudeSysInfo { version, ..info });
+                    None
+                }
+                Ok(Err(err)) if ctrl_tx.is_closed() => {
+                    // The session closes when the CLI exits, wait for how it exited.
+                    debug!(?err, "Claude exited during initialize");
+                    let mut process_exit = exit_rx.clone();
+                    let exit = tokio::time::timeout(
+                        EXIT_STATUS_TIMEOUT,
+                        process_exit.wait_for(Option::is_some),
+                    )
+                    .await;
+                    let exit = match exit {
+                        Ok(Ok(exit)) => exit.clone(),
+                        _ => None,
+                    };
+                    Some(InitError::ProcessExited {
+                        code: exit.as_ref().and_then(|exit| exit.code),
+                        signal: exit.as_ref().and_then(|exit| exit.signal),
+                        stderr_tail: stderr_tail.join(),
+                    })
+                }
+                Ok(Err(err)) => Some(InitError::Failed {
+                    error: format!("{err:#}"),
+                    stderr_tail: stderr_tail.join(),
+                }),
+                Err(_) => Some(InitError::Timeout {
+                    timeout: init_timeout,
+                    stderr_tail: stderr_tail.join(),
+                }),
+            };
+            if let Some(err) = err {
+                notify.notify(StopReason::InitFailed);
+                return Err(err.into());
+            }
         }
 
+        watch_abort(
+            abort_controller.clone(),
+            options
+                .abort_grace_period
+                .unwrap_or(DEFAULT_ABORT_GRACE_PERIOD),
+            writer_tx.clone(),
+            notify.clone(),
+        );
+
         Ok(Self {
             receiver: out_rx,
-            writer_chan: writer_tx,
+            controller: QueryController {
+                writer_chan: writer_tx,
+                ctrl_chan: ctrl_tx,
+                timeout: control_timeout,
+            },
             claude_sys_info: sys_info,
             session_id: None,
+            mcp_status: mcp_status_rx,
+            control_events: Some(event_rx),
+            abort_controller,
+            shutdown,
+            process_exit: exit_rx,
             stop_notify: notify.clone(),
         })
     }
@@ -686,52 +1234,100 @@ impl QueryStream {
     async fn get_init_info(
         wirter_tx: &UnboundedSender<ClaudeWriterMessage>,
         ctrl_tx: &UnboundedSender<ControlMessage>,
+        hooks: Option<HashMap<HookEvent, Vec<HookMatcherConfig>>>,
     ) -> Result<ClaudeSysInfo> {
         debug!("get claude system init info");
-        let id = gen_request_id();
-        let req = json!({
-          "request_id": &id,
-          "type": "control_request",
-          "request": {
-            "subtype": "initialize"
-          }
-        });
-        wirter_tx.send(ClaudeWriterMessage::Write(req)).unwrap();
-        let (tx, rx) = oneshot::channel();
-        ctrl_tx
-            .send(ControlMessage::RegisterResponseChan { id: id, chan: tx })
-            .unwrap();
-        let res = rx.await.context("Failed to wait claude sys info")?;
+        let cmd = QueryCommand::Initialize { hooks };
+        let res = control_request(wirter_tx, ctrl_tx, cmd, None)
+            .await
+            .context("Failed to wait claude sys info")?;
         debug!("got claude system info");
-        let commands = &res["response"]["commands"];
-        let models = &res["response"]["models"];
+        let commands = &res["commands"];
+        let models = &res["models"];
         let commands = serde_json::from_value(commands.clone())?;
         let models = serde_json::from_value(models.clone())?;
 
         Ok(ClaudeSysInfo {
             supported_commands: commands,
             models,
+            version: None,
         })
     }
 
+    /// ID of the session, taken from the messages read so far. `None` until the first one.
+    /// With [`fork_session`](ClaudeCodeOptions::fork_session) it's the ID of the new session.
     pub fn session_id(&self) -> Option<&str> {
         self.session_id.as_deref()
     }
 
+    /// Status of the configured MCP servers, reported by the CLI in the `system/init` message.
+    /// Returns `None` until that message is received.
+    pub fn mcp_server_status(&self) -> Option<Vec<MCPServerStatus>> {
+        self.mcp_status.borrow().clone()
+    }
+
+    /// Events about control requests from the CLI, e.g. a permission prompt withdrawn by
+    /// an interrupt. Can only be taken once.
+    pub fn take_control_events(&mut self) -> Option<UnboundedReceiver<ControlEvent>> {
+        self.control_events.take()
+    }
+
+    /// The handle that cancels this query. It can be cloned and aborted from other tasks.
+    pub fn abort_controller(&self) -> AbortController {
+        self.abort_controller.clone()
+    }
+
+    /// Kills the CLI right away. See [`shutdown`](Self::shutdown) to let it exit cleanly.
     pub fn stop(self) {
         self.stop_notify.notify(StopReason::User);
     }
 
-    pub fn interrupt(&self) -> Result<()> {
-        self.send_req(QueryCommand::Interrupt {})
+    /// Stops the CLI so it can flush its session log and be resumed later.
+    ///
+    /// Interrupts the current turn and closes stdin. If the CLI is still running after
+    /// `shutdown_timeout`, it gets SIGTERM, and SIGKILL after another timeout.
+    /// Messages still coming from the CLI are dropped.
+    pub async fn shutdown(mut self) -> Result<()> {
+        info!("Shutting down Claude cli");
+        if let Some(writer_tx) = &self.controller.writer_chan {
+            send_control_request(writer_tx, QueryCommand::Interrupt {});
+            let _ = writer_tx.send(ClaudeWriterMessage::CloseStdin);
+        }
+        self.shutdown.cancel();
+
+        let mut process_exit = self.process_exit.clone();
+        let exited = process_exit.wait_for(Option::is_some);
+        tokio::pin!(exited);
+        loop {
+            select! {
+                result = &mut exited => {
+                    // The sender is gone if the CLI was killed before.
+                    return match result {
+                        Ok(_) => Ok(()),
+                        Err(_) => bail!("Claude cli was already stopped"),
+                    };
+                }
+                // Keep the CLI from blocking on a full output queue.
+                Some(_) = self.receiver.recv() => {}
+            }
+        }
     }
 
-    pub fn set_permission_mode(&self, mode: PermissionMode) -> Result<()> {
-        self.send_req(QueryCommand::SetPermissionMode { mode })
+    /// A cloneable handle to send control requests from other tasks.
+    pub fn controller(&self) -> QueryController {
+        self.controller.clone()
     }
 
-    pub fn set_model(&self, model: String) -> Result<()> {
-        self.send_req(QueryCommand::SetModel { model })
+    pub async fn interrupt(&self) -> Result<()> {
+        self.controller.interrupt().await
+    }
+
+    pub async fn set_permission_mode(&self, mode: PermissionMode) -> Result<()> {
+        self.controller.set_permission_mode(mode).await
+    }
+
+    pub async fn set_model(&self, model: String) -> Result<()> {
+        self.controller.set_model(model).await
     }
 
     pub fn supported_commands(&self) -> Result<Vec<SlashCommand>> {
@@ -742,6 +1338,16 @@ impl QueryStream {
         Ok(info.supported_commands.clone())
     }
 
+    /// Depth metrics of the queue between the CLI's stdout and this stream.
+    pub fn output_queue_stats(&self) -> QueueStats {
+        self.receiver.metrics().stats()
+    }
+
+    /// The CLI version checked at start. Only available in streaming mode.
+    pub fn claude_version(&self) -> Option<ClaudeVersion> {
+        self.claude_sys_info.as_ref()?.version
+    }
+
     pub fn supported_models(&self) -> Result<Vec<ModelInfo>> {
         let Some(info) = &self.claude_sys_info else {
             bail!("supportedCommands is only supported in streaming mode")
@@ -749,23 +1355,101 @@ impl QueryStream {
 
         Ok(info.models.clone())
     }
+}
+
+pub const DEFAULT_CONTROL_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends control requests to the CLI and waits for the responses.
+#[derive(Clone)]
+pub struct QueryController {
+    writer_chan: Option<UnboundedSender<ClaudeWriterMessage>>,
+    ctrl_chan: UnboundedSender<ControlMessage>,
+    timeout: Duration,
+}
+
+impl QueryController {
+    pub async fn interrupt(&self) -> Result<()> {
+        self.request(QueryCommand::Interrupt {}).await?;
+        Ok(())
+    }
 
-    fn send_req(&self, sub_type: QueryCommand) -> anyhow::Result<()> {
+    pub async fn set_permission_mode(&self, mode: PermissionMode) -> Result<()> {
+        self.request(QueryCommand::SetPermissionMode { mode })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_model(&self, model: String) -> Result<()> {
+        self.request(QueryCommand::SetModel { model }).await?;
+        Ok(())
+    }
+
+    /// Sends the request and returns the payload of the CLI's success response.
+    /// An error response, or no response within the timeout, is an error.
+    pub async fn request(&self, cmd: QueryCommand) -> Result<Value> {
         let Some(writer_tx) = &self.writer_chan else {
-            bail!("{} requires --input-format stream-json", sub_type.name());
+            bail!("{} requires --input-format stream-json", cmd.name());
         };
 
-        let id = gen_request_id();
-        let req = json!({
-          "request_id": &id,
-          "type": "control_request",
-          "request": sub_type
-        });
-        if let Err(err) = writer_tx.send(ClaudeWriterMessage::Write(req)) {
-            info!(?err, "Failed to send msg to claude writer")
-        }
+        control_request(writer_tx, &self.ctrl_chan, cmd, Some(self.timeout)).await
+    }
+}
 
-        Ok(())
+async fn control_request(
+    writer_tx: &UnboundedSender<ClaudeWriterMessage>,
+    ctrl_tx: &UnboundedSender<ControlMessage>,
+    cmd: QueryCommand,
+    timeout: Option<Duration>,
+) -> Result<Value> {
+    let name = cmd.name();
+    let id = gen_request_id();
+    let (tx, rx) = oneshot::channel();
+    ctrl_tx
+        .send(ControlMessage::RegisterResponseChan {
+            id: id.clone(),
+            chan: tx,
+        })
+        .context("Claude session is closed")?;
+    let req = json!({
+      "request_id": &id,
+      "type": "control_request",
+      "request": cmd
+    });
+    writer_tx
+        .send(ClaudeWriterMessage::Write(req))
+        .context("Claude session is closed")?;
+
+    let resp = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, rx).await {
+            Ok(resp) => resp,
+            Err(_) => {
+                // Forget the response channel and tell the CLI to stop working on it.
+                let _ = ctrl_tx.send(ControlMessage::UnregisterResponseChan { id: id.clone() });
+                let cancel = json!({ "type": "control_cancel_request", "request_id": &id });
+                let _ = writer_tx.send(ClaudeWriterMessage::Write(cancel));
+                bail!("{name} timed out after {timeout:?}");
+            }
+        },
+        None => rx.await,
+    };
+    let mut resp = resp.with_context(|| format!("Claude exited before responding to {name}"))?;
+    if resp["subtype"] == "error" {
+        let err = resp["error"].as_str().unwrap_or("unknown error");
+        bail!("{name} failed: {err}");
+    }
+
+    Ok(resp["response"].take())
+}
+
+fn send_control_request(writer_tx: &UnboundedSender<ClaudeWriterMessage>, sub_type: QueryCommand) {
+    let id = gen_request_id();
+    let req = json!({
+      "request_id": &id,
+      "type": "control_request",
+      "request": sub_type
+    });
+    if let Err(err) = writer_tx.send(ClaudeWriterMessage::Write(req)) {
+        info!(?err, "Failed to send msg to claude writer")
     }
 }
 
@@ -773,18 +1457,87 @@ fn gen_request_id() -> String {
     nanoid::nanoid!()
 }
 
+/// How long an aborted query may take to finish the interrupted turn before it's killed.
+pub const DEFAULT_ABORT_GRACE_PERIOD: Duration = Duration::from_secs(3);
+
+fn watch_abort(
+    abort: AbortController,
+    grace_period: Duration,
+    writer_tx: Option<UnboundedSender<ClaudeWriterMessage>>,
+    mut notify: StopNotify,
+) {
+    tokio::spawn(async move {
+        select! {
+            _ = abort.aborted() => {}
+            _ = notify.wait_notify() => return,
+        }
+
+        info!("Query aborted");
+        if let Some(writer_tx) = &writer_tx {
+            send_control_request(writer_tx, QueryCommand::Interrupt {});
+            select! {
+                _ = tokio::time::sleep(grace_period) => {}
+                _ = notify.wait_notify() => return,
+            }
+        }
+        notify.notify(StopReason::Aborted);
+    });
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HookMatcherConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matcher: Option<String>,
+    hook_callback_ids: Vec<String>,
+}
+
+fn register_hooks(
+    hooks: HashMap<HookEvent, Vec<HookCallbackMatcher>>,
+) -> (HashMap<HookEvent, Vec<HookMatcherConfig>>, HookCallbacks) {
+    let mut config = HashMap::new();
+    let mut callbacks = HashMap::new();
+    for (event, matchers) in hooks {
+        let mut matcher_configs = vec![];
+        for matcher in matchers {
+            let mut ids = vec![];
+            for hook in matcher.hooks {
+                let id = format!("hook_{}", callbacks.len());
+                callbacks.insert(id.clone(), Arc::new(Mutex::new(hook)));
+                ids.push(id);
+            }
+            matcher_configs.push(HookMatcherConfig {
+                matcher: matcher.matcher,
+                hook_callback_ids: ids,
+            });
+        }
+        config.insert(event, matcher_configs);
+    }
+
+    (config, callbacks)
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "subtype")]
 #[serde(rename_all = "snake_case")]
 pub enum QueryCommand {
+    Initialize {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hooks: Option<HashMap<HookEvent, Vec<HookMatcherConfig>>>,
+    },
     Interrupt {},
-    SetPermissionMode { mode: PermissionMode },
-    SetModel { model: String },
+    SetPermissionMode {
+        mode: PermissionMode,
+    },
+    SetModel {
+        model: String,
+    },
 }
 
 impl QueryCommand {
     pub fn name(&self) -> &'static str {
         match self {
+            QueryCommand::Initialize { .. } => "initialize",
             QueryCommand::Interrupt {} => "interrupt",
             QueryCommand::SetPermissionMode { .. } => "set_permission_mode",
             QueryCommand::SetModel { .. } => "set_model",
@@ -792,69 +1545,212 @@ impl QueryCommand {
     }
 }
 
-fn handle_stderr(err_cb: Option<Box<dyn DebugCallBack>>, stderr: tokio::process::ChildStderr) {
-    if let Some(err_cb) = err_cb {
-        tokio::spawn(async move {
-            let mut reader = FramedRead::new(stderr, LinesCodec::new());
-            while let Some(data) = reader.next().await {
-                match data {
-                    Ok(d) => {
-                        err_cb.call(d);
-                    }
-                    Err(err) => {
-                        error!(%err, "Failed to read claude code stderr");
+/// How many stderr lines to keep for [`ClaudeStreamError::ProcessExited`].
+const STDERR_TAIL_LINES: usize = 30;
+
+/// How long the reader waits for the exit status after stdout is closed.
+const EXIT_STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Default)]
+struct StderrTail {
+    lines: Arc<std::sync::Mutex<VecDeque<String>>>,
+}
+
+impl StderrTail {
+    fn push(&self, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == STDERR_TAIL_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    fn join(&self) -> String {
+        let lines = self.lines.lock().unwrap();
+        lines
+            .iter()
+            .map(|l| l.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ProcessExit {
+    success: bool,
+    /// The process was stopped by [`QueryStream::shutdown`].
+    shutdown: bool,
+    code: Option<i32>,
+    signal: Option<i32>,
+    stderr_tail: String,
+}
+
+impl ProcessExit {
+    fn new(status: ExitStatus, shutdown: bool, stderr_tail: String) -> Self {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+        #[cfg(not(unix))]
+        let signal = None;
+
+        Self {
+            success: status.success(),
+            shutdown,
+            code: status.code(),
+            signal,
+            stderr_tail,
+        }
+    }
+}
+
+fn handle_stderr(
+    err_cb: Option<Box<dyn DebugCallBack>>,
+    stderr: tokio::process::ChildStderr,
+    tail: StderrTail,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut reader = FramedRead::new(stderr, LinesCodec::new());
+        while let Some(data) = reader.next().await {
+            match data {
+                Ok(d) => {
+                    match &err_cb {
+                        Some(err_cb) => err_cb.call(d.clone()),
+                        None => debug!("claude code stderr: {d}"),
                     }
+                    tail.push(d);
+                }
+                Err(err) => {
+                    error!(%err, "Failed to read claude code stderr");
                 }
             }
-        });
-    } else {
-        tokio::spawn(async move {
-            let mut reader = FramedRead::new(stderr, LinesCodec::new());
-            while let Some(data) = reader.next().await {
-                match data {
-                    Ok(d) => {
-                        debug!("claude code stderr: {d}")
-                    }
-                    Err(err) => {
-                        error!(%err, "Failed to read claude code stderr");
-                    }
+        }
+    })
+}
+
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reaps the CLI process, or kills it when the query stops.
+/// On [`QueryStream::shutdown`], waits for it to exit before escalating to SIGTERM and SIGKILL.
+fn monitor_process(
+    mut child: Child,
+    stderr_task: JoinHandle<()>,
+    stderr_tail: StderrTail,
+    exit_tx: watch::Sender<Option<ProcessExit>>,
+    shutdown: CancellationToken,
+    shutdown_timeout: Duration,
+    mut notify: StopNotify,
+) {
+    tokio::spawn(async move {
+        debug!("waiting for stop notification");
+        let (status, is_shutdown) = select! {
+            status = child.wait() => (status, false),
+            _ = shutdown.cancelled() => {
+                (stop_gracefully(&mut child, shutdown_timeout).await, true)
+            }
+            Some(reason) = notify.wait_notify() => {
+                info!(?reason, "Killing Claude cli");
+                if let Err(err) = child.kill().await {
+                    warn!(%err, "Failed to kill Claude Cli")
                 }
+                return;
             }
-        });
+        };
+        let status = match status {
+            Ok(status) => status,
+            Err(err) => {
+                warn!(%err, "Failed to wait Claude Cli");
+                return;
+            }
+        };
+
+        // Stderr is closed at exit, let the reader catch up.
+        let _ = tokio::time::timeout(Duration::from_secs(1), stderr_task).await;
+        let exit = ProcessExit::new(status, is_shutdown, stderr_tail.join());
+        info!(?exit.code, ?exit.signal, "Claude cli exited");
+        exit_tx.send_replace(Some(exit));
+    });
+}
+
+/// Stdin is already closed. Waits for the CLI to exit, then SIGTERM, then SIGKILL.
+async fn stop_gracefully(child: &mut Child, timeout: Duration) -> std::io::Result<ExitStatus> {
+    if let Ok(status) = tokio::time::timeout(timeout, child.wait()).await {
+        return status;
     }
+
+    info!("Claude cli is still running, terminating it");
+    terminate(child)?;
+    if let Ok(status) = tokio::time::timeout(timeout, child.wait()).await {
+        return status;
+    }
+
+    warn!("Claude cli ignored SIGTERM, killing it");
+    child.kill().await?;
+    child.wait().await
+}
+
+#[cfg(unix)]
+fn terminate(child: &Child) -> std::io::Result<()> {
+    let Some(pid) = child.id() else {
+        return Ok(());
+    };
+    // SAFETY: `pid` is our child which has not been reaped, so it can't be reused.
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut Child) -> std::io::Result<()> {
+    child.start_kill()
 }
 
 struct ClaudeProcess {
     inner: Child,
-    has_stderr: bool,
 }
 
-fn spawn_cc_cli(prompt: &Prompt, options: &ClaudeCodeOptions) -> anyhow::Result<ClaudeProcess> {
+fn spawn_cc_cli(
+    prompt: &Prompt,
+    options: &ClaudeCodeOptions,
+    launcher: &ClaudeLauncher,
+) -> anyhow::Result<ClaudeProcess> {
     let ClaudeCodeOptions {
         abort_controller: _,
+        abort_grace_period: _,
         additional_directories,
         allowed_tools,
         append_system_prompt,
         can_use_tool,
+        config_dir,
         r#continue,
+        control_request_timeout: _,
         custom_system_prompt,
         cwd,
         disallowed_tools,
         env,
-        executable,
+        executable: _,
         executable_args,
         extra_args,
         fallback_model,
-        hooks: _,
+        fork_session,
+        hooks,
         include_partial_messages,
+        init_timeout: _,
+        max_budget_usd: _,
         max_thinking_tokens: _,
+        max_total_tokens: _,
         max_turns,
-        mcp_servers: _,
+        mcp_servers,
         model,
-        path_to_claude_code_executable,
+        output_capacity: _,
+        parse_mode: _,
+        path_to_claude_code_executable: _,
         permission_mode,
         permission_prompt_tool_name,
         resume,
+        resume_session_at,
+        runtime_preference: _,
+        settings,
+        shutdown_timeout: _,
         stderr: _, // use later
         strict_mcp_config,
     } = options;
@@ -923,6 +1819,16 @@ fn spawn_cc_cli(prompt: &Prompt, options: &ClaudeCodeOptions) -> anyhow::Result<
         args.arg("--permission-prompt-tool").arg(tool_name);
     }
 
+    if hooks.is_some() && prompt.is_oneshot() {
+        bail!(
+            "hooks callback requires --input-format stream-json. Please set prompt as an AsyncIterable."
+        )
+    }
+
+    if let Some(settings) = settings {
+        args.arg("--settings").arg(settings);
+    }
+
     // Add continue flag
     if r#continue.unwrap_or(false) {
         args.arg("--continue");
@@ -933,6 +1839,20 @@ fn spawn_cc_cli(prompt: &Prompt, options: &ClaudeCodeOptions) -> anyhow::Result<
         args.arg("--resume").arg(resume);
     }
 
+    if let Some(message_id) = resume_session_at {
+        if resume.is_none() {
+            bail!("resume_session_at requires resume");
+        }
+        args.arg("--resume-session-at").arg(message_id);
+    }
+
+    if fork_session.unwrap_or(false) {
+        if resume.is_none() && !r#continue.unwrap_or(false) {
+            bail!("fork_session requires resume or continue");
+        }
+        args.arg("--fork-session");
+    }
+
     // Add allowed tools
     if let Some(allowed_tools) = allowed_tools {
         if !allowed_tools.is_empty() {
@@ -947,7 +1867,22 @@ fn spawn_cc_cli(prompt: &Prompt, options: &ClaudeCodeOptions) -> anyhow::Result<
                 .arg(disallowed_tools.join(","));
         }
     }
-    // TODO: add mcp config here
+
+    // Add mcp config
+    if let Some(mcp_servers) = mcp_servers
+        && !mcp_servers.is_empty()
+    {
+        let has_sdk_server = mcp_servers
+            .values()
+            .any(|c| matches!(c, McpServerConfig::Sdk(..)));
+        if has_sdk_server && prompt.is_oneshot() {
+            bail!(
+                "SDK MCP servers require --input-format stream-json. Please set prompt as an AsyncIterable."
+            )
+        }
+        let config = json!({ "mcpServers": mcp_servers });
+        args.arg("--mcp-config").arg(config.to_string());
+    }
 
     if strict_mcp_config == &Some(true) {
         args.arg("--strict-mcp-config");
@@ -1001,76 +1936,35 @@ fn spawn_cc_cli(prompt: &Prompt, options: &ClaudeCodeOptions) -> anyhow::Result<
         }
     }
 
-    let bin_path = path_to_claude_code_executable
-        .as_deref()
-        .unwrap_or_else(|| Path::new("claude"));
-    let path = &*bin_path.to_string_lossy();
-    let claude_bin_path = if is_command(path) {
-        match find_command_real_path(path)? {
-            Some(p) => p,
-            None => {
-                bail!("Failed to find claude command. Did you install it")
-            }
-        }
-    } else {
-        bin_path.to_owned()
-    };
-
-    let is_native = is_native_binary(&claude_bin_path);
-    if !claude_bin_path.exists() {
-        if is_native {
-            bail!(
-                "Claude Code native binary not found at {claude_bin_path:?}. Please ensure Claude Code is installed via native installer or specify a valid path with options.pathToClaudeCodeExecutable."
-            );
-        } else {
-            bail!(
-                "Claude Code executable not found at {claude_bin_path:?}. Is options.pathToClaudeCodeExecutable set?"
-            )
-        }
-    }
-
-    let command = if is_native {
-        &claude_bin_path.to_string_lossy()
-    } else {
-        match executable {
-            Some(e) => e.as_str(),
-            None => default_claude_runtime()?.as_str(),
-        }
-    };
     let args = args
         .args
         .iter()
         .map(|a| (&**a).as_ref())
         .collect::<Vec<_>>();
 
-    let args = if is_native {
-        args
-    } else {
-        let mut execute_args = match &executable_args {
-            Some(e) => e.iter().map(|a| a.as_ref()).collect(),
-            None => {
-                vec![]
-            }
-        };
-        execute_args.push(claude_bin_path.as_os_str());
-        execute_args.extend(args);
+    let args = match &launcher.entrypoint {
+        None => args,
+        Some(entrypoint) => {
+            let mut execute_args = match &executable_args {
+                Some(e) => e.iter().map(|a| a.as_ref()).collect(),
+                None => {
+                    vec![]
+                }
+            };
+            execute_args.push(entrypoint.as_os_str());
+            execute_args.extend(args);
 
-        execute_args
+            execute_args
+        }
     };
 
-    let mut cmd = Command::new(command);
+    let mut cmd = Command::new(&launcher.program);
     cmd.kill_on_drop(true);
     cmd.args(args);
 
     cmd.stdout(Stdio::piped());
 
-    let mut has_stderr = false;
-    if let Some(env) = env {
-        if env.contains_key("DEBUG") {
-            has_stderr = true;
-            cmd.stderr(Stdio::piped());
-        }
-    }
+    cmd.stderr(Stdio::piped());
 
     if prompt.is_oneshot() {
         cmd.stdin(Stdio::null());
@@ -1089,6 +1983,10 @@ fn spawn_cc_cli(prompt: &Prompt, options: &ClaudeCodeOptions) -> anyhow::Result<
         cmd.current_dir(cwd);
     }
 
+    if let Some(config_dir) = config_dir {
+        cmd.env("CLAUDE_CONFIG_DIR", config_dir);
+    }
+
     // Set environment variables
     if let Some(env_vars) = env {
         for (key, value) in env_vars {
@@ -1098,35 +1996,7 @@ fn spawn_cc_cli(prompt: &Prompt, options: &ClaudeCodeOptions) -> anyhow::Result<
 
     let child = cmd.spawn()?;
 
-    Ok(ClaudeProcess {
-        inner: child,
-        has_stderr,
-    })
-}
-
-fn default_claude_runtime() -> anyhow::Result<Executable> {
-    // TODO: find available runtime
-    Ok(Executable::Node)
-}
-
-fn is_native_binary(path: &Path) -> bool {
-    static JS_EXTENSIONS: &[&str] = &[".js", ".mjs", ".tsx", ".ts", ".jsx"];
-    !JS_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
-}
-
-fn is_command(cmd: &str) -> bool {
-    let path = Path::new(cmd);
-    path.parent() == Some(Path::new(""))
-}
-
-fn find_command_real_path(cmd: &str) -> Result<Option<PathBuf>> {
-    if !is_command(cmd) {
-        return Ok(None);
-    }
-
-    let path = which(cmd).with_context(|| format!("Failed to find command path: {cmd}"))?;
-
-    Ok(Some(path))
+    Ok(ClaudeProcess { inner: child })
 }
 
 #[derive(Clone, Debug)]
@@ -1138,6 +2008,9 @@ enum StopReason {
     InvalidClaudeOutput,
     CannotWriteToClaude(String),
     ParseClaudeControlRequest(String),
+    Aborted,
+    InitFailed,
+    BudgetExceeded,
 }
 
 #[derive(Clone)]
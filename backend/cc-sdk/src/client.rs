@@ -0,0 +1,154 @@
+//! A multi-turn client, for using the CLI interactively without a hand-written prompt generator.
+
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, ready},
+};
+
+use anyhow::{Context as _, Result};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio_stream::Stream;
+
+use crate::{
+    cli::{ClaudeStreamError, PromptGenerator, QueryController, QueryStream, QueryStreamItem},
+    types::{
+        APIUserMessage, APIUserMessageRole, ClaudeCodeOptions, PermissionMode, SDKMessageTyped,
+        SDKUserMessage, UserContent,
+    },
+};
+
+/// A conversation with one CLI process.
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use cc_sdk::{StreamExt, client::ClaudeClient};
+///
+/// let mut client = ClaudeClient::connect(Default::default()).await?;
+/// client.send("Hello")?;
+/// let mut response = client.receive_response();
+/// while let Some(msg) = response.next().await {
+///     println!("{:?}", msg?);
+/// }
+/// client.disconnect().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ClaudeClient {
+    prompt_tx: UnboundedSender<SDKUserMessage>,
+    stream: QueryStream,
+}
+
+impl ClaudeClient {
+    /// Starts the CLI in streaming mode and waits until it's initialized.
+    pub async fn connect(options: ClaudeCodeOptions) -> Result<Self> {
+        let (prompt_tx, receiver) = unbounded_channel();
+        let stream = QueryStream::new(ChannelPrompt { receiver }, options).await?;
+        Ok(Self { prompt_tx, stream })
+    }
+
+    /// Sends a user message. Read the answer with [`receive_response`](Self::receive_response).
+    pub fn send(&self, prompt: impl Into<UserContent>) -> Result<()> {
+        self.send_message(SDKUserMessage {
+            uuid: None,
+            message: APIUserMessage {
+                content: Arc::new(prompt.into()),
+                role: APIUserMessageRole::User,
+            },
+            parent_tool_use_id: None,
+        })
+    }
+
+    pub fn send_message(&self, msg: SDKUserMessage) -> Result<()> {
+        self.prompt_tx
+            .send(msg)
+            .ok()
+            .context("Claude cli is already stopped")
+    }
+
+    /// Messages of the current turn. The stream ends after the `result` message, including
+    /// one that doesn't parse, or after an error that stops the query.
+    pub fn receive_response(&mut self) -> Response<'_> {
+        Response {
+            stream: &mut self.stream,
+            done: false,
+        }
+    }
+
+    /// All messages from the CLI, across turns.
+    pub fn receive_messages(&mut self) -> &mut QueryStream {
+        &mut self.stream
+    }
+
+    pub async fn interrupt(&self) -> Result<()> {
+        self.stream.interrupt().await
+    }
+
+    pub async fn set_model(&self, model: impl Into<String>) -> Result<()> {
+        self.stream.set_model(model.into()).await
+    }
+
+    pub async fn set_permission_mode(&self, mode: PermissionMode) -> Result<()> {
+        self.stream.set_permission_mode(mode).await
+    }
+
+    /// A cloneable handle to send control requests from other tasks.
+    pub fn controller(&self) -> QueryController {
+        self.stream.controller()
+    }
+
+    pub fn session_id(&self) -> Option<&str> {
+        self.stream.session_id()
+    }
+
+    pub fn stream(&self) -> &QueryStream {
+        &self.stream
+    }
+
+    /// Shuts down the CLI gracefully. See [`QueryStream::shutdown`].
+    pub async fn disconnect(self) -> Result<()> {
+        self.stream.shutdown().await
+    }
+}
+
+/// Messages of one turn, returned by [`ClaudeClient::receive_response`].
+pub struct Response<'a> {
+    stream: &'a mut QueryStream,
+    done: bool,
+}
+
+impl Stream for Response<'_> {
+    type Item = QueryStreamItem;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        let item = ready!(Pin::new(&mut *this.stream).poll_next(cx));
+        this.done = match &item {
+            Some(Ok(msg)) => match &msg.typed {
+                SDKMessageTyped::Result(..) => true,
+                // A result that doesn't parse in tolerant mode, see `cost::unreadable_result`.
+                SDKMessageTyped::Unknown(value) => value["type"] == "result",
+                _ => false,
+            },
+            // Tolerant mode skips a message that doesn't parse and goes on. When it's fatal,
+            // the stream ends right after it.
+            Some(Err(ClaudeStreamError::CannotDeserialize(..))) => false,
+            Some(Err(..)) | None => true,
+        };
+        Poll::Ready(item)
+    }
+}
+
+struct ChannelPrompt {
+    receiver: UnboundedReceiver<SDKUserMessage>,
+}
+
+impl PromptGenerator for ChannelPrompt {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<SDKUserMessage>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
@@ -0,0 +1,527 @@
+//! Token and cost accounting over the `result` messages of one or more sessions.
+//!
+//! Within a turn, before its `result` arrives, the cost is estimated from the usage of the
+//! assistant messages, see [`estimate_cost_usd`].
+
+use std::collections::HashMap;
+
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::types::{
+    ModelUsage, SDKMessage, SDKMessageTyped, SDKResultErrorMessage, SDKResultMessage,
+    SDKResultSuccessMessage, anthropic::Usage,
+};
+
+/// Cost of one turn, i.e. one `result` message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TurnCost {
+    pub session_id: String,
+    pub uuid: String,
+    pub is_error: bool,
+    pub duration_ms: u64,
+    pub cost_usd: f64,
+    pub usage: Usage,
+    pub model_usage: HashMap<String, ModelUsage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CostTotals {
+    pub turns: u32,
+    pub cost_usd: f64,
+    pub usage: Usage,
+    pub model_usage: HashMap<String, ModelUsage>,
+}
+
+impl CostTotals {
+    fn add(&mut self, turn: &TurnCost) {
+        self.turns += 1;
+        self.cost_usd += turn.cost_usd;
+        self.usage.add(&turn.usage);
+        for (model, usage) in &turn.model_usage {
+            self.model_usage
+                .entry(model.clone())
+                .or_default()
+                .add(usage);
+        }
+    }
+}
+
+/// Adds up the cost of turns, per session and overall.
+///
+/// The CLI reports `total_cost_usd` and `modelUsage` as running totals of its process,
+/// while `usage` only covers the last turn. The tracker keeps the previous running totals
+/// of each session to get the per-turn numbers.
+#[derive(Debug, Default)]
+pub struct CostTracker {
+    turns: Vec<TurnCost>,
+    sessions: HashMap<String, SessionCost>,
+    total: CostTotals,
+}
+
+#[derive(Debug, Default)]
+struct SessionCost {
+    totals: CostTotals,
+    reported_cost_usd: f64,
+    reported_model_usage: HashMap<String, ModelUsage>,
+}
+
+impl CostTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds a message of a [`QueryStream`](crate::cli::QueryStream).
+    /// Returns the cost of the turn if `msg` is a `result` message.
+    /// Results that can't be parsed are skipped with a warning, see [`unreadable_result`].
+    pub fn push_message(&mut self, msg: &SDKMessage) -> Option<&TurnCost> {
+        if let Some(reason) = unreadable_result(msg) {
+            warn!(reason, "The cost of a result message is not counted");
+            return None;
+        }
+        let SDKMessageTyped::Result(result) = &msg.typed else {
+            return None;
+        };
+        let (uuid, is_error, duration_ms, total_cost_usd, usage, model_usage) = match result {
+            SDKResultMessage::Success(SDKResultSuccessMessage {
+                uuid,
+                is_error,
+                duration_ms,
+                total_cost_usd,
+                usage,
+                model_usage,
+                ..
+            })
+            | SDKResultMessage::ErrorMaxTurns(SDKResultErrorMessage {
+                uuid,
+                is_error,
+                duration_ms,
+                total_cost_usd,
+                usage,
+                model_usage,
+                ..
+            })
+            | SDKResultMessage::ErrorDuringExecution(SDKResultErrorMessage {
+                uuid,
+                is_error,
+                duration_ms,
+                total_cost_usd,
+                usage,
+                model_usage,
+                ..
+            }) => (
+                uuid,
+                is_error,
+                duration_ms,
+                total_cost_usd,
+                usage,
+                model_usage,
+            ),
+            SDKResultMessage::Unknown(..) => return None,
+        };
+
+        let session = self.sessions.entry(msg.session_id.clone()).or_default();
+        // A lower running total means the session was resumed in a new process.
+        if *total_cost_usd < session.reported_cost_usd {
+            session.reported_cost_usd = 0.0;
+            session.reported_model_usage.clear();
+        }
+        let turn = TurnCost {
+            session_id: msg.session_id.clone(),
+            uuid: uuid.clone(),
+            is_error: *is_error,
+            duration_ms: *duration_ms,
+            cost_usd: (total_cost_usd - session.reported_cost_usd).max(0.0),
+            usage: usage.clone(),
+            model_usage: model_usage
+                .iter()
+                .map(|(model, usage)| {
+                    let reported = session.reported_model_usage.get(model);
+                    let usage = reported.map_or(usage.clone(), |reported| usage.sub(reported));
+                    (model.clone(), usage)
+                })
+                .collect(),
+        };
+        session.reported_cost_usd = *total_cost_usd;
+        session.reported_model_usage = model_usage.clone();
+        session.totals.add(&turn);
+        self.total.add(&turn);
+
+        self.turns.push(turn);
+        self.turns.last()
+    }
+
+    pub fn turns(&self) -> &[TurnCost] {
+        &self.turns
+    }
+
+    pub fn session(&self, session_id: &str) -> Option<&CostTotals> {
+        self.sessions.get(session_id).map(|session| &session.totals)
+    }
+
+    pub fn sessions(&self) -> impl Iterator<Item = (&str, &CostTotals)> {
+        self.sessions
+            .iter()
+            .map(|(id, session)| (id.as_str(), &session.totals))
+    }
+
+    /// Totals of all sessions.
+    pub fn total(&self) -> &CostTotals {
+        &self.total
+    }
+}
+
+/// A limit of [`ClaudeCodeOptions`](crate::types::ClaudeCodeOptions) that a query went over.
+/// The SDK interrupts the query and yields it as [`ClaudeStreamError::BudgetExceeded`].
+///
+/// [`ClaudeStreamError::BudgetExceeded`]: crate::cli::ClaudeStreamError::BudgetExceeded
+#[derive(Display, Debug, Clone, PartialEq)]
+pub enum BudgetExceeded {
+    #[display("Spent ${spent_usd:.4}, over max_budget_usd ${limit_usd}")]
+    Cost { spent_usd: f64, limit_usd: f64 },
+    #[display("Used {spent} tokens, over max_total_tokens {limit}")]
+    Tokens { spent: u64, limit: u64 },
+    /// A `result` message couldn't be read, so the spend is unknown.
+    #[display("Cannot tell the cost of the query: {reason}")]
+    UnknownCost { reason: String },
+}
+
+/// Why the cost of a `result` message can't be read, if it can't.
+pub fn unreadable_result(msg: &SDKMessage) -> Option<String> {
+    match &msg.typed {
+        SDKMessageTyped::Result(SDKResultMessage::Unknown(value)) => {
+            Some(format!("unknown result subtype {}", value["subtype"]))
+        }
+        SDKMessageTyped::Unknown(value) if value["type"] == "result" => {
+            Some(format!("result/{} doesn't parse", value["subtype"]))
+        }
+        _ => None,
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+/// List prices of a model family, in USD per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelPricing {
+    pub input: f64,
+    pub output: f64,
+    /// Cache reads are billed at 10% of the input price.
+    pub cache_read: f64,
+    /// Cache writes with the 5 minute TTL are billed at 125% of the input price,
+    /// the 1 hour TTL at 200%.
+    pub cache_write_5m: f64,
+    pub cache_write_1h: f64,
+}
+
+impl ModelPricing {
+    const fn new(input: f64, output: f64) -> Self {
+        Self {
+            input,
+            output,
+            cache_read: input * 0.1,
+            cache_write_5m: input * 1.25,
+            cache_write_1h: input * 2.0,
+        }
+    }
+
+    /// Prices of the most expensive model, used for models missing from the table so the
+    /// budget errs on the safe side.
+    pub const FALLBACK: ModelPricing = ModelPricing::new(15.0, 75.0);
+
+    /// Prices of a model by its ID, e.g. `claude-sonnet-4-5-20250929`.
+    pub fn of(model: &str) -> ModelPricing {
+        // More specific names first.
+        const TABLE: &[(&str, ModelPricing)] = &[
+            ("opus-4-5", ModelPricing::new(5.0, 25.0)),
+            ("opus", ModelPricing::new(15.0, 75.0)),
+            ("sonnet", ModelPricing::new(3.0, 15.0)),
+            ("haiku-4-5", ModelPricing::new(1.0, 5.0)),
+            ("3-5-haiku", ModelPricing::new(0.8, 4.0)),
+            ("3-haiku", ModelPricing::new(0.25, 1.25)),
+        ];
+
+        TABLE
+            .iter()
+            .find(|(name, _)| model.contains(name))
+            .map_or(Self::FALLBACK, |(_, pricing)| *pricing)
+    }
+}
+
+/// Estimates the cost of an API call from its usage and the list prices of the model.
+///
+/// It's an approximation: it ignores batch and long context prices and server tool fees.
+/// The CLI's own `total_cost_usd` in the `result` message is what counts in the end.
+pub fn estimate_cost_usd(model: &str, usage: &Usage) -> f64 {
+    let pricing = ModelPricing::of(model);
+    let cache_write = match &usage.cache_creation {
+        Some(creation) => {
+            creation.ephemeral_5m_input_tokens as f64 * pricing.cache_write_5m
+                + creation.ephemeral_1h_input_tokens as f64 * pricing.cache_write_1h
+        }
+        None => {
+            usage.cache_creation_input_tokens.unwrap_or_default() as f64 * pricing.cache_write_5m
+        }
+    };
+    let cost = usage.input_tokens as f64 * pricing.input
+        + usage.output_tokens as f64 * pricing.output
+        + usage.cache_read_input_tokens.unwrap_or_default() as f64 * pricing.cache_read
+        + cache_write;
+
+    cost / 1_000_000.0
+}
+
+/// Enforces `max_budget_usd` and `max_total_tokens` on the messages of a query.
+#[derive(Debug)]
+pub(crate) struct BudgetGuard {
+    max_budget_usd: Option<f64>,
+    max_total_tokens: Option<u64>,
+    tracker: CostTracker,
+    /// Usage of the assistant messages in the current turn, by message ID: the tokens and
+    /// the estimated cost. The CLI sends one message per content block, all with the same
+    /// usage. Replaced by the reported cost once the `result` arrives.
+    turn_usage: HashMap<String, (u64, f64)>,
+    exceeded: bool,
+}
+
+impl BudgetGuard {
+    pub(crate) fn new(max_budget_usd: Option<f64>, max_total_tokens: Option<u64>) -> Option<Self> {
+        if max_budget_usd.is_none() && max_total_tokens.is_none() {
+            return None;
+        }
+        Some(Self {
+            max_budget_usd,
+            max_total_tokens,
+            tracker: CostTracker::new(),
+            turn_usage: HashMap::new(),
+            exceeded: false,
+        })
+    }
+
+    /// Returns the exceeded limit the first time a message goes over one.
+    /// A result it can't read counts as over budget.
+    pub(crate) fn push_message(&mut self, msg: &SDKMessage) -> Option<BudgetExceeded> {
+        if let Some(reason) = unreadable_result(msg) {
+            return self.exceed(BudgetExceeded::UnknownCost { reason });
+        }
+        match &msg.typed {
+            SDKMessageTyped::Assistant(assistant) => {
+                let message = &assistant.message;
+                let tokens = message.usage.total_tokens();
+                let cost_usd = estimate_cost_usd(&message.model, &message.usage);
+                self.turn_usage
+                    .insert(message.id.clone(), (tokens, cost_usd));
+            }
+            SDKMessageTyped::Result(..) => {
+                self.tracker.push_message(msg);
+                self.turn_usage.clear();
+            }
+            _ => return None,
+        }
+        if self.exceeded {
+            return None;
+        }
+
+        let total = self.tracker.total();
+        let spent_usd =
+            total.cost_usd + self.turn_usage.values().map(|(_, cost)| cost).sum::<f64>();
+        let spent = total.usage.total_tokens()
+            + self
+                .turn_usage
+                .values()
+                .map(|(tokens, _)| tokens)
+                .sum::<u64>();
+        let exceeded = match (self.max_budget_usd, self.max_total_tokens) {
+            (Some(limit_usd), _) if spent_usd > limit_usd => BudgetExceeded::Cost {
+                spent_usd,
+                limit_usd,
+            },
+            (_, Some(limit)) if spent > limit => BudgetExceeded::Tokens { spent, limit },
+            _ => return None,
+        };
+        self.exceed(exceeded)
+    }
+
+    fn exceed(&mut self, exceeded: BudgetExceeded) -> Option<BudgetExceeded> {
+        if self.exceeded {
+            return None;
+        }
+        self.exceeded = true;
+        Some(exceeded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn result(session_id: &str, cost: f64, input_tokens: u64, model_input: u64) -> SDKMessage {
+        serde_json::from_value(json!({
+            "type": "result",
+            "subtype": "success",
+            "uuid": "uuid",
+            "session_id": session_id,
+            "duration_ms": 10,
+            "duration_api_ms": 10,
+            "is_error": false,
+            "num_turns": 1,
+            "result": "ok",
+            "total_cost_usd": cost,
+            "usage": {
+                "input_tokens": input_tokens,
+                "output_tokens": 1,
+                "cache_read_input_tokens": 100,
+                "server_tool_use": { "web_search_requests": 1 }
+            },
+            "modelUsage": {
+                "claude-sonnet-4-5": {
+                    "inputTokens": model_input,
+                    "outputTokens": 1,
+                    "cacheReadInputTokens": 0,
+                    "cacheCreationInputTokens": 0,
+                    "webSearchRequests": 0,
+                    "costUSD": cost
+                }
+            },
+            "permission_denials": []
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_tracker() {
+        let mut tracker = CostTracker::new();
+        let turn = tracker.push_message(&result("s1", 0.5, 10, 10)).unwrap();
+        assert_eq!(turn.cost_usd, 0.5);
+        let turn = tracker.push_message(&result("s1", 0.75, 20, 30)).unwrap();
+        assert_eq!(turn.cost_usd, 0.25);
+        assert_eq!(turn.model_usage["claude-sonnet-4-5"].input_tokens, 20);
+        // Resumed in a new process, the running totals start over.
+        let turn = tracker.push_message(&result("s1", 0.125, 5, 5)).unwrap();
+        assert_eq!(turn.cost_usd, 0.125);
+        tracker.push_message(&result("s2", 1.0, 1, 1));
+
+        let s1 = tracker.session("s1").unwrap();
+        assert_eq!(s1.turns, 3);
+        assert_eq!(s1.cost_usd, 0.875);
+        assert_eq!(s1.usage.input_tokens, 35);
+        assert_eq!(s1.usage.cache_read_input_tokens, Some(300));
+        assert_eq!(
+            s1.usage
+                .server_tool_use
+                .as_ref()
+                .unwrap()
+                .web_search_requests,
+            3
+        );
+        assert_eq!(s1.model_usage["claude-sonnet-4-5"].input_tokens, 35);
+
+        let total = tracker.total();
+        assert_eq!(total.turns, 4);
+        assert_eq!(total.cost_usd, 1.875);
+        assert_eq!(total.usage.total_tokens(), 36 + 4 + 400);
+        assert_eq!(tracker.turns().len(), 4);
+    }
+
+    #[test]
+    fn test_estimate_cost() {
+        assert_eq!(
+            ModelPricing::of("claude-sonnet-4-5-20250929"),
+            ModelPricing::new(3.0, 15.0)
+        );
+        assert_eq!(
+            ModelPricing::of("claude-opus-4-5-20251101"),
+            ModelPricing::new(5.0, 25.0)
+        );
+        assert_eq!(
+            ModelPricing::of("claude-3-5-haiku-20241022"),
+            ModelPricing::new(0.8, 4.0)
+        );
+        assert_eq!(ModelPricing::of("<synthetic>"), ModelPricing::FALLBACK);
+
+        let usage = Usage {
+            input_tokens: 1_000_000,
+            output_tokens: 100_000,
+            cache_read_input_tokens: Some(1_000_000),
+            cache_creation_input_tokens: Some(1_000_000),
+            ..Default::default()
+        };
+        let cost = estimate_cost_usd("claude-sonnet-4-5", &usage);
+        assert!((cost - (3.0 + 1.5 + 0.3 + 3.75)).abs() < 1e-9, "{cost}");
+    }
+
+    #[test]
+    fn test_budget_guard() {
+        let assistant = |id: &str| -> SDKMessage {
+            serde_json::from_value(json!({
+                "type": "assistant",
+                "uuid": "uuid",
+                "session_id": "s1",
+                "parent_tool_use_id": null,
+                "message": {
+                    "id": id,
+                    "type": "message",
+                    "role": "assistant",
+                    "model": "claude-sonnet-4-5",
+                    "content": [],
+                    "stop_reason": null,
+                    "stop_sequence": null,
+                    "usage": { "input_tokens": 100, "output_tokens": 10 }
+                }
+            }))
+            .unwrap()
+        };
+
+        assert!(BudgetGuard::new(None, None).is_none());
+
+        let mut guard = BudgetGuard::new(None, Some(300)).unwrap();
+        assert_eq!(guard.push_message(&assistant("msg_1")), None);
+        assert_eq!(guard.push_message(&assistant("msg_1")), None);
+        assert_eq!(guard.push_message(&assistant("msg_2")), None);
+        assert_eq!(
+            guard.push_message(&assistant("msg_3")),
+            Some(BudgetExceeded::Tokens {
+                spent: 330,
+                limit: 300
+            })
+        );
+        assert_eq!(guard.push_message(&assistant("msg_4")), None);
+
+        // Spend within a turn is estimated from the assistant messages.
+        let mut guard = BudgetGuard::new(Some(0.001), None).unwrap();
+        assert_eq!(guard.push_message(&assistant("msg_1")), None);
+        assert_eq!(guard.push_message(&assistant("msg_2")), None);
+        assert!(matches!(
+            guard.push_message(&assistant("msg_3")),
+            Some(BudgetExceeded::Cost { spent_usd, .. }) if spent_usd > 0.001
+        ));
+
+        let mut guard = BudgetGuard::new(Some(1.0), None).unwrap();
+        assert_eq!(guard.push_message(&result("s1", 0.5, 10, 10)), None);
+        assert_eq!(
+            guard.push_message(&result("s1", 1.5, 10, 10)),
+            Some(BudgetExceeded::Cost {
+                spent_usd: 1.5,
+                limit_usd: 1.0
+            })
+        );
+    }
+
+    #[test]
+    fn test_budget_guard_unreadable_result() {
+        let mut value = serde_json::to_value(result("s1", 0.1, 1, 1)).unwrap();
+        value["usage"] = json!({ "input_tokens": "many" });
+        let msg: SDKMessage = serde_json::from_value(value).unwrap();
+        assert!(unreadable_result(&msg).is_some());
+
+        let mut guard = BudgetGuard::new(Some(1.0), None).unwrap();
+        assert!(matches!(
+            guard.push_message(&msg),
+            Some(BudgetExceeded::UnknownCost { .. })
+        ));
+        assert!(CostTracker::new().push_message(&msg).is_none());
+    }
+}
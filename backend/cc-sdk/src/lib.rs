@@ -3,7 +3,18 @@ use crate::{
     types::ClaudeCodeOptions,
 };
 
+pub mod abort;
+pub mod channel;
 pub mod cli;
+pub mod client;
+pub mod cost;
+pub mod mcp;
+mod permission_util;
+pub mod policy;
+pub mod rules;
+pub mod runtime;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod types;
 
 pub use tokio_stream::{Stream, StreamExt};
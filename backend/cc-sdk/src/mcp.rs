@@ -0,0 +1,477 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
+use serde_json::{Value, json};
+use tracing::debug;
+
+pub const MCP_PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// A server entry of `--mcp-config`. Like in `.mcp.json`, an entry without a `type` is a
+/// stdio server.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum McpServerConfig {
+    Stdio(McpStdioServerConfig),
+    Sse(McpSseServerConfig),
+    Http(McpHttpServerConfig),
+    /// In-process server, driven over the control channel.
+    Sdk(Arc<SdkMcpServer>),
+}
+
+impl<'de> Deserialize<'de> for McpServerConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(tag = "type")]
+        #[serde(rename_all = "snake_case")]
+        enum External {
+            Stdio(McpStdioServerConfig),
+            Sse(McpSseServerConfig),
+            Http(McpHttpServerConfig),
+        }
+
+        let mut value = Value::deserialize(deserializer)?;
+        if let Some(obj) = value.as_object_mut() {
+            obj.entry("type").or_insert_with(|| "stdio".into());
+        }
+        let config = match External::deserialize(value).map_err(D::Error::custom)? {
+            External::Stdio(c) => McpServerConfig::Stdio(c),
+            External::Sse(c) => McpServerConfig::Sse(c),
+            External::Http(c) => McpServerConfig::Http(c),
+        };
+        Ok(config)
+    }
+}
+
+/// Parses the servers of an MCP config, given either as the bare name-to-server map or
+/// wrapped in `{"mcpServers": {...}}` like `.mcp.json`.
+pub fn parse_mcp_servers(config: Value) -> serde_json::Result<HashMap<String, McpServerConfig>> {
+    let config = match config {
+        Value::Object(mut obj) if obj.len() == 1 && obj.contains_key("mcpServers") => {
+            obj.remove("mcpServers").unwrap()
+        }
+        config => config,
+    };
+    serde_json::from_value(config)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct McpStdioServerConfig {
+    pub command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub env: Option<HashMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct McpSseServerConfig {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<HashMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct McpHttpServerConfig {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<HashMap<String, String>>,
+}
+
+impl From<SdkMcpServer> for McpServerConfig {
+    fn from(value: SdkMcpServer) -> Self {
+        McpServerConfig::Sdk(Arc::new(value))
+    }
+}
+
+impl From<McpStdioServerConfig> for McpServerConfig {
+    fn from(value: McpStdioServerConfig) -> Self {
+        McpServerConfig::Stdio(value)
+    }
+}
+
+impl From<McpSseServerConfig> for McpServerConfig {
+    fn from(value: McpSseServerConfig) -> Self {
+        McpServerConfig::Sse(value)
+    }
+}
+
+impl From<McpHttpServerConfig> for McpServerConfig {
+    fn from(value: McpHttpServerConfig) -> Self {
+        McpServerConfig::Http(value)
+    }
+}
+
+pub struct SdkMcpServer {
+    name: String,
+    version: String,
+    tools: HashMap<String, SdkMcpTool>,
+}
+
+impl Debug for SdkMcpServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SdkMcpServer")
+            .field("name", &self.name)
+            .field("version", &self.version)
+            .field("tools", &self.tools.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// The CLI only needs to know the name, the tools are served through `mcp_message`.
+impl Serialize for SdkMcpServer {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        json!({ "name": self.name }).serialize(serializer)
+    }
+}
+
+pub struct SdkMcpTool {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value,
+    handler: BoxedMcpToolHandler,
+}
+
+impl SdkMcpTool {
+    pub fn new<H: McpToolHandler>(
+        name: impl Into<String>,
+        description: impl Into<String>,
+        input_schema: Value,
+        handler: H,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            description: description.into(),
+            input_schema,
+            handler: Box::new(handler),
+        }
+    }
+}
+
+pub trait McpToolHandler: Send + Sync + 'static {
+    fn call(&self, arguments: Value)
+    -> impl Future<Output = anyhow::Result<CallToolResult>> + Send;
+}
+
+impl<F, Fut> McpToolHandler for F
+where
+    F: Fn(Value) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<CallToolResult>> + Send,
+{
+    fn call(
+        &self,
+        arguments: Value,
+    ) -> impl Future<Output = anyhow::Result<CallToolResult>> + Send {
+        self(arguments)
+    }
+}
+
+#[async_trait::async_trait]
+pub trait McpToolHandlerDyn: Send + Sync + 'static {
+    async fn call(&self, arguments: Value) -> anyhow::Result<CallToolResult>;
+}
+
+#[async_trait::async_trait]
+impl<T> McpToolHandlerDyn for T
+where
+    T: McpToolHandler,
+{
+    async fn call(&self, arguments: Value) -> anyhow::Result<CallToolResult> {
+        McpToolHandler::call(self, arguments).await
+    }
+}
+
+pub type BoxedMcpToolHandler = Box<dyn McpToolHandlerDyn>;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CallToolResult {
+    pub content: Vec<McpContent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_error: Option<bool>,
+}
+
+impl CallToolResult {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self {
+            content: vec![McpContent::Text { text: text.into() }],
+            is_error: None,
+        }
+    }
+
+    pub fn error(text: impl Into<String>) -> Self {
+        Self {
+            content: vec![McpContent::Text { text: text.into() }],
+            is_error: Some(true),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum McpContent {
+    Text {
+        text: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Image {
+        data: String,
+        mime_type: String,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonRpcRequest {
+    /// Notifications have no id.
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct CallToolParams {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+impl SdkMcpServer {
+    pub fn new(name: impl Into<String>, version: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            version: version.into(),
+            tools: Default::default(),
+        }
+    }
+
+    pub fn tool(mut self, tool: SdkMcpTool) -> Self {
+        self.tools.insert(tool.name.clone(), tool);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Handles one JSON-RPC message from the CLI and returns the JSON-RPC response.
+    /// Notifications, like `notifications/initialized`, get no response.
+    pub async fn handle_message(&self, message: Value) -> Option<Value> {
+        let req: JsonRpcRequest = match serde_json::from_value(message) {
+            Ok(req) => req,
+            Err(err) => {
+                return Some(json_rpc_error(
+                    Value::Null,
+                    INVALID_PARAMS,
+                    format!("{err}"),
+                ));
+            }
+        };
+        debug!(
+            server = self.name,
+            method = req.method,
+            "handle mcp message"
+        );
+        // Notifications have no id and get no response.
+        let id = req.id?;
+
+        let resp = match &*req.method {
+            "initialize" => json_rpc_result(
+                id,
+                json!({
+                    "protocolVersion": MCP_PROTOCOL_VERSION,
+                    "capabilities": { "tools": {} },
+                    "serverInfo": { "name": self.name, "version": self.version }
+                }),
+            ),
+            "tools/list" => {
+                let tools = self
+                    .tools
+                    .values()
+                    .map(|tool| {
+                        json!({
+                            "name": tool.name,
+                            "description": tool.description,
+                            "inputSchema": tool.input_schema,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                json_rpc_result(id, json!({ "tools": tools }))
+            }
+            "tools/call" => {
+                let params: CallToolParams = match serde_json::from_value(req.params) {
+                    Ok(p) => p,
+                    Err(err) => {
+                        return Some(json_rpc_error(id, INVALID_PARAMS, format!("{err}")));
+                    }
+                };
+                let Some(tool) = self.tools.get(&params.name) else {
+                    let msg = format!("Tool not found: {}", params.name);
+                    return Some(json_rpc_error(id, INVALID_PARAMS, msg));
+                };
+                let result = match tool.handler.call(params.arguments).await {
+                    Ok(r) => r,
+                    Err(err) => CallToolResult::error(format!("{err:#}")),
+                };
+                json_rpc_result(id, serde_json::to_value(result).unwrap())
+            }
+            method => {
+                let msg = format!("Method not found: {method}");
+                json_rpc_error(id, METHOD_NOT_FOUND, msg)
+            }
+        };
+        Some(resp)
+    }
+}
+
+fn json_rpc_result(id: Value, result: Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": result,
+    })
+}
+
+fn json_rpc_error(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mcp_config() -> anyhow::Result<()> {
+        let servers: HashMap<String, McpServerConfig> = serde_json::from_value(json!({
+            "fs": {
+                "type": "stdio",
+                "command": "npx",
+                "args": ["-y", "@modelcontextprotocol/server-filesystem", "/tmp"]
+            },
+            "docs": { "type": "http", "url": "https://example.com/mcp" }
+        }))?;
+        let mut servers = servers;
+        servers.insert("niu".to_string(), server().into());
+
+        let config = serde_json::to_value(&servers)?;
+        assert_eq!(config["fs"]["args"][0], "-y");
+        assert_eq!(config["docs"]["type"], "http");
+        assert_eq!(config["niu"], json!({ "type": "sdk", "name": "niu" }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mcp_json() -> anyhow::Result<()> {
+        let mcp_json = r#"{
+          "mcpServers": {
+            "filesystem": {
+              "command": "npx",
+              "args": ["-y", "@modelcontextprotocol/server-filesystem", "/tmp"],
+              "env": { "DEBUG": "1" }
+            },
+            "github": {
+              "type": "http",
+              "url": "https://api.githubcopilot.com/mcp/",
+              "headers": { "Authorization": "Bearer token" }
+            },
+            "events": { "type": "sse", "url": "http://localhost:8080/sse" }
+          }
+        }"#;
+        let servers = parse_mcp_servers(serde_json::from_str(mcp_json)?)?;
+        assert_eq!(servers.len(), 3);
+        let McpServerConfig::Stdio(fs) = &servers["filesystem"] else {
+            panic!("expected a stdio server: {:?}", servers["filesystem"]);
+        };
+        assert_eq!(fs.command, "npx");
+        assert!(matches!(servers["github"], McpServerConfig::Http(..)));
+        assert!(matches!(servers["events"], McpServerConfig::Sse(..)));
+        assert_eq!(
+            serde_json::to_value(&servers)?["filesystem"]["type"],
+            "stdio"
+        );
+
+        // The bare map works too.
+        let servers = parse_mcp_servers(json!({ "fs": { "command": "npx" } }))?;
+        assert!(matches!(servers["fs"], McpServerConfig::Stdio(..)));
+
+        // Only the CLI side can create sdk servers.
+        assert!(parse_mcp_servers(json!({ "niu": { "type": "sdk", "name": "niu" } })).is_err());
+
+        Ok(())
+    }
+
+    fn server() -> SdkMcpServer {
+        SdkMcpServer::new("niu", "0.1.0").tool(SdkMcpTool::new(
+            "echo",
+            "Echo the input text",
+            json!({
+                "type": "object",
+                "properties": { "text": { "type": "string" } },
+                "required": ["text"]
+            }),
+            |args: Value| async move {
+                let text = args["text"].as_str().unwrap_or_default().to_owned();
+                Ok(CallToolResult::text(text))
+            },
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_tools_list_and_call() {
+        let server = server();
+
+        let resp = server
+            .handle_message(json!({"jsonrpc": "2.0", "id": 1, "method": "tools/list"}))
+            .await
+            .unwrap();
+        assert_eq!(resp["result"]["tools"][0]["name"], "echo");
+
+        let resp = server
+            .handle_message(json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "tools/call",
+                "params": { "name": "echo", "arguments": { "text": "hello" } }
+            }))
+            .await
+            .unwrap();
+        assert_eq!(resp["id"], 2);
+        assert_eq!(resp["result"]["content"][0]["text"], "hello");
+
+        let resp = server
+            .handle_message(json!({"jsonrpc": "2.0", "id": 3, "method": "resources/list"}))
+            .await
+            .unwrap();
+        assert_eq!(resp["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_notification() {
+        let server = server();
+
+        let resp = server
+            .handle_message(json!({"jsonrpc": "2.0", "method": "notifications/initialized"}))
+            .await;
+        assert!(resp.is_none());
+        let resp = server
+            .handle_message(json!({"jsonrpc": "2.0", "method": "notifications/cancelled"}))
+            .await;
+        assert!(resp.is_none());
+    }
+}
@@ -0,0 +1,81 @@
+//! Helpers shared by the permission rules of the CLI settings and by policies.
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::types::ToolUseParams;
+
+/// The commands of a `&&`, `||`, `;` or `|` chain. Quotes are not parsed, so a quoted
+/// operator splits too, which only makes allow rules stricter.
+pub(crate) fn split_commands(command: &str) -> impl Iterator<Item = &str> {
+    command
+        .split([';', '|', '&', '\n'])
+        .map(str::trim)
+        .filter(|command| !command.is_empty())
+}
+
+/// Like [`split_commands`], but also splits out the commands inside `$(...)`, backticks,
+/// `( ... )` subshells and `{ ...; }` groups, so deny and ask rules see them. The leftovers,
+/// like `echo $`, only make those rules match more.
+pub(crate) fn split_nested_commands(command: &str) -> impl Iterator<Item = &str> {
+    command
+        .split([';', '|', '&', '\n', '`', '(', ')', '{', '}'])
+        .map(str::trim)
+        .filter(|command| !command.is_empty())
+}
+
+/// Redirections and command substitutions, which an allow rule can't vouch for.
+pub(crate) fn has_unsafe_shell(command: &str) -> bool {
+    ["`", "$(", ">", "<"].iter().any(|s| command.contains(s))
+}
+
+pub(crate) fn tool_name(tool_use: &ToolUseParams) -> &str {
+    match tool_use {
+        ToolUseParams::Mcp { tool_name, .. } => tool_name.as_str(),
+        _ => tool_use.tool_name(),
+    }
+}
+
+/// The absolute path a file tool works on.
+pub(crate) fn tool_path(tool_use: &ToolUseParams, cwd: &Path) -> Option<PathBuf> {
+    let path = match tool_use {
+        ToolUseParams::Read { input } => Some(&input.file_path),
+        ToolUseParams::Write { input } => Some(&input.file_path),
+        ToolUseParams::Edit { input } => Some(&input.file_path),
+        ToolUseParams::MultiEdit { input } => Some(&input.file_path),
+        ToolUseParams::NotebookEdit { input } => Some(&input.notebook_path),
+        ToolUseParams::Glob { input } => input.path.as_ref(),
+        ToolUseParams::Grep { input } => input.path.as_ref(),
+        _ => return None,
+    };
+    let path = path.map_or(cwd, Path::new);
+    Some(normalize(path, cwd))
+}
+
+/// Makes the path absolute and removes `.` and `..` without touching the file system.
+pub(crate) fn normalize(path: &Path, cwd: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+pub(crate) fn url_host(url: &str) -> Option<String> {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    // WHATWG URL parsers, like the one in Node, also end the authority at `\`
+    let authority = rest.split(['/', '\\', '?', '#']).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
@@ -0,0 +1,420 @@
+//! Rule-based permission decisions, so that routine tool calls don't need a human.
+//!
+//! Rules are checked in order and the first match wins. A policy in JSON:
+//!
+//! ```json
+//! {
+//!   "default": "ask",
+//!   "rules": [
+//!     { "action": "allow", "tool": "Bash", "command": ["git status*", "git diff*"] },
+//!     { "action": "deny", "tool": "Write", "outside_cwd": true, "reason": "Stay in the project" },
+//!     { "action": "allow", "tool": "WebFetch", "domain": ["docs.rs", "*.rust-lang.org"] },
+//!     { "action": "ask", "tool": "WebFetch" }
+//!   ]
+//! }
+//! ```
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    permission_util::{
+        has_unsafe_shell, normalize, split_commands, split_nested_commands, tool_name, tool_path,
+        url_host,
+    },
+    types::{
+        BoxedCanUseTollCallback, CanUseToolCallBack, PermissionAllow, PermissionDeny,
+        PermissionResult, PermissionUpdate, ToolUseParams,
+    },
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyAction {
+    Allow,
+    Deny,
+    /// Leave the decision to the fallback callback, usually a human.
+    #[default]
+    Ask,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct Policy {
+    /// Applied when no rule matches.
+    #[serde(default)]
+    pub default: PolicyAction,
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+}
+
+/// A rule matches if the tool name matches and every condition that is set matches.
+///
+/// Patterns support `*` (any characters, `/` included) and `?` (one character).
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PolicyRule {
+    pub action: PolicyAction,
+    /// Tool name pattern, e.g. `Bash` or `mcp__github__*`.
+    pub tool: String,
+    /// `Bash` command patterns, checked against each command of a `&&`, `||`, `;` or `|` chain.
+    /// An allow rule must match every command, and never matches redirections or command
+    /// substitutions. Other rules match if any command matches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<Patterns>,
+    /// Path patterns of file tools. Relative patterns are resolved against the cwd.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<Patterns>,
+    /// Whether the path of a file tool is outside the cwd. `Glob` and `Grep` without a path
+    /// search the cwd. Paths are compared lexically, symlinks are not resolved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outside_cwd: Option<bool>,
+    /// `WebFetch` host patterns, e.g. `docs.rs` or `*.github.com`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<Patterns>,
+    /// Sent to Claude when the rule denies a tool call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// One pattern or a list of patterns.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(from = "OneOrMany")]
+pub struct Patterns(pub Vec<String>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<OneOrMany> for Patterns {
+    fn from(value: OneOrMany) -> Self {
+        match value {
+            OneOrMany::One(pattern) => Patterns(vec![pattern]),
+            OneOrMany::Many(patterns) => Patterns(patterns),
+        }
+    }
+}
+
+impl Patterns {
+    fn matches(&self, text: &str) -> bool {
+        self.0.iter().any(|pattern| wildcard_match(pattern, text))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PolicyDecision<'a> {
+    pub action: PolicyAction,
+    /// The rule that matched. `None` if the default action applies.
+    pub rule: Option<&'a PolicyRule>,
+}
+
+impl Policy {
+    pub fn from_json(s: &str) -> Result<Self> {
+        serde_json::from_str(s).context("Invalid permission policy")
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self> {
+        toml::from_str(s).context("Invalid permission policy")
+    }
+
+    /// Loads a `.toml` file as TOML, and any other file as JSON.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read permission policy {}", path.display()))?;
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            Self::from_toml(&content)
+        } else {
+            Self::from_json(&content)
+        }
+    }
+
+    pub fn evaluate(&self, tool_use: &ToolUseParams, cwd: &Path) -> PolicyDecision<'_> {
+        let cwd = normalize(cwd, Path::new("/"));
+        let rule = self.rules.iter().find(|rule| rule.matches(tool_use, &cwd));
+        PolicyDecision {
+            action: rule.map_or(self.default, |rule| rule.action),
+            rule,
+        }
+    }
+}
+
+impl PolicyRule {
+    fn matches(&self, tool_use: &ToolUseParams, cwd: &Path) -> bool {
+        if !wildcard_match(&self.tool, tool_name(tool_use)) {
+            return false;
+        }
+
+        if let Some(patterns) = &self.command {
+            let ToolUseParams::Bash { input } = tool_use else {
+                return false;
+            };
+            if !self.matches_command(patterns, &input.command) {
+                return false;
+            }
+        }
+
+        if self.path.is_some() || self.outside_cwd.is_some() {
+            let Some(path) = tool_path(tool_use, cwd) else {
+                return false;
+            };
+            if let Some(outside_cwd) = self.outside_cwd
+                && path.starts_with(cwd) == outside_cwd
+            {
+                return false;
+            }
+            if let Some(patterns) = &self.path {
+                let path = path.to_string_lossy();
+                let matched = patterns.0.iter().any(|pattern| {
+                    let pattern = cwd.join(pattern);
+                    wildcard_match(&pattern.to_string_lossy(), &path)
+                });
+                if !matched {
+                    return false;
+                }
+            }
+        }
+
+        if let Some(patterns) = &self.domain {
+            let ToolUseParams::WebFetch { input } = tool_use else {
+                return false;
+            };
+            let Some(host) = url_host(&input.url) else {
+                return false;
+            };
+            if !patterns
+                .0
+                .iter()
+                .any(|pattern| wildcard_match(&pattern.to_ascii_lowercase(), &host))
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn matches_command(&self, patterns: &Patterns, command: &str) -> bool {
+        if self.action == PolicyAction::Allow {
+            !has_unsafe_shell(command)
+                && split_commands(command).all(|command| patterns.matches(command))
+        } else {
+            split_nested_commands(command).any(|command| patterns.matches(command))
+        }
+    }
+}
+
+/// A [`CanUseToolCallBack`] that decides by a [`Policy`], and asks the fallback for `ask`.
+/// Without a fallback, `ask` is denied.
+#[derive(Debug)]
+pub struct PolicyCallback {
+    policy: Policy,
+    cwd: PathBuf,
+    fallback: Option<BoxedCanUseTollCallback>,
+}
+
+impl PolicyCallback {
+    /// `cwd` should be the cwd of the CLI, relative paths and patterns are resolved against it.
+    pub fn new(policy: Policy, cwd: impl Into<PathBuf>) -> Self {
+        Self {
+            policy,
+            cwd: cwd.into(),
+            fallback: None,
+        }
+    }
+
+    pub fn with_fallback(mut self, fallback: BoxedCanUseTollCallback) -> Self {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+}
+
+impl CanUseToolCallBack for PolicyCallback {
+    async fn call(
+        &mut self,
+        tool_use: ToolUseParams,
+        suggestions: Option<Vec<PermissionUpdate>>,
+    ) -> Result<Arc<PermissionResult>> {
+        let decision = self.policy.evaluate(&tool_use, &self.cwd);
+        tracing::debug!(
+            tool = tool_name(&tool_use),
+            action = ?decision.action,
+            rule = ?decision.rule,
+            "Permission policy decision"
+        );
+
+        let result = match decision.action {
+            PolicyAction::Allow => PermissionResult::Allow(PermissionAllow {
+                updated_input: tool_use.into(),
+                updated_permissions: None,
+            }),
+            PolicyAction::Deny => {
+                let message = decision
+                    .rule
+                    .and_then(|rule| rule.reason.clone())
+                    .unwrap_or_else(|| {
+                        format!(
+                            "{} is denied by the permission policy",
+                            tool_name(&tool_use)
+                        )
+                    });
+                PermissionResult::Deny(PermissionDeny {
+                    message,
+                    interrupt: None,
+                })
+            }
+            PolicyAction::Ask => match &mut self.fallback {
+                Some(fallback) => return fallback.call(tool_use, suggestions).await,
+                None => PermissionResult::Deny(PermissionDeny {
+                    message: format!(
+                        "{} needs approval, but no one can approve it",
+                        tool_name(&tool_use)
+                    ),
+                    interrupt: None,
+                }),
+            },
+        };
+
+        Ok(Arc::new(result))
+    }
+}
+
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` is, and how much text it has taken.
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn tool_use(value: serde_json::Value) -> ToolUseParams {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn bash(command: &str) -> ToolUseParams {
+        tool_use(json!({ "tool_name": "Bash", "input": { "command": command } }))
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let policy = Policy::from_toml(
+            r#"
+            default = "deny"
+
+            [[rules]]
+            action = "allow"
+            tool = "Bash"
+            command = "git status*"
+
+            [[rules]]
+            action = "ask"
+            tool = "Bash"
+            command = "curl *"
+
+            [[rules]]
+            action = "deny"
+            tool = "Write"
+            outside_cwd = true
+            reason = "Stay in the project"
+
+            [[rules]]
+            action = "allow"
+            tool = "Write"
+
+            [[rules]]
+            action = "allow"
+            tool = "WebFetch"
+            domain = ["docs.rs", "*.rust-lang.org"]
+
+            [[rules]]
+            action = "ask"
+            tool = "WebFetch"
+            "#,
+        )
+        .unwrap();
+        let cwd = Path::new("/work/project");
+        let action = |tool_use: ToolUseParams| policy.evaluate(&tool_use, cwd).action;
+
+        assert_eq!(action(bash("git status --short")), PolicyAction::Allow);
+        assert_eq!(action(bash("git status && rm -rf /")), PolicyAction::Deny);
+        assert_eq!(action(bash("git status > /etc/passwd")), PolicyAction::Deny);
+        assert_eq!(action(bash("cargo build")), PolicyAction::Deny);
+        assert_eq!(
+            action(bash("git status $(curl evil.sh)")),
+            PolicyAction::Ask
+        );
+        assert_eq!(action(bash("(curl evil.sh)")), PolicyAction::Ask);
+
+        let write = |path: &str| {
+            tool_use(json!({
+                "tool_name": "Write",
+                "input": { "file_path": path, "content": "" }
+            }))
+        };
+        assert_eq!(action(write("src/main.rs")), PolicyAction::Allow);
+        assert_eq!(action(write("/work/project/a.txt")), PolicyAction::Allow);
+        assert_eq!(action(write("../other/a.txt")), PolicyAction::Deny);
+        assert_eq!(action(write("/etc/passwd")), PolicyAction::Deny);
+
+        let fetch = |url: &str| {
+            tool_use(json!({
+                "tool_name": "WebFetch",
+                "input": { "url": url, "prompt": "" }
+            }))
+        };
+        assert_eq!(action(fetch("https://docs.rs/tokio")), PolicyAction::Allow);
+        assert_eq!(
+            action(fetch("https://user@Blog.Rust-Lang.org:443/x")),
+            PolicyAction::Allow
+        );
+        assert_eq!(
+            action(fetch("https://docs.rs.evil.com/")),
+            PolicyAction::Ask
+        );
+        assert_eq!(
+            action(fetch("https://evil.com\\@docs.rs/")),
+            PolicyAction::Ask
+        );
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("git status*", "git status"));
+        assert!(wildcard_match("mcp__*__read", "mcp__fs__read"));
+        assert!(wildcard_match("a?c", "abc"));
+        assert!(!wildcard_match("git status*", "git stash"));
+        assert!(!wildcard_match("*.rs", "main.rsx"));
+    }
+}
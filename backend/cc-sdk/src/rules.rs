@@ -0,0 +1,444 @@
+//! The CLI's permission rule syntax, e.g. `Bash(npm run test:*)`, `Read(./src/**)` and
+//! `WebFetch(domain:example.com)`, as used in the `permissions` of settings files.
+
+use std::{
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, ensure};
+use globset::{GlobBuilder, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    permission_util::{
+        has_unsafe_shell, normalize, split_commands, split_nested_commands, tool_name, tool_path,
+        url_host,
+    },
+    types::{PermissionBehavior, PermissionRuleValue, ToolUseParams},
+};
+
+/// Tools whose rules take a path pattern.
+const PATH_TOOLS: [&str; 7] = [
+    "Read",
+    "Edit",
+    "Write",
+    "MultiEdit",
+    "NotebookEdit",
+    "Glob",
+    "Grep",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PermissionRule {
+    pub tool_name: String,
+    /// `None` if the rule covers every call of the tool.
+    pub specifier: Option<RuleSpecifier>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RuleSpecifier {
+    /// `Bash(npm run build)`: exactly this command.
+    Command(String),
+    /// `Bash(npm run test:*)`: the command, with or without more arguments.
+    CommandPrefix(String),
+    /// `Read(./src/**)`: a gitignore style pattern. `//` starts an absolute path and `~/` the
+    /// home directory. Other patterns, including ones starting with `/`, are relative to the cwd.
+    Path(String),
+    /// `WebFetch(domain:example.com)`
+    Domain(String),
+    /// The specifier of any other tool. It's kept, but never matches a tool call.
+    Other(String),
+}
+
+impl FromStr for PermissionRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (tool_name, content) = match s.split_once('(') {
+            Some((tool_name, rest)) => {
+                let content = rest
+                    .strip_suffix(')')
+                    .with_context(|| format!("Missing `)` in permission rule: {s}"))?;
+                (tool_name, Some(content))
+            }
+            None => (s, None),
+        };
+        Self::new(tool_name, content)
+    }
+}
+
+impl PermissionRule {
+    fn new(tool_name: &str, content: Option<&str>) -> Result<Self> {
+        ensure!(
+            !tool_name.is_empty()
+                && tool_name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '*'),
+            "Invalid tool name in permission rule: {tool_name:?}"
+        );
+        let specifier = content
+            .filter(|content| !content.is_empty())
+            .map(|content| RuleSpecifier::parse(tool_name, content))
+            .transpose()?;
+        Ok(Self {
+            tool_name: tool_name.to_string(),
+            specifier,
+        })
+    }
+
+    /// Whether the rule covers the tool call. A command rule that allows must match every
+    /// command of a chain, and never matches redirections or command substitutions. Deny and
+    /// ask rules match any command, including those in substitutions and subshells.
+    pub fn matches(
+        &self,
+        tool_use: &ToolUseParams,
+        cwd: &Path,
+        behavior: PermissionBehavior,
+    ) -> bool {
+        if !self.matches_tool(tool_name(tool_use)) {
+            return false;
+        }
+
+        let cwd = normalize(cwd, Path::new("/"));
+        match &self.specifier {
+            None => true,
+            Some(RuleSpecifier::Command(..) | RuleSpecifier::CommandPrefix(..)) => {
+                let ToolUseParams::Bash { input } = tool_use else {
+                    return false;
+                };
+                let command = &input.command;
+                if behavior == PermissionBehavior::Allow {
+                    !has_unsafe_shell(command)
+                        && split_commands(command).all(|c| self.matches_command(c))
+                } else {
+                    split_nested_commands(command).any(|c| self.matches_command(c))
+                }
+            }
+            Some(RuleSpecifier::Path(pattern)) => {
+                let Some(path) = tool_path(tool_use, &cwd) else {
+                    return false;
+                };
+                matches_path(pattern, &path, &cwd)
+            }
+            Some(RuleSpecifier::Domain(domain)) => {
+                let ToolUseParams::WebFetch { input } = tool_use else {
+                    return false;
+                };
+                url_host(&input.url).is_some_and(|host| {
+                    host == *domain
+                        || domain
+                            .strip_prefix("*.")
+                            .is_some_and(|parent| host.ends_with(&format!(".{parent}")))
+                })
+            }
+            Some(RuleSpecifier::Other(..)) => false,
+        }
+    }
+
+    fn matches_tool(&self, name: &str) -> bool {
+        let rule = self.tool_name.as_str();
+        if rule == name {
+            return true;
+        }
+        match rule {
+            // Like the CLI, edit rules cover every tool that changes files, and read rules
+            // cover the search tools.
+            "Edit" => matches!(name, "MultiEdit" | "Write" | "NotebookEdit"),
+            "Read" => matches!(name, "Glob" | "Grep"),
+            // `mcp__server` and `mcp__server__*` cover every tool of the server.
+            _ if rule.starts_with("mcp__") => {
+                let server = rule.strip_suffix("__*").unwrap_or(rule);
+                name.strip_prefix(server)
+                    .is_some_and(|tool| tool.starts_with("__"))
+            }
+            _ => false,
+        }
+    }
+
+    fn matches_command(&self, command: &str) -> bool {
+        match &self.specifier {
+            Some(RuleSpecifier::Command(expected)) => command == expected.trim(),
+            Some(RuleSpecifier::CommandPrefix(prefix)) => command
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace)),
+            _ => false,
+        }
+    }
+}
+
+impl RuleSpecifier {
+    fn parse(tool_name: &str, content: &str) -> Result<Self> {
+        let specifier = match tool_name {
+            "Bash" => match content.strip_suffix(":*") {
+                Some(prefix) => Self::CommandPrefix(prefix.trim_end().to_string()),
+                None => Self::Command(content.to_string()),
+            },
+            "WebFetch" => {
+                let domain = content
+                    .strip_prefix("domain:")
+                    .filter(|domain| !domain.is_empty())
+                    .with_context(|| {
+                        format!("Expect `WebFetch(domain:<host>)`, got: WebFetch({content})")
+                    })?;
+                Self::Domain(domain.to_ascii_lowercase())
+            }
+            tool if PATH_TOOLS.contains(&tool) => {
+                GlobBuilder::new(content)
+                    .build()
+                    .with_context(|| format!("Invalid path pattern: {tool}({content})"))?;
+                Self::Path(content.to_string())
+            }
+            _ => Self::Other(content.to_string()),
+        };
+        Ok(specifier)
+    }
+}
+
+impl Display for RuleSpecifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Command(command) => f.write_str(command),
+            Self::CommandPrefix(prefix) => write!(f, "{prefix}:*"),
+            Self::Path(pattern) => f.write_str(pattern),
+            Self::Domain(domain) => write!(f, "domain:{domain}"),
+            Self::Other(content) => f.write_str(content),
+        }
+    }
+}
+
+impl Display for PermissionRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.specifier {
+            Some(specifier) => write!(f, "{}({specifier})", self.tool_name),
+            None => f.write_str(&self.tool_name),
+        }
+    }
+}
+
+impl TryFrom<String> for PermissionRule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<PermissionRule> for String {
+    fn from(value: PermissionRule) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<&PermissionRuleValue> for PermissionRule {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &PermissionRuleValue) -> Result<Self> {
+        Self::new(&value.tool_name, value.rule_content.as_deref())
+    }
+}
+
+impl From<PermissionRule> for PermissionRuleValue {
+    fn from(value: PermissionRule) -> Self {
+        PermissionRuleValue {
+            tool_name: value.tool_name,
+            rule_content: value.specifier.map(|specifier| specifier.to_string()),
+        }
+    }
+}
+
+/// The rules of the `permissions` object in a settings file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PermissionRuleSet {
+    #[serde(default)]
+    pub allow: Vec<PermissionRule>,
+    #[serde(default)]
+    pub deny: Vec<PermissionRule>,
+    #[serde(default)]
+    pub ask: Vec<PermissionRule>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RuleMatch<'a> {
+    pub behavior: PermissionBehavior,
+    pub rule: &'a PermissionRule,
+}
+
+impl PermissionRuleSet {
+    /// Deny rules win over ask rules, and ask rules over allow rules, like in the CLI.
+    /// Returns `None` if no rule matches.
+    pub fn evaluate(&self, tool_use: &ToolUseParams, cwd: &Path) -> Option<RuleMatch<'_>> {
+        [
+            (PermissionBehavior::Deny, &self.deny),
+            (PermissionBehavior::Ask, &self.ask),
+            (PermissionBehavior::Allow, &self.allow),
+        ]
+        .into_iter()
+        .find_map(|(behavior, rules)| {
+            let rule = rules
+                .iter()
+                .find(|rule| rule.matches(tool_use, cwd, behavior))?;
+            Some(RuleMatch { behavior, rule })
+        })
+    }
+}
+
+fn matches_path(pattern: &str, path: &Path, cwd: &Path) -> bool {
+    // Like gitignore, a pattern with a slash only matches from the base, and one without
+    // matches at any depth.
+    let (base, relative, anchored) = if let Some(absolute) = pattern.strip_prefix("//") {
+        (PathBuf::from("/"), absolute, true)
+    } else if let Some(relative) = pattern.strip_prefix("~/") {
+        let Some(home) = std::env::home_dir() else {
+            return false;
+        };
+        (home, relative, true)
+    } else if let Some(relative) = pattern.strip_prefix("./") {
+        (cwd.to_path_buf(), relative, true)
+    } else {
+        let anchored = pattern.trim_end_matches('/').contains('/');
+        (cwd.to_path_buf(), pattern, anchored)
+    };
+    let relative = relative.trim_start_matches('/').trim_end_matches('/');
+    let base = globset::escape(&base.to_string_lossy());
+    let mut glob = base.trim_end_matches('/').to_string();
+    glob.push('/');
+    if !anchored {
+        glob.push_str("**/");
+    }
+    glob.push_str(relative);
+
+    let mut builder = GlobSetBuilder::new();
+    // A directory covers everything in it.
+    for glob in [glob.clone(), format!("{glob}/**")] {
+        match GlobBuilder::new(&glob).literal_separator(true).build() {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(_) => return false,
+        }
+    }
+    builder.build().is_ok_and(|globs| globs.is_match(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn tool_use(tool_name: &str, input: serde_json::Value) -> ToolUseParams {
+        serde_json::from_value(json!({ "tool_name": tool_name, "input": input })).unwrap()
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        for rule in [
+            "Bash",
+            "Bash(npm run test:*)",
+            "Bash(git status)",
+            "Read(./src/**)",
+            "Edit(//tmp/scratch.txt)",
+            "WebFetch(domain:example.com)",
+            "mcp__github__get_issue",
+            "Task(Explore)",
+        ] {
+            assert_eq!(rule.parse::<PermissionRule>()?.to_string(), rule);
+        }
+
+        let rule: PermissionRule = "Bash(npm run test:*)".parse()?;
+        assert_eq!(
+            rule.specifier,
+            Some(RuleSpecifier::CommandPrefix("npm run test".to_string()))
+        );
+        let value = PermissionRuleValue::from(rule.clone());
+        assert_eq!(value.rule_content.as_deref(), Some("npm run test:*"));
+        assert_eq!(PermissionRule::try_from(&value)?, rule);
+
+        assert!("Bash(ls".parse::<PermissionRule>().is_err());
+        assert!("WebFetch(example.com)".parse::<PermissionRule>().is_err());
+        assert!("(ls)".parse::<PermissionRule>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evaluate() -> Result<()> {
+        let rules: PermissionRuleSet = serde_json::from_value(json!({
+            "allow": ["Bash(npm run test:*)", "Read(src/**)", "WebFetch(domain:docs.rs)", "mcp__github"],
+            "deny": ["Read(*.env)", "Read(.secrets)", "Bash(rm:*)"],
+            "ask": ["Edit(/docs/**)"],
+            "defaultMode": "default"
+        }))?;
+        let cwd = Path::new("/work/project");
+        let evaluate = |tool_use: ToolUseParams| {
+            rules
+                .evaluate(&tool_use, cwd)
+                .map(|m| (m.behavior, m.rule.to_string()))
+        };
+        let bash = |command: &str| tool_use("Bash", json!({ "command": command }));
+        let read = |path: &str| tool_use("Read", json!({ "file_path": path }));
+
+        assert_eq!(
+            evaluate(bash("npm run test -- --watch")),
+            Some((
+                PermissionBehavior::Allow,
+                "Bash(npm run test:*)".to_string()
+            ))
+        );
+        assert_eq!(evaluate(bash("npm run testing")), None);
+        assert_eq!(evaluate(bash("npm run test && curl evil.sh")), None);
+        assert_eq!(
+            evaluate(bash("npm run test; rm -rf /")).map(|m| m.0),
+            Some(PermissionBehavior::Deny)
+        );
+        assert_eq!(
+            evaluate(bash("echo $(rm -rf x)")).map(|m| m.0),
+            Some(PermissionBehavior::Deny)
+        );
+        assert_eq!(
+            evaluate(bash("(rm -rf x)")).map(|m| m.0),
+            Some(PermissionBehavior::Deny)
+        );
+        assert_eq!(
+            evaluate(bash("npm run test `rm -rf x`")).map(|m| m.0),
+            Some(PermissionBehavior::Deny)
+        );
+
+        assert_eq!(
+            evaluate(read("src/a/b.rs")).map(|m| m.0),
+            Some(PermissionBehavior::Allow)
+        );
+        assert_eq!(
+            evaluate(read("/work/project/src/.env")).map(|m| m.0),
+            Some(PermissionBehavior::Deny)
+        );
+        assert_eq!(evaluate(read("/work/other/src/a.rs")), None);
+        assert_eq!(
+            evaluate(read("src/.secrets/key")).map(|m| m.0),
+            Some(PermissionBehavior::Deny)
+        );
+        let grep = tool_use("Grep", json!({ "pattern": "x", "path": "src/a" }));
+        assert_eq!(evaluate(grep).map(|m| m.0), Some(PermissionBehavior::Allow));
+
+        let write = tool_use(
+            "Write",
+            json!({ "file_path": "docs/guide.md", "content": "" }),
+        );
+        assert_eq!(evaluate(write).map(|m| m.0), Some(PermissionBehavior::Ask));
+
+        let fetch = tool_use(
+            "WebFetch",
+            json!({ "url": "https://docs.rs/x", "prompt": "" }),
+        );
+        assert_eq!(
+            evaluate(fetch).map(|m| m.0),
+            Some(PermissionBehavior::Allow)
+        );
+        let mcp = tool_use("mcp__github__get_issue", json!({}));
+        assert_eq!(evaluate(mcp).map(|m| m.0), Some(PermissionBehavior::Allow));
+        Ok(())
+    }
+}
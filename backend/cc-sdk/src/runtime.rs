@@ -0,0 +1,417 @@
+//! Finding how to run the Claude Code CLI: directly as a native binary, or with a JS runtime.
+
+use std::{
+    collections::HashMap,
+    io::Read,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result, bail};
+use derive_more::Display;
+use serde::Serialize;
+use tokio::process::Command;
+use tracing::info;
+use which::which;
+
+use crate::types::{ClaudeCodeOptions, Executable};
+
+/// Comma separated runtime preference like `bun,node`, used when
+/// `ClaudeCodeOptions::runtime_preference` is not set.
+pub const RUNTIME_ENV: &str = "CC_SDK_RUNTIME";
+
+const DEFAULT_PREFERENCE: &[Executable] = &[Executable::Node, Executable::Bun, Executable::Deno];
+
+#[derive(Debug, Clone)]
+pub struct Runtime {
+    pub executable: Executable,
+    pub path: PathBuf,
+    pub version: Version,
+}
+
+/// Claude Code versions whose stream-json and control protocol this crate speaks.
+pub const SUPPORTED_CLAUDE_VERSIONS: Range<Version> = Version::new(2, 0, 0)..Version::new(3, 0, 0);
+
+const VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display, Serialize)]
+#[display("{major}.{minor}.{patch}")]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl Version {
+    pub const fn new(major: u64, minor: u64, patch: u64) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parses `1.2.3`, `v1.2.3` or `1.2.3-beta.1`. The patch number may be omitted.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.strip_prefix('v').unwrap_or(s);
+        let mut parts = s.splitn(3, '.').map(|part| {
+            let end = part
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(part.len());
+            part[..end].parse::<u64>().ok()
+        });
+        let major = parts.next()??;
+        let minor = parts.next()??;
+        let patch = match parts.next() {
+            Some(patch) => patch?,
+            None => 0,
+        };
+
+        Some(Self::new(major, minor, patch))
+    }
+
+    /// The first version in a `--version` output, e.g. `deno 2.1.4 (stable, release)`.
+    pub fn find(output: &str) -> Option<Self> {
+        output.split_whitespace().find_map(Self::parse)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compatibility {
+    Supported,
+    TooOld,
+    TooNew,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ClaudeVersion {
+    pub version: Version,
+    pub compatibility: Compatibility,
+}
+
+impl ClaudeVersion {
+    /// Checks the version against [`SUPPORTED_CLAUDE_VERSIONS`].
+    pub fn new(version: Version) -> Self {
+        let compatibility = if version < SUPPORTED_CLAUDE_VERSIONS.start {
+            Compatibility::TooOld
+        } else if version >= SUPPORTED_CLAUDE_VERSIONS.end {
+            Compatibility::TooNew
+        } else {
+            Compatibility::Supported
+        };
+
+        Self {
+            version,
+            compatibility,
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.compatibility == Compatibility::Supported
+    }
+}
+
+impl FromStr for Executable {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "bun" => Ok(Executable::Bun),
+            "deno" => Ok(Executable::Deno),
+            "node" => Ok(Executable::Node),
+            other => bail!("Unknown JS runtime: {other:?}. Expect bun, deno or node"),
+        }
+    }
+}
+
+impl Executable {
+    pub fn min_version(&self) -> Version {
+        match self {
+            Executable::Bun => Version::new(1, 0, 0),
+            Executable::Deno => Version::new(2, 0, 0),
+            Executable::Node => Version::new(18, 0, 0),
+        }
+    }
+}
+
+/// Finds a usable runtime. An explicit `executable` is the only candidate, otherwise the
+/// preference order from the options, then [`RUNTIME_ENV`], then node, bun and deno.
+pub async fn find_runtime(
+    executable: Option<Executable>,
+    preference: Option<&[Executable]>,
+) -> Result<Runtime> {
+    let candidates = match (executable, preference) {
+        (Some(executable), _) => vec![executable],
+        (None, Some(preference)) => preference.to_vec(),
+        (None, None) => match std::env::var(RUNTIME_ENV) {
+            Ok(value) => value
+                .split(',')
+                .map(Executable::from_str)
+                .collect::<Result<_>>()
+                .with_context(|| format!("Invalid {RUNTIME_ENV}"))?,
+            Err(_) => DEFAULT_PREFERENCE.to_vec(),
+        },
+    };
+
+    let mut problems = vec![];
+    for executable in candidates {
+        match probe_runtime(executable).await {
+            Ok(runtime) => return Ok(runtime),
+            Err(err) => problems.push(format!("{}: {err:#}", executable.as_str())),
+        }
+    }
+
+    bail!(
+        "No usable JS runtime to run Claude Code ({}). Install one, or use the native Claude Code binary",
+        problems.join("; ")
+    )
+}
+
+/// Runs `<runtime> --version`. The result is cached until the runtime file changes.
+async fn probe_runtime(executable: Executable) -> Result<Runtime> {
+    type Cache = HashMap<(PathBuf, SystemTime), Runtime>;
+    static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Default::default);
+
+    let path = which(executable.as_str()).context("not found in PATH")?;
+    let modified = std::fs::metadata(&path)?.modified()?;
+    let key = (path.clone(), modified);
+    if let Some(runtime) = CACHE.lock().unwrap().get(&key) {
+        return Ok(runtime.clone());
+    }
+
+    let mut cmd = Command::new(&path);
+    cmd.arg("--version").kill_on_drop(true);
+    let output = tokio::time::timeout(VERSION_TIMEOUT, cmd.output())
+        .await
+        .with_context(|| format!("`{} --version` timed out", path.display()))?
+        .with_context(|| format!("failed to run {path:?}"))?;
+    if !output.status.success() {
+        bail!("`{} --version` failed: {}", path.display(), output.status);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = Version::find(&stdout)
+        .with_context(|| format!("unrecognized version output: {:?}", stdout.trim()))?;
+    let min_version = executable.min_version();
+    if version < min_version {
+        bail!("{path:?} is {version}, but at least {min_version} is required");
+    }
+
+    let runtime = Runtime {
+        executable,
+        path,
+        version,
+    };
+    CACHE.lock().unwrap().insert(key, runtime.clone());
+
+    Ok(runtime)
+}
+
+/// How to start the CLI: the native binary itself, or a runtime with the JS entrypoint.
+#[derive(Debug, Clone)]
+pub struct ClaudeLauncher {
+    pub program: PathBuf,
+    pub entrypoint: Option<PathBuf>,
+}
+
+impl ClaudeLauncher {
+    /// The path of the Claude Code CLI itself.
+    pub fn claude_path(&self) -> &Path {
+        self.entrypoint.as_deref().unwrap_or(&self.program)
+    }
+
+    /// Runs `claude --version`. The result is cached until the CLI file changes.
+    pub async fn version(&self) -> Result<ClaudeVersion> {
+        type Cache = HashMap<(PathBuf, SystemTime), ClaudeVersion>;
+        static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(Default::default);
+
+        let modified = std::fs::metadata(self.claude_path())?.modified()?;
+        let key = (self.claude_path().to_owned(), modified);
+        if let Some(version) = CACHE.lock().unwrap().get(&key) {
+            return Ok(*version);
+        }
+
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.entrypoint)
+            .arg("--version")
+            .kill_on_drop(true);
+        let output = tokio::time::timeout(VERSION_TIMEOUT, cmd.output())
+            .await
+            .context("`claude --version` timed out")?
+            .context("Failed to run `claude --version`")?;
+        if !output.status.success() {
+            bail!("`claude --version` failed: {}", output.status);
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let version = Version::find(&stdout)
+            .with_context(|| format!("Unrecognized Claude Code version: {:?}", stdout.trim()))?;
+        let version = ClaudeVersion::new(version);
+        CACHE.lock().unwrap().insert(key, version);
+
+        Ok(version)
+    }
+}
+
+pub async fn find_claude_launcher(
+    path_to_claude_code_executable: Option<PathBuf>,
+    executable: Option<Executable>,
+    runtime_preference: Option<Vec<Executable>>,
+) -> Result<ClaudeLauncher> {
+    let bin_path = path_to_claude_code_executable.unwrap_or_else(|| PathBuf::from("claude"));
+    let path = &*bin_path.to_string_lossy();
+    let claude_bin_path = if is_command(path) {
+        match find_command_real_path(path)? {
+            Some(p) => p,
+            None => {
+                bail!("Failed to find claude command. Did you install it")
+            }
+        }
+    } else {
+        bin_path.clone()
+    };
+
+    let is_native = is_native_binary(&claude_bin_path);
+    if !claude_bin_path.exists() {
+        if is_native {
+            bail!(
+                "Claude Code native binary not found at {claude_bin_path:?}. Please ensure Claude Code is installed via native installer or specify a valid path with options.pathToClaudeCodeExecutable."
+            );
+        } else {
+            bail!(
+                "Claude Code executable not found at {claude_bin_path:?}. Is options.pathToClaudeCodeExecutable set?"
+            )
+        }
+    }
+
+    if is_native {
+        return Ok(ClaudeLauncher {
+            program: claude_bin_path,
+            entrypoint: None,
+        });
+    }
+
+    let runtime = find_runtime(executable, runtime_preference.as_deref()).await?;
+    info!(runtime = %runtime.path.display(), version = %runtime.version, "found JS runtime");
+    Ok(ClaudeLauncher {
+        program: runtime.path,
+        entrypoint: Some(claude_bin_path),
+    })
+}
+
+fn is_command(cmd: &str) -> bool {
+    let path = Path::new(cmd);
+    path.parent() == Some(Path::new(""))
+}
+
+fn find_command_real_path(cmd: &str) -> Result<Option<PathBuf>> {
+    if !is_command(cmd) {
+        return Ok(None);
+    }
+
+    let path = which(cmd).with_context(|| format!("Failed to find command path: {cmd}"))?;
+
+    Ok(Some(path))
+}
+
+/// Finds the Claude Code CLI the options point at, and checks its version.
+pub fn claude_version(
+    options: &ClaudeCodeOptions,
+) -> impl Future<Output = Result<ClaudeVersion>> + Send + 'static {
+    let path = options.path_to_claude_code_executable.clone();
+    let executable = options.executable;
+    let runtime_preference = options.runtime_preference.clone();
+    async move {
+        let launcher = find_claude_launcher(path, executable, runtime_preference).await?;
+        launcher.version().await
+    }
+}
+
+/// Whether the Claude Code entrypoint runs on its own rather than with a JS runtime.
+///
+/// JS files are recognized by extension, or by a shebang naming a JS runtime, which is how
+/// the npm `claude` command looks. Missing files count as native.
+pub fn is_native_binary(path: &Path) -> bool {
+    static JS_EXTENSIONS: &[&str] = &["js", "mjs", "cjs", "tsx", "ts", "jsx"];
+    static JS_RUNTIMES: &[&str] = &["node", "bun", "deno"];
+    static NATIVE_MAGICS: &[&[u8]] = &[
+        b"\x7fELF",
+        b"\xfe\xed\xfa\xce",
+        b"\xfe\xed\xfa\xcf",
+        b"\xce\xfa\xed\xfe",
+        b"\xcf\xfa\xed\xfe",
+        b"\xca\xfe\xba\xbe",
+        b"MZ",
+    ];
+
+    if let Some(ext) = path.extension()
+        && JS_EXTENSIONS.iter().any(|js| ext == *js)
+    {
+        return false;
+    }
+
+    let mut head = Vec::with_capacity(256);
+    let read = std::fs::File::open(path).and_then(|f| f.take(256).read_to_end(&mut head));
+    if read.is_err() {
+        return true;
+    }
+
+    if let Some(shebang) = head.strip_prefix(b"#!") {
+        let line = shebang.split(|b| *b == b'\n').next().unwrap_or_default();
+        let line = String::from_utf8_lossy(line);
+        return !line
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .any(|word| JS_RUNTIMES.contains(&word));
+    }
+
+    NATIVE_MAGICS.iter().any(|magic| head.starts_with(magic))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(Version::find("v20.11.1\n"), Some(Version::new(20, 11, 1)));
+        assert_eq!(
+            Version::find("deno 2.1.4 (stable, release, x86_64-unknown-linux-gnu)\nv8 13.0"),
+            Some(Version::new(2, 1, 4))
+        );
+        assert_eq!(Version::find("1.2.0-canary.1"), Some(Version::new(1, 2, 0)));
+        assert_eq!(Version::find("2.0"), Some(Version::new(2, 0, 0)));
+        assert_eq!(Version::find("unknown"), None);
+    }
+
+    #[test]
+    fn test_claude_version_compatibility() {
+        let version = |s| ClaudeVersion::new(Version::parse(s).unwrap()).compatibility;
+        assert_eq!(version("1.0.128"), Compatibility::TooOld);
+        assert_eq!(version("2.0.14"), Compatibility::Supported);
+        assert_eq!(version("3.0.0"), Compatibility::TooNew);
+    }
+
+    #[test]
+    fn test_is_native_binary() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("cc-sdk-runtime-{}", nanoid::nanoid!()));
+        std::fs::create_dir_all(&dir)?;
+
+        let script = dir.join("claude");
+        std::fs::write(&script, "#!/usr/bin/env node\nconsole.log(1)\n")?;
+        assert!(!is_native_binary(&script));
+
+        let wrapper = dir.join("claude-wrapper");
+        std::fs::write(&wrapper, "#!/bin/sh\nexec claude \"$@\"\n")?;
+        assert!(is_native_binary(&wrapper));
+
+        assert!(!is_native_binary(&dir.join("cli.js")));
+        assert!(is_native_binary(&std::env::current_exe()?));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+//! A scriptable fake of the Claude Code CLI, for tests that must not touch the real one.
+//!
+//! The `fake-claude` binary reads a JSONL script from the file in `FAKE_CLAUDE_SCRIPT` and
+//! plays it over the stream-json and control protocol. Point `path_to_claude_code_executable`
+//! at the binary, or let [`FakeClaude::apply`] set up the options.
+//! Both need the `testing` feature.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, Lines};
+
+use crate::types::ClaudeCodeOptions;
+
+pub const SCRIPT_ENV: &str = "FAKE_CLAUDE_SCRIPT";
+pub const FAKE_SESSION_ID: &str = "fake-session";
+/// Printed for `--version`, within the supported range.
+pub const FAKE_CLAUDE_VERSION: &str = "2.0.0 (Claude Code)";
+
+/// One step of a fake CLI script. A script is a JSONL file, one step per line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptStep {
+    /// Write a message to stdout.
+    Send(Value),
+    /// Wait for a user message on stdin.
+    RecvUser,
+    /// Wait for a control request of the subtype and answer it, with `error` if set.
+    /// `initialize` gets an empty command and model list if no response is given.
+    RecvControl {
+        subtype: String,
+        #[serde(default)]
+        response: Option<Value>,
+        #[serde(default)]
+        error: Option<String>,
+    },
+    /// Wait for a control request of the subtype, don't answer it, and wait for the SDK to
+    /// cancel it with a `control_cancel_request`.
+    IgnoreControl {
+        subtype: String,
+    },
+    /// Send a control request and wait for the response.
+    /// If `expect` is set, the response must contain it.
+    ControlRequest {
+        request: Value,
+        #[serde(default)]
+        expect: Option<Value>,
+    },
+    /// Send a control request, cancel it, and wait for the error response.
+    CancelControlRequest {
+        request: Value,
+    },
+    /// Fail unless the command line has these arguments next to each other, e.g. a flag
+    /// and its value.
+    ExpectArgs(Vec<String>),
+    /// Write a line to stderr.
+    Stderr(String),
+    SleepMs(u64),
+    /// Exit immediately with the code, like a crash.
+    Exit(i32),
+}
+
+/// Builder of fake CLI scripts.
+#[derive(Debug, Clone, Default)]
+pub struct FakeClaude {
+    steps: Vec<ScriptStep>,
+}
+
+impl FakeClaude {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A script that starts with the `initialize` handshake of stream-json mode.
+    pub fn streaming() -> Self {
+        Self::new().step(ScriptStep::RecvControl {
+            subtype: "initialize".to_string(),
+            response: None,
+            error: None,
+        })
+    }
+
+    pub fn step(mut self, step: ScriptStep) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn send(self, msg: Value) -> Self {
+        self.step(ScriptStep::Send(msg))
+    }
+
+    pub fn recv_user(self) -> Self {
+        self.step(ScriptStep::RecvUser)
+    }
+
+    /// Send an assistant message with a single text block.
+    pub fn assistant_text(self, text: &str) -> Self {
+        self.send(assistant_text(text))
+    }
+
+    /// Send a successful result message.
+    pub fn result(self, result: &str) -> Self {
+        self.send(success_result(result))
+    }
+
+    pub fn steps(&self) -> &[ScriptStep] {
+        &self.steps
+    }
+
+    pub fn to_jsonl(&self) -> String {
+        self.steps
+            .iter()
+            .map(|step| serde_json::to_string(step).unwrap() + "\n")
+            .collect()
+    }
+
+    /// Writes the script to a temp file, deleted when the returned guard is dropped.
+    pub fn write_script(&self) -> Result<ScriptFile> {
+        let path = std::env::temp_dir().join(format!("fake-claude-{}.jsonl", nanoid::nanoid!()));
+        std::fs::write(&path, self.to_jsonl()).context("Failed to write fake claude script")?;
+        Ok(ScriptFile { path })
+    }
+
+    /// Writes the script and points the options at the fake CLI binary. Keep the returned
+    /// guard until the CLI has read the script, e.g. until `query` returns.
+    pub fn apply(
+        &self,
+        bin: impl AsRef<Path>,
+        options: &mut ClaudeCodeOptions,
+    ) -> Result<ScriptFile> {
+        let script = self.write_script()?;
+        script.apply(bin, options);
+        Ok(script)
+    }
+}
+
+/// A script file of the fake CLI, deleted on drop.
+#[derive(Debug)]
+#[must_use = "the script is deleted when the guard is dropped"]
+pub struct ScriptFile {
+    path: PathBuf,
+}
+
+impl ScriptFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Points the options at the fake CLI binary and this script.
+    pub fn apply(&self, bin: impl AsRef<Path>, options: &mut ClaudeCodeOptions) {
+        options.path_to_claude_code_executable = Some(bin.as_ref().to_owned());
+        options.env.get_or_insert_default().insert(
+            SCRIPT_ENV.to_string(),
+            self.path.to_string_lossy().into_owned(),
+        );
+    }
+}
+
+impl Drop for ScriptFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Builds the `fake-claude` binary once per test process and returns its path.
+///
+/// Cargo doesn't build the binaries of a dependency, so tests of other crates call this.
+/// It builds into a target dir of its own, next to the running test executable, because
+/// the running `cargo test` holds the lock of the main one.
+/// Tests in cc-sdk itself can use `env!("CARGO_BIN_EXE_fake-claude")` instead.
+pub fn fake_claude_bin() -> Result<PathBuf> {
+    static BIN: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+
+    BIN.get_or_init(|| build_fake_claude().map_err(|err| format!("{err:#}")))
+        .clone()
+        .map_err(anyhow::Error::msg)
+}
+
+fn build_fake_claude() -> Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    // The test executable is at `<target>/<profile>/deps/<name>`.
+    let target_dir = exe
+        .ancestors()
+        .nth(3)
+        .with_context(|| format!("Unexpected test executable path: {}", exe.display()))?
+        .join("fake-claude");
+
+    let output = std::process::Command::new(env!("CARGO"))
+        .args([
+            "build",
+            "--quiet",
+            "--bin",
+            "fake-claude",
+            "--features",
+            "testing",
+        ])
+        .arg("--manifest-path")
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .output()
+        .context("Failed to run cargo to build fake-claude")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to build fake-claude: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let bin = target_dir
+        .join("debug")
+        .join(format!("fake-claude{}", std::env::consts::EXE_SUFFIX));
+    if !bin.is_file() {
+        bail!("fake-claude binary not found at {}", bin.display());
+    }
+    Ok(bin)
+}
+
+pub fn assistant_text(text: &str) -> Value {
+    json!({
+        "type": "assistant",
+        "session_id": FAKE_SESSION_ID,
+        "uuid": nanoid::nanoid!(),
+        "parent_tool_use_id": null,
+        "message": {
+            "id": format!("msg_{}", nanoid::nanoid!()),
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-fake",
+            "content": [{ "type": "text", "text": text }],
+            "stop_reason": null,
+            "stop_sequence": null,
+            "usage": { "input_tokens": 1, "output_tokens": 1 }
+        }
+    })
+}
+
+pub fn success_result(result: &str) -> Value {
+    json!({
+        "type": "result",
+        "subtype": "success",
+        "session_id": FAKE_SESSION_ID,
+        "uuid": nanoid::nanoid!(),
+        "duration_ms": 1,
+        "duration_api_ms": 1,
+        "is_error": false,
+        "num_turns": 1,
+        "result": result,
+        "total_cost_usd": 0.0,
+        "usage": { "input_tokens": 1, "output_tokens": 1 },
+        "modelUsage": {},
+        "permission_denials": []
+    })
+}
+
+/// Plays the script over the given stdin and stdout. `args` is the command line without
+/// the program name. Returns the exit code.
+pub async fn run_script<R, W>(
+    steps: Vec<ScriptStep>,
+    args: &[String],
+    stdin: R,
+    mut stdout: W,
+) -> Result<i32>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut stdin = stdin.lines();
+    let mut next_id = 0;
+    for step in steps {
+        match step {
+            ScriptStep::Send(msg) => write_line(&mut stdout, &msg).await?,
+            ScriptStep::RecvUser => {
+                let msg = read_message(&mut stdin).await?;
+                if msg["type"] != "user" {
+                    bail!("Expect a user message, got: {msg}");
+                }
+            }
+            ScriptStep::RecvControl {
+                subtype,
+                response,
+                error,
+            } => {
+                let msg = read_message(&mut stdin).await?;
+                if msg["type"] != "control_request" || msg["request"]["subtype"] != *subtype {
+                    bail!("Expect control request {subtype}, got: {msg}");
+                }
+                let response = response.unwrap_or_else(|| match &*subtype {
+                    "initialize" => json!({ "commands": [], "models": [] }),
+                    _ => json!({}),
+                });
+                let resp = match error {
+                    Some(error) => json!({
+                        "type": "control_response",
+                        "response": {
+                            "subtype": "error",
+                            "request_id": msg["request_id"],
+                            "error": error
+                        }
+                    }),
+                    None => json!({
+                        "type": "control_response",
+                        "response": {
+                            "subtype": "success",
+                            "request_id": msg["request_id"],
+                            "response": response
+                        }
+                    }),
+                };
+                write_line(&mut stdout, &resp).await?;
+            }
+            ScriptStep::IgnoreControl { subtype } => {
+                let msg = read_message(&mut stdin).await?;
+                if msg["type"] != "control_request" || msg["request"]["subtype"] != *subtype {
+                    bail!("Expect control request {subtype}, got: {msg}");
+                }
+                let cancel = read_message(&mut stdin).await?;
+                if cancel["type"] != "control_cancel_request"
+                    || cancel["request_id"] != msg["request_id"]
+                {
+                    bail!("Expect control request {subtype} to be cancelled, got: {cancel}");
+                }
+            }
+            ScriptStep::ControlRequest { request, expect } => {
+                next_id += 1;
+                let id = format!("fake_req_{next_id}");
+                write_control_request(&mut stdout, &id, request).await?;
+                let resp = read_control_response(&mut stdin, &id).await?;
+                if let Some(expect) = expect
+                    && !contains(&resp, &expect)
+                {
+                    bail!("Control response {resp} doesn't contain {expect}");
+                }
+            }
+            ScriptStep::CancelControlRequest { request } => {
+                next_id += 1;
+                let id = format!("fake_req_{next_id}");
+                write_control_request(&mut stdout, &id, request).await?;
+                let cancel = json!({ "type": "control_cancel_request", "request_id": id });
+                write_line(&mut stdout, &cancel).await?;
+                let resp = read_control_response(&mut stdin, &id).await?;
+                if resp["subtype"] != "error" {
+                    bail!("Expect an error response for the cancelled request, got: {resp}");
+                }
+            }
+            ScriptStep::ExpectArgs(expect) => {
+                if !args.windows(expect.len()).any(|window| window == expect) {
+                    bail!("Expect arguments {expect:?}, got: {args:?}");
+                }
+            }
+            ScriptStep::Stderr(line) => eprintln!("{line}"),
+            ScriptStep::SleepMs(ms) => {
+                tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+            }
+            ScriptStep::Exit(code) => return Ok(code),
+        }
+    }
+
+    // Like the real CLI, keep running until the input is closed.
+    while stdin.next_line().await?.is_some() {}
+
+    Ok(0)
+}
+
+/// Entry of the `fake-claude` binary.
+pub async fn run_fake_cli() -> Result<i32> {
+    if std::env::args().any(|arg| arg == "--version") {
+        println!("{FAKE_CLAUDE_VERSION}");
+        return Ok(0);
+    }
+
+    let path = std::env::var(SCRIPT_ENV).with_context(|| format!("{SCRIPT_ENV} is not set"))?;
+    let script = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read fake claude script: {path}"))?;
+    let steps = script
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<ScriptStep>, _>>()
+        .context("Invalid fake claude script")?;
+
+    let stdin = tokio::io::BufReader::new(tokio::io::stdin());
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    run_script(steps, &args, stdin, tokio::io::stdout()).await
+}
+
+async fn write_line<W: AsyncWrite + Unpin>(stdout: &mut W, msg: &Value) -> Result<()> {
+    let line = serde_json::to_string(msg)? + "\n";
+    stdout.write_all(line.as_bytes()).await?;
+    stdout.flush().await?;
+    Ok(())
+}
+
+async fn write_control_request<W: AsyncWrite + Unpin>(
+    stdout: &mut W,
+    id: &str,
+    request: Value,
+) -> Result<()> {
+    let req = json!({ "type": "control_request", "request_id": id, "request": request });
+    write_line(stdout, &req).await
+}
+
+async fn read_message<R: AsyncBufRead + Unpin>(stdin: &mut Lines<R>) -> Result<Value> {
+    let line = stdin
+        .next_line()
+        .await?
+        .context("stdin closed while waiting for a message")?;
+    serde_json::from_str(&line).with_context(|| format!("Invalid json from stdin: {line}"))
+}
+
+async fn read_control_response<R: AsyncBufRead + Unpin>(
+    stdin: &mut Lines<R>,
+    id: &str,
+) -> Result<Value> {
+    let mut msg = read_message(stdin).await?;
+    if msg["type"] != "control_response" || msg["response"]["request_id"] != id {
+        bail!("Expect control response of {id}, got: {msg}");
+    }
+    Ok(msg["response"].take())
+}
+
+/// Whether `value` has every field of `expect`, recursively.
+fn contains(value: &Value, expect: &Value) -> bool {
+    match (value, expect) {
+        (Value::Object(value), Value::Object(expect)) => expect
+            .iter()
+            .all(|(k, v)| value.get(k).is_some_and(|value| contains(value, v))),
+        _ => value == expect,
+    }
+}
@@ -1,10 +1,14 @@
 pub mod anthropic;
 mod can_use_tool;
+mod hooks;
 mod message;
 mod options;
 mod tool_input;
+mod tool_output;
 
 pub use can_use_tool::*;
+pub use hooks::*;
 pub use message::*;
 pub use options::*;
 pub use tool_input::*;
+pub use tool_output::*;
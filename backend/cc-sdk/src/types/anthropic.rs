@@ -1,13 +1,22 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{collections::HashMap, fmt::Debug, path::Path, sync::Arc};
 
+use anyhow::{Context, bail};
+use base64::prelude::*;
 use derive_more::From;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::types::ToolUseParams;
 
 #[derive(Serialize, Deserialize, From, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum ContentBlockParam {
     Text(TextBlockParam),
+    Image(ImageBlockParam),
+    Document(DocumentBlockParam),
+    ToolResult(ToolResultBlockParam),
+    Thinking(ThinkingBlockParam),
     #[serde(untagged)]
     Other(serde_json::Value),
 }
@@ -16,12 +25,18 @@ impl Debug for ContentBlockParam {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Text(arg0) => f.debug_tuple("Text").field(arg0).finish(),
+            Self::Image(arg0) => f.debug_tuple("Image").field(arg0).finish(),
+            Self::Document(arg0) => f.debug_tuple("Document").field(arg0).finish(),
+            Self::ToolResult(arg0) => f.debug_tuple("ToolResult").field(arg0).finish(),
+            Self::Thinking(arg0) => f.debug_tuple("Thinking").field(arg0).finish(),
             Self::Other(arg0) => {
                 let Some(ty) = arg0["type"].as_str() else {
                     return write!(f, "Unknown ContentBlockParam");
                 };
-                if ty == "image" {
-                    return write!(f, "Image(..)");
+                // Images and documents with unsupported media types end up here, keep their
+                // data out of the logs.
+                if ty == "image" || ty == "document" {
+                    return write!(f, "Other({ty}, ..)");
                 }
 
                 f.debug_tuple("Other").field(arg0).finish()
@@ -30,6 +45,267 @@ impl Debug for ContentBlockParam {
     }
 }
 
+impl ContentBlockParam {
+    /// Loads an image, a PDF or a UTF-8 text file, detected by its content.
+    pub async fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let data = read_file(path, MAX_DOCUMENT_SIZE).await?;
+        match FileKind::detect(&data) {
+            Some(FileKind::Image(media_type)) => {
+                check_size(path, data.len(), MAX_IMAGE_SIZE)?;
+                Ok(ImageBlockParam::base64(media_type, &data).into())
+            }
+            Some(FileKind::Pdf | FileKind::Text) => {
+                Ok(DocumentBlockParam::from_data(path, data)?.into())
+            }
+            None => bail!("Unsupported file type: {}", path.display()),
+        }
+    }
+}
+
+/// The largest image the API accepts.
+pub const MAX_IMAGE_SIZE: u64 = 5 * 1024 * 1024;
+
+/// The largest document the API accepts.
+pub const MAX_DOCUMENT_SIZE: u64 = 32 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageBlockParam {
+    pub source: ImageSource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<CacheControlEphemeral>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ImageSource {
+    Base64 {
+        media_type: ImageMediaType,
+        data: String,
+    },
+    Url {
+        url: String,
+    },
+}
+
+impl Debug for ImageSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Base64 { media_type, data } => f
+                .debug_struct("Base64")
+                .field("media_type", media_type)
+                .field("len", &data.len())
+                .finish(),
+            Self::Url { url } => f.debug_struct("Url").field("url", url).finish(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageMediaType {
+    #[serde(rename = "image/jpeg")]
+    Jpeg,
+    #[serde(rename = "image/png")]
+    Png,
+    #[serde(rename = "image/gif")]
+    Gif,
+    #[serde(rename = "image/webp")]
+    Webp,
+}
+
+impl ImageBlockParam {
+    pub fn base64(media_type: ImageMediaType, data: &[u8]) -> Self {
+        Self {
+            source: ImageSource::Base64 {
+                media_type,
+                data: BASE64_STANDARD.encode(data),
+            },
+            cache_control: None,
+        }
+    }
+
+    pub fn url(url: impl Into<String>) -> Self {
+        Self {
+            source: ImageSource::Url { url: url.into() },
+            cache_control: None,
+        }
+    }
+
+    /// Loads a JPEG, PNG, GIF or WebP file of at most [`MAX_IMAGE_SIZE`] bytes.
+    pub async fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let data = read_file(path, MAX_IMAGE_SIZE).await?;
+        let Some(FileKind::Image(media_type)) = FileKind::detect(&data) else {
+            bail!("Not a JPEG, PNG, GIF or WebP image: {}", path.display());
+        };
+        Ok(Self::base64(media_type, &data))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DocumentBlockParam {
+    pub source: DocumentSource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub citations: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<CacheControlEphemeral>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentSource {
+    /// A base64 encoded PDF.
+    Base64 {
+        media_type: PdfMediaType,
+        data: String,
+    },
+    Text {
+        media_type: PlainTextMediaType,
+        data: String,
+    },
+    /// The URL of a PDF.
+    Url { url: String },
+}
+
+impl Debug for DocumentSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Base64 { data, .. } => {
+                f.debug_struct("Base64").field("len", &data.len()).finish()
+            }
+            Self::Text { data, .. } => f.debug_struct("Text").field("len", &data.len()).finish(),
+            Self::Url { url } => f.debug_struct("Url").field("url", url).finish(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PdfMediaType {
+    #[serde(rename = "application/pdf")]
+    Pdf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlainTextMediaType {
+    #[serde(rename = "text/plain")]
+    Plain,
+}
+
+impl DocumentBlockParam {
+    fn new(source: DocumentSource) -> Self {
+        Self {
+            source,
+            title: None,
+            context: None,
+            citations: None,
+            cache_control: None,
+        }
+    }
+
+    pub fn pdf(data: &[u8]) -> Self {
+        Self::new(DocumentSource::Base64 {
+            media_type: PdfMediaType::Pdf,
+            data: BASE64_STANDARD.encode(data),
+        })
+    }
+
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::new(DocumentSource::Text {
+            media_type: PlainTextMediaType::Plain,
+            data: text.into(),
+        })
+    }
+
+    pub fn url(url: impl Into<String>) -> Self {
+        Self::new(DocumentSource::Url { url: url.into() })
+    }
+
+    pub fn with_title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    /// Loads a PDF or a UTF-8 text file of at most [`MAX_DOCUMENT_SIZE`] bytes.
+    /// The file name becomes the title.
+    pub async fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let data = read_file(path, MAX_DOCUMENT_SIZE).await?;
+        Self::from_data(path, data)
+    }
+
+    fn from_data(path: &Path, data: Vec<u8>) -> anyhow::Result<Self> {
+        let document = match FileKind::detect(&data) {
+            Some(FileKind::Pdf) => Self::pdf(&data),
+            Some(FileKind::Text) => Self::text(String::from_utf8(data)?),
+            _ => bail!("Not a PDF or text file: {}", path.display()),
+        };
+        let Some(name) = path.file_name() else {
+            return Ok(document);
+        };
+        Ok(document.with_title(name.to_string_lossy()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThinkingBlockParam {
+    pub thinking: String,
+    pub signature: String,
+}
+
+enum FileKind {
+    Image(ImageMediaType),
+    Pdf,
+    Text,
+}
+
+impl FileKind {
+    /// Detects the type by the magic bytes. Other UTF-8 files are text.
+    fn detect(data: &[u8]) -> Option<Self> {
+        let kind = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Self::Image(ImageMediaType::Png)
+        } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+            Self::Image(ImageMediaType::Jpeg)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Self::Image(ImageMediaType::Gif)
+        } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+            Self::Image(ImageMediaType::Webp)
+        } else if data.starts_with(b"%PDF-") {
+            Self::Pdf
+        } else if std::str::from_utf8(data).is_ok() {
+            Self::Text
+        } else {
+            return None;
+        };
+        Some(kind)
+    }
+}
+
+async fn read_file(path: &Path, limit: u64) -> anyhow::Result<Vec<u8>> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    check_size(path, metadata.len() as usize, limit)?;
+    tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))
+}
+
+fn check_size(path: &Path, size: usize, limit: u64) -> anyhow::Result<()> {
+    if size as u64 > limit {
+        bail!(
+            "{} is too large: {size} bytes, the limit is {limit}",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize, Debug, From, Clone)]
 pub struct TextBlockParam {
     pub text: Arc<String>,
@@ -37,6 +313,44 @@ pub struct TextBlockParam {
     pub citations: Option<serde_json::Value>,
 }
 
+/// The result of a tool call, sent back in a user message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolResultBlockParam {
+    pub tool_use_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<ToolResultContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_error: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ToolResultContent {
+    Text(String),
+    Blocks(Vec<ContentBlockParam>),
+}
+
+impl ToolResultBlockParam {
+    pub fn is_error(&self) -> bool {
+        self.is_error.unwrap_or(false)
+    }
+
+    /// All the text of the content, joined together.
+    pub fn text(&self) -> String {
+        match &self.content {
+            None => String::new(),
+            Some(ToolResultContent::Text(text)) => text.clone(),
+            Some(ToolResultContent::Blocks(blocks)) => blocks
+                .iter()
+                .filter_map(|block| match block {
+                    ContentBlockParam::Text(block) => Some(block.text.as_str()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CacheControlEphemeral {
     r#type: CacheControlEphemeralType,
@@ -78,3 +392,666 @@ pub enum TTL {
     #[serde(rename = "1h")]
     _1H,
 }
+
+/// An assistant message returned by the Messages API.
+///
+/// Synthetic messages written by the CLI itself, like API errors in the session logs, may
+/// lack the `id`, `model` or `usage`, or carry a plain string as `content`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub r#type: MessageType,
+    pub role: MessageRole,
+    #[serde(default)]
+    pub model: String,
+    #[serde(deserialize_with = "deserialize_content")]
+    pub content: Vec<ContentBlock>,
+    #[serde(default)]
+    pub stop_reason: Option<StopReason>,
+    #[serde(default)]
+    pub stop_sequence: Option<String>,
+    #[serde(default)]
+    pub usage: Usage,
+}
+
+fn deserialize_content<'de, D>(deserializer: D) -> Result<Vec<ContentBlock>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Content {
+        Text(String),
+        Blocks(Vec<ContentBlock>),
+    }
+
+    Ok(match Content::deserialize(deserializer)? {
+        Content::Text(text) => vec![ContentBlock::Text(TextBlock {
+            text,
+            citations: None,
+        })],
+        Content::Blocks(blocks) => blocks,
+    })
+}
+
+impl Message {
+    /// All the text blocks, joined together.
+    pub fn text(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text(block) => Some(block.text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn tool_uses(&self) -> impl Iterator<Item = &ToolUseBlock> {
+        self.content.iter().filter_map(|block| match block {
+            ContentBlock::ToolUse(block) => Some(block),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageType {
+    #[default]
+    Message,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageRole {
+    Assistant,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    EndTurn,
+    MaxTokens,
+    StopSequence,
+    ToolUse,
+    PauseTurn,
+    Refusal,
+    #[serde(untagged)]
+    Other(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text(TextBlock),
+    Thinking(ThinkingBlock),
+    RedactedThinking(RedactedThinkingBlock),
+    ToolUse(ToolUseBlock),
+    ServerToolUse(ServerToolUseBlock),
+    WebSearchToolResult(WebSearchToolResultBlock),
+    /// Block types this crate doesn't know yet, kept as they are.
+    #[serde(untagged)]
+    Other(serde_json::Value),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TextBlock {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub citations: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThinkingBlock {
+    pub thinking: String,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RedactedThinkingBlock {
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolUseBlock {
+    pub id: String,
+    pub name: String,
+    pub input: serde_json::Value,
+}
+
+impl ToolUseBlock {
+    /// Typed input of the built-in tools. `None` for unknown tools or malformed input.
+    pub fn params(&self) -> Option<ToolUseParams> {
+        serde_json::from_value(json!({
+            "tool_name": self.name,
+            "input": self.input,
+        }))
+        .ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerToolUseBlock {
+    pub id: String,
+    pub name: String,
+    pub input: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebSearchToolResultBlock {
+    pub tool_use_id: String,
+    pub content: WebSearchToolResultContent,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum WebSearchToolResultContent {
+    Results(Vec<WebSearchResultBlock>),
+    Error(WebSearchToolResultError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebSearchResultBlock {
+    pub r#type: String,
+    pub title: String,
+    pub url: String,
+    pub encrypted_content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_age: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebSearchToolResultError {
+    pub r#type: String,
+    pub error_code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_creation_input_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_read_input_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_creation: Option<CacheCreation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_tool_use: Option<ServerToolUsage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        fn add_opt(total: &mut Option<u64>, other: Option<u64>) {
+            if let Some(other) = other {
+                *total = Some(total.unwrap_or_default() + other);
+            }
+        }
+
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        add_opt(
+            &mut self.cache_creation_input_tokens,
+            other.cache_creation_input_tokens,
+        );
+        add_opt(
+            &mut self.cache_read_input_tokens,
+            other.cache_read_input_tokens,
+        );
+        if let Some(other) = &other.cache_creation {
+            let total = self.cache_creation.get_or_insert_default();
+            total.ephemeral_5m_input_tokens += other.ephemeral_5m_input_tokens;
+            total.ephemeral_1h_input_tokens += other.ephemeral_1h_input_tokens;
+        }
+        if let Some(other) = &other.server_tool_use {
+            let total = self.server_tool_use.get_or_insert_default();
+            total.web_search_requests += other.web_search_requests;
+        }
+        if other.service_tier.is_some() {
+            self.service_tier = other.service_tier.clone();
+        }
+    }
+
+    /// Input tokens including cache reads and writes.
+    pub fn total_input_tokens(&self) -> u64 {
+        self.input_tokens
+            + self.cache_creation_input_tokens.unwrap_or_default()
+            + self.cache_read_input_tokens.unwrap_or_default()
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.total_input_tokens() + self.output_tokens
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CacheCreation {
+    pub ephemeral_5m_input_tokens: u64,
+    pub ephemeral_1h_input_tokens: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ServerToolUsage {
+    pub web_search_requests: u64,
+}
+
+/// Server-sent events of a streaming Messages API response.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStreamEvent {
+    MessageStart {
+        message: Message,
+    },
+    ContentBlockStart {
+        index: usize,
+        content_block: ContentBlock,
+    },
+    ContentBlockDelta {
+        index: usize,
+        delta: ContentBlockDelta,
+    },
+    ContentBlockStop {
+        index: usize,
+    },
+    MessageDelta {
+        delta: MessageDelta,
+        usage: MessageDeltaUsage,
+    },
+    MessageStop,
+    Ping,
+    #[serde(untagged)]
+    Other(serde_json::Value),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum ContentBlockDelta {
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    ThinkingDelta {
+        thinking: String,
+    },
+    SignatureDelta {
+        signature: String,
+    },
+    CitationsDelta {
+        citation: serde_json::Value,
+    },
+    #[serde(untagged)]
+    Other(serde_json::Value),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageDelta {
+    pub stop_reason: Option<StopReason>,
+    pub stop_sequence: Option<String>,
+}
+
+/// Cumulative usage in a `message_delta` event.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MessageDeltaUsage {
+    pub output_tokens: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_creation_input_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_read_input_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_tool_use: Option<ServerToolUsage>,
+}
+
+/// Builds the assistant message from the stream events, the same way the Anthropic SDKs do.
+#[derive(Debug, Default)]
+pub struct MessageAccumulator {
+    message: Option<Message>,
+    /// Raw tool input of the open `tool_use` blocks, by index.
+    partial_json: HashMap<usize, String>,
+}
+
+impl MessageAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, event: &MessageStreamEvent) -> anyhow::Result<()> {
+        match event {
+            MessageStreamEvent::MessageStart { message } => {
+                self.message = Some(message.clone());
+                self.partial_json.clear();
+            }
+            MessageStreamEvent::ContentBlockStart {
+                index,
+                content_block,
+            } => {
+                let content = &mut self.message_mut()?.content;
+                if *index > content.len() {
+                    bail!("content_block_start out of order: {index}");
+                }
+                if *index == content.len() {
+                    content.push(content_block.clone());
+                } else {
+                    content[*index] = content_block.clone();
+                }
+                if matches!(
+                    content_block,
+                    ContentBlock::ToolUse(..) | ContentBlock::ServerToolUse(..)
+                ) {
+                    self.partial_json.insert(*index, String::new());
+                }
+            }
+            MessageStreamEvent::ContentBlockDelta { index, delta } => {
+                let index = *index;
+                let Some(block) = self.message_mut()?.content.get_mut(index) else {
+                    bail!("content_block_delta for unknown block: {index}");
+                };
+                match (block, delta) {
+                    (ContentBlock::Text(block), ContentBlockDelta::TextDelta { text }) => {
+                        block.text.push_str(text);
+                    }
+                    (ContentBlock::Text(block), ContentBlockDelta::CitationsDelta { citation }) => {
+                        let citations = block.citations.get_or_insert_with(|| json!([]));
+                        if let Some(citations) = citations.as_array_mut() {
+                            citations.push(citation.clone());
+                        }
+                    }
+                    (
+                        ContentBlock::Thinking(block),
+                        ContentBlockDelta::ThinkingDelta { thinking },
+                    ) => {
+                        block.thinking.push_str(thinking);
+                    }
+                    (
+                        ContentBlock::Thinking(block),
+                        ContentBlockDelta::SignatureDelta { signature },
+                    ) => {
+                        block.signature = signature.clone();
+                    }
+                    (
+                        ContentBlock::ToolUse(..) | ContentBlock::ServerToolUse(..),
+                        ContentBlockDelta::InputJsonDelta { partial_json },
+                    ) => {
+                        self.partial_json
+                            .entry(index)
+                            .or_default()
+                            .push_str(partial_json);
+                    }
+                    (_, ContentBlockDelta::Other(..)) => {}
+                    (block, delta) => {
+                        bail!("{delta:?} doesn't match the content block {block:?}")
+                    }
+                }
+            }
+            MessageStreamEvent::ContentBlockStop { index } => {
+                let Some(json) = self.partial_json.remove(index) else {
+                    return Ok(());
+                };
+                let input = if json.is_empty() {
+                    json!({})
+                } else {
+                    serde_json::from_str(&json).context("Invalid tool input json")?
+                };
+                match self.message_mut()?.content.get_mut(*index) {
+                    Some(ContentBlock::ToolUse(block)) => block.input = input,
+                    Some(ContentBlock::ServerToolUse(block)) => block.input = input,
+                    _ => {}
+                }
+            }
+            MessageStreamEvent::MessageDelta { delta, usage } => {
+                let message = self.message_mut()?;
+                message.stop_reason = delta.stop_reason.clone();
+                message.stop_sequence = delta.stop_sequence.clone();
+
+                let total = &mut message.usage;
+                total.output_tokens = usage.output_tokens;
+                if let Some(tokens) = usage.input_tokens {
+                    total.input_tokens = tokens;
+                }
+                if usage.cache_creation_input_tokens.is_some() {
+                    total.cache_creation_input_tokens = usage.cache_creation_input_tokens;
+                }
+                if usage.cache_read_input_tokens.is_some() {
+                    total.cache_read_input_tokens = usage.cache_read_input_tokens;
+                }
+                if usage.server_tool_use.is_some() {
+                    total.server_tool_use = usage.server_tool_use.clone();
+                }
+            }
+            MessageStreamEvent::MessageStop
+            | MessageStreamEvent::Ping
+            | MessageStreamEvent::Other(..) => {}
+        }
+
+        Ok(())
+    }
+
+    /// The message received so far. `None` before `message_start`.
+    pub fn message(&self) -> Option<&Message> {
+        self.message.as_ref()
+    }
+
+    /// Tool input received so far for a `tool_use` block that isn't finished yet.
+    /// Useful to preview the input while it's streaming.
+    pub fn partial_input(&self, index: usize) -> Option<&str> {
+        self.partial_json.get(&index).map(|s| s.as_str())
+    }
+
+    pub fn finish(self) -> Option<Message> {
+        self.message
+    }
+
+    fn message_mut(&mut self) -> anyhow::Result<&mut Message> {
+        self.message
+            .as_mut()
+            .context("message_start is not received")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() -> anyhow::Result<()> {
+        let value = json!({
+            "id": "msg_01",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5",
+            "content": [
+                { "type": "thinking", "thinking": "Let me look", "signature": "sig" },
+                { "type": "text", "text": "Running it." },
+                {
+                    "type": "tool_use",
+                    "id": "toolu_01",
+                    "name": "Bash",
+                    "input": { "command": "ls" }
+                },
+                {
+                    "type": "server_tool_use",
+                    "id": "srvtoolu_01",
+                    "name": "web_search",
+                    "input": { "query": "rust" }
+                },
+                {
+                    "type": "web_search_tool_result",
+                    "tool_use_id": "srvtoolu_01",
+                    "content": { "type": "web_search_tool_result_error", "error_code": "max_uses_exceeded" }
+                },
+                { "type": "container_upload", "file_id": "file_01" }
+            ],
+            "stop_reason": "tool_use",
+            "stop_sequence": null,
+            "usage": {
+                "input_tokens": 10,
+                "output_tokens": 20,
+                "cache_read_input_tokens": 100,
+                "service_tier": "standard"
+            }
+        });
+        let msg: Message = serde_json::from_value(value.clone())?;
+
+        assert_eq!(msg.text(), "Running it.");
+        assert_eq!(msg.stop_reason, Some(StopReason::ToolUse));
+        assert_eq!(msg.usage.cache_read_input_tokens, Some(100));
+        let tool_use = msg.tool_uses().next().unwrap();
+        assert!(matches!(
+            tool_use.params(),
+            Some(ToolUseParams::Bash { .. })
+        ));
+        assert!(matches!(
+            msg.content[4],
+            ContentBlock::WebSearchToolResult(WebSearchToolResultBlock {
+                content: WebSearchToolResultContent::Error(..),
+                ..
+            })
+        ));
+        assert!(matches!(msg.content[5], ContentBlock::Other(..)));
+        assert_eq!(serde_json::to_value(&msg)?, value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_synthetic_message() -> anyhow::Result<()> {
+        let msg: Message = serde_json::from_value(json!({
+            "role": "assistant",
+            "content": "API Error: Request was aborted."
+        }))?;
+        assert_eq!(msg.text(), "API Error: Request was aborted.");
+        assert_eq!(msg.usage, Usage::default());
+
+        let msg: Message = serde_json::from_value(json!({
+            "id": "b1c2",
+            "type": "message",
+            "role": "assistant",
+            "model": "<synthetic>",
+            "content": [{ "type": "text", "text": "No response requested." }]
+        }))?;
+        assert_eq!(msg.model, "<synthetic>");
+        assert_eq!(msg.stop_reason, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_accumulate_stream() -> anyhow::Result<()> {
+        let events = [
+            json!({
+                "type": "message_start",
+                "message": {
+                    "id": "msg_01",
+                    "type": "message",
+                    "role": "assistant",
+                    "model": "claude-sonnet-4-5",
+                    "content": [],
+                    "stop_reason": null,
+                    "stop_sequence": null,
+                    "usage": { "input_tokens": 10, "output_tokens": 1 }
+                }
+            }),
+            json!({"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Hello"}}),
+            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": " world"}}),
+            json!({"type": "content_block_stop", "index": 0}),
+            json!({"type": "content_block_start", "index": 1, "content_block": {"type": "tool_use", "id": "toolu_01", "name": "Bash", "input": {}}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "input_json_delta", "partial_json": "{\"comm"}}),
+            json!({"type": "content_block_delta", "index": 1, "delta": {"type": "input_json_delta", "partial_json": "and\": \"ls\"}"}}),
+            json!({"type": "content_block_stop", "index": 1}),
+            json!({"type": "message_delta", "delta": {"stop_reason": "tool_use", "stop_sequence": null}, "usage": {"output_tokens": 30}}),
+            json!({"type": "message_stop"}),
+        ];
+
+        let mut acc = MessageAccumulator::new();
+        for (i, event) in events.into_iter().enumerate() {
+            let event: MessageStreamEvent = serde_json::from_value(event)?;
+            acc.push(&event)?;
+            if i == 6 {
+                assert_eq!(acc.partial_input(1), Some("{\"comm"));
+            }
+        }
+
+        let msg = acc.finish().unwrap();
+        assert_eq!(msg.text(), "Hello world");
+        assert_eq!(msg.stop_reason, Some(StopReason::ToolUse));
+        assert_eq!(msg.usage.input_tokens, 10);
+        assert_eq!(msg.usage.output_tokens, 30);
+        assert_eq!(
+            msg.tool_uses().next().unwrap().input,
+            json!({"command": "ls"})
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_blocks() -> anyhow::Result<()> {
+        let value = json!([
+            {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw=="}},
+            {"type": "document", "source": {"type": "text", "media_type": "text/plain", "data": "hi"}, "title": "a.txt"},
+            {"type": "tool_result", "tool_use_id": "toolu_01", "content": "ok"},
+            {"type": "thinking", "thinking": "hmm", "signature": "sig"},
+            {"type": "image", "source": {"type": "base64", "media_type": "image/bmp", "data": "Qk0="}},
+        ]);
+        let blocks: Vec<ContentBlockParam> = serde_json::from_value(value.clone())?;
+        assert!(matches!(
+            &blocks[0],
+            ContentBlockParam::Image(ImageBlockParam {
+                source: ImageSource::Base64 {
+                    media_type: ImageMediaType::Png,
+                    ..
+                },
+                ..
+            })
+        ));
+        assert!(
+            matches!(&blocks[1], ContentBlockParam::Document(doc) if doc.title.as_deref() == Some("a.txt"))
+        );
+        assert!(
+            matches!(&blocks[2], ContentBlockParam::ToolResult(result) if result.text() == "ok")
+        );
+        assert!(matches!(&blocks[3], ContentBlockParam::Thinking(_)));
+        assert!(matches!(&blocks[4], ContentBlockParam::Other(_)));
+        assert!(!format!("{:?}", blocks[0]).contains("iVBORw=="));
+        assert!(!format!("{:?}", blocks[4]).contains("Qk0="));
+        assert_eq!(serde_json::to_value(&blocks)?, value);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_from_file() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("cc-sdk-blocks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let png = dir.join("a.png");
+        std::fs::write(&png, b"\x89PNG\r\n\x1a\n0000")?;
+        let txt = dir.join("a.txt");
+        std::fs::write(&txt, "hello")?;
+        let bin = dir.join("a.bin");
+        std::fs::write(&bin, [0xffu8, 0xfe, 0x00])?;
+
+        let block = ContentBlockParam::from_file(&png).await?;
+        assert!(matches!(block, ContentBlockParam::Image(_)));
+        let block = ContentBlockParam::from_file(&txt).await?;
+        let ContentBlockParam::Document(doc) = block else {
+            panic!("expected a document");
+        };
+        assert_eq!(doc.title.as_deref(), Some("a.txt"));
+        assert!(matches!(doc.source, DocumentSource::Text { ref data, .. } if data == "hello"));
+        assert!(ContentBlockParam::from_file(&bin).await.is_err());
+        assert!(ImageBlockParam::from_file(&txt).await.is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
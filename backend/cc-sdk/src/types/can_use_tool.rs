@@ -69,7 +69,7 @@ pub struct PermissionRuleValue {
     pub rule_content: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub enum PermissionBehavior {
     Allow,
@@ -0,0 +1,363 @@
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::types::{CompactMetadataTrigger, PermissionMode, ToolUseParams};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HookEvent {
+    PreToolUse,
+    PostToolUse,
+    Notification,
+    UserPromptSubmit,
+    Stop,
+    SubagentStop,
+    PreCompact,
+}
+
+impl HookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookEvent::PreToolUse => "PreToolUse",
+            HookEvent::PostToolUse => "PostToolUse",
+            HookEvent::Notification => "Notification",
+            HookEvent::UserPromptSubmit => "UserPromptSubmit",
+            HookEvent::Stop => "Stop",
+            HookEvent::SubagentStop => "SubagentStop",
+            HookEvent::PreCompact => "PreCompact",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HookCallbackMatcher {
+    /// Tool name pattern for `PreToolUse` and `PostToolUse`, e.g. `Bash` or `Edit|Write`.
+    /// `None` matches everything.
+    pub matcher: Option<String>,
+    pub hooks: Vec<BoxedHookCallBack>,
+}
+
+impl HookCallbackMatcher {
+    pub fn new(matcher: Option<&str>) -> Self {
+        Self {
+            matcher: matcher.map(|m| m.to_owned()),
+            hooks: vec![],
+        }
+    }
+
+    pub fn hook<T: HookCallBack>(mut self, hook: T) -> Self {
+        self.hooks.push(hook.boxed());
+        self
+    }
+
+    pub fn hook_fn<F, Fut>(self, f: F) -> Self
+    where
+        F: FnMut(HookInput, Option<String>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<HookOutput>> + Send,
+    {
+        self.hook(HookFn(f))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct HookInput {
+    pub session_id: String,
+    pub transcript_path: String,
+    pub cwd: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permission_mode: Option<PermissionMode>,
+    #[serde(flatten)]
+    pub event: HookEventInput,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "hook_event_name")]
+pub enum HookEventInput {
+    PreToolUse {
+        tool_name: String,
+        tool_input: Value,
+    },
+    PostToolUse {
+        tool_name: String,
+        tool_input: Value,
+        tool_response: Value,
+    },
+    Notification {
+        message: String,
+        title: Option<String>,
+    },
+    UserPromptSubmit {
+        prompt: String,
+    },
+    Stop {
+        stop_hook_active: bool,
+    },
+    SubagentStop {
+        stop_hook_active: bool,
+    },
+    PreCompact {
+        trigger: CompactMetadataTrigger,
+        custom_instructions: Option<String>,
+    },
+    /// An event added by a newer CLI, or a known one that doesn't parse, kept as it is.
+    #[serde(untagged)]
+    Unknown(Value),
+}
+
+impl HookEventInput {
+    /// `None` for [`HookEventInput::Unknown`].
+    pub fn event(&self) -> Option<HookEvent> {
+        let event = match self {
+            HookEventInput::PreToolUse { .. } => HookEvent::PreToolUse,
+            HookEventInput::PostToolUse { .. } => HookEvent::PostToolUse,
+            HookEventInput::Notification { .. } => HookEvent::Notification,
+            HookEventInput::UserPromptSubmit { .. } => HookEvent::UserPromptSubmit,
+            HookEventInput::Stop { .. } => HookEvent::Stop,
+            HookEventInput::SubagentStop { .. } => HookEvent::SubagentStop,
+            HookEventInput::PreCompact { .. } => HookEvent::PreCompact,
+            HookEventInput::Unknown(..) => return None,
+        };
+        Some(event)
+    }
+
+    /// Typed view of the tool call for `PreToolUse` and `PostToolUse` hooks.
+    pub fn tool_use(&self) -> Option<ToolUseParams> {
+        let (tool_name, tool_input) = match self {
+            HookEventInput::PreToolUse {
+                tool_name,
+                tool_input,
+            }
+            | HookEventInput::PostToolUse {
+                tool_name,
+                tool_input,
+                ..
+            } => (tool_name, tool_input),
+            _ => return None,
+        };
+
+        serde_json::from_value(json!({
+            "tool_name": tool_name,
+            "input": tool_input,
+        }))
+        .ok()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HookOutput {
+    #[serde(rename = "continue")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub r#continue: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suppress_output: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decision: Option<HookDecision>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hook_specific_output: Option<HookSpecificOutput>,
+}
+
+impl HookOutput {
+    /// Let the CLI carry on as if the hook was not there.
+    pub fn pass() -> Self {
+        Self::default()
+    }
+
+    /// `PreToolUse` output that decides the permission of the tool call.
+    pub fn permission(decision: HookPermissionDecision, reason: impl Into<String>) -> Self {
+        Self {
+            hook_specific_output: Some(HookSpecificOutput::PreToolUse {
+                permission_decision: Some(decision),
+                permission_decision_reason: Some(reason.into()),
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// Block the current action, the reason is shown to Claude.
+    pub fn block(reason: impl Into<String>) -> Self {
+        Self {
+            decision: Some(HookDecision::Block),
+            reason: Some(reason.into()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookDecision {
+    Approve,
+    Block,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookPermissionDecision {
+    Allow,
+    Deny,
+    Ask,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "hookEventName")]
+pub enum HookSpecificOutput {
+    #[serde(rename_all = "camelCase")]
+    PreToolUse {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        permission_decision: Option<HookPermissionDecision>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        permission_decision_reason: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    PostToolUse {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        additional_context: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    UserPromptSubmit {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        additional_context: Option<String>,
+    },
+}
+
+pub trait HookCallBack: Send + Sync + Debug + 'static + Sized {
+    fn call(
+        &mut self,
+        input: HookInput,
+        tool_use_id: Option<String>,
+    ) -> impl Future<Output = anyhow::Result<HookOutput>> + Send;
+
+    fn boxed(self) -> BoxedHookCallBack {
+        Box::new(self)
+    }
+}
+
+#[async_trait::async_trait]
+pub trait HookCallBackDyn: Send + Sync + Debug + 'static {
+    async fn call(
+        &mut self,
+        input: HookInput,
+        tool_use_id: Option<String>,
+    ) -> anyhow::Result<HookOutput>;
+}
+
+#[async_trait::async_trait]
+impl<T> HookCallBackDyn for T
+where
+    T: HookCallBack,
+{
+    async fn call(
+        &mut self,
+        input: HookInput,
+        tool_use_id: Option<String>,
+    ) -> anyhow::Result<HookOutput> {
+        HookCallBack::call(self, input, tool_use_id).await
+    }
+}
+
+pub type BoxedHookCallBack = Box<dyn HookCallBackDyn>;
+
+pub struct HookFn<F>(pub F);
+
+impl<F> Debug for HookFn<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("HookFn(..)")
+    }
+}
+
+impl<F, Fut> HookCallBack for HookFn<F>
+where
+    F: FnMut(HookInput, Option<String>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<HookOutput>> + Send,
+{
+    fn call(
+        &mut self,
+        input: HookInput,
+        tool_use_id: Option<String>,
+    ) -> impl Future<Output = anyhow::Result<HookOutput>> + Send {
+        (self.0)(input, tool_use_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pre_tool_use() -> anyhow::Result<()> {
+        let input: HookInput = serde_json::from_value(json!({
+            "session_id": "s1",
+            "transcript_path": "/tmp/s1.jsonl",
+            "cwd": "/tmp",
+            "permission_mode": "default",
+            "hook_event_name": "PreToolUse",
+            "tool_name": "Bash",
+            "tool_input": { "command": "git status" }
+        }))?;
+
+        assert_eq!(input.event.event(), Some(HookEvent::PreToolUse));
+        let Some(ToolUseParams::Bash { input }) = input.event.tool_use() else {
+            panic!("expect Bash tool use");
+        };
+        assert_eq!(input.command, "git status");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_unknown_event() -> anyhow::Result<()> {
+        let base = json!({
+            "session_id": "s1",
+            "transcript_path": "/tmp/s1.jsonl",
+            "cwd": "/tmp",
+            "source": "startup",
+        });
+        let parse = |event: Value| -> anyhow::Result<HookInput> {
+            let mut value = base.clone();
+            value
+                .as_object_mut()
+                .unwrap()
+                .extend(event.as_object().unwrap().clone());
+            Ok(serde_json::from_value(value)?)
+        };
+
+        let input = parse(json!({ "hook_event_name": "SessionStart" }))?;
+        assert_eq!(input.event.event(), None);
+        let HookEventInput::Unknown(event) = &input.event else {
+            panic!("expect an unknown event: {:?}", input.event);
+        };
+        assert_eq!(event["hook_event_name"], "SessionStart");
+
+        let input = parse(json!({ "hook_event_name": "PreCompact", "trigger": "scheduled" }))?;
+        assert!(matches!(input.event, HookEventInput::Unknown(..)));
+
+        // New fields of known events are ignored.
+        let input = parse(json!({ "hook_event_name": "Stop", "stop_hook_active": false }))?;
+        assert_eq!(input.event.event(), Some(HookEvent::Stop));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize_output() {
+        let output = HookOutput::permission(HookPermissionDecision::Deny, "not allowed");
+        assert_eq!(
+            serde_json::to_value(output).unwrap(),
+            json!({
+                "hookSpecificOutput": {
+                    "hookEventName": "PreToolUse",
+                    "permissionDecision": "deny",
+                    "permissionDecisionReason": "not allowed"
+                }
+            })
+        );
+    }
+}
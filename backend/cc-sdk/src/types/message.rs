@@ -2,9 +2,9 @@ use std::{fmt::Debug, sync::Arc};
 
 use derive_more::From;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
 
-use crate::types::anthropic::ContentBlockParam;
+use crate::types::anthropic::{self, ContentBlockParam, ToolResultBlockParam};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SDKMessage {
@@ -13,22 +13,132 @@ pub struct SDKMessage {
     pub typed: SDKMessageTyped,
 }
 
+impl SDKMessage {
+    /// Fields and message types this crate doesn't know yet, e.g. `result.foo`.
+    /// Empty if the message is fully typed.
+    pub fn unknown_fields(&self) -> Vec<String> {
+        let mut unknown = vec![];
+        match &self.typed {
+            SDKMessageTyped::Assistant(..)
+            | SDKMessageTyped::User(..)
+            | SDKMessageTyped::StreamEvent(..) => {}
+            SDKMessageTyped::Result(result) => match result {
+                SDKResultMessage::Success(SDKResultSuccessMessage {
+                    permission_denials,
+                    extra,
+                    ..
+                })
+                | SDKResultMessage::ErrorMaxTurns(SDKResultErrorMessage {
+                    permission_denials,
+                    extra,
+                    ..
+                })
+                | SDKResultMessage::ErrorDuringExecution(SDKResultErrorMessage {
+                    permission_denials,
+                    extra,
+                    ..
+                }) => {
+                    collect_extra(&mut unknown, "result", extra);
+                    for denial in permission_denials {
+                        collect_extra(&mut unknown, "result.permission_denials", &denial.extra);
+                    }
+                }
+                SDKResultMessage::Unknown(value) => {
+                    unknown.push(format!("result.subtype={}", value["subtype"]));
+                }
+            },
+            SDKMessageTyped::System(system) => match system {
+                SDKSystemMessage::Init(init) => {
+                    collect_extra(&mut unknown, "system", &init.extra);
+                    for server in &init.mcp_servers {
+                        collect_extra(&mut unknown, "system.mcp_servers", &server.extra);
+                    }
+                }
+                SDKSystemMessage::CompactBoundary(msg) => {
+                    collect_extra(&mut unknown, "system", &msg.extra);
+                    let metadata = &msg.compact_metadata.extra;
+                    collect_extra(&mut unknown, "system.compact_metadata", metadata);
+                }
+                SDKSystemMessage::Unknown(value) => {
+                    unknown.push(format!("system.subtype={}", value["subtype"]));
+                }
+            },
+            SDKMessageTyped::Unknown(value) => {
+                unknown.push(format!("type={}", value["type"]));
+            }
+        }
+
+        unknown
+    }
+}
+
+impl SDKMessage {
+    /// Fields of `raw` that this crate drops, found by serializing the message again.
+    /// Assistant, user and stream event payloads have no `extra` map, so
+    /// [`unknown_fields`](Self::unknown_fields) can't see their unknown fields.
+    /// Empty for other message types.
+    pub fn dropped_fields(&self, raw: &Value) -> Vec<String> {
+        let path = match &self.typed {
+            SDKMessageTyped::Assistant(..) => "assistant",
+            SDKMessageTyped::User(..) => "user",
+            SDKMessageTyped::StreamEvent(..) => "stream_event",
+            _ => return vec![],
+        };
+        let Ok(value) = serde_json::to_value(self) else {
+            return vec![];
+        };
+        let mut dropped = vec![];
+        collect_dropped(&mut dropped, path, raw, &value);
+        dropped
+    }
+}
+
+/// Null fields are skipped, they're dropped by `skip_serializing_if = "Option::is_none"`.
+fn collect_dropped(dropped: &mut Vec<String>, path: &str, raw: &Value, value: &Value) {
+    match (raw, value) {
+        (Value::Object(raw), Value::Object(value)) => {
+            for (key, raw) in raw {
+                let path = format!("{path}.{key}");
+                match value.get(key) {
+                    Some(value) => collect_dropped(dropped, &path, raw, value),
+                    None if raw.is_null() => {}
+                    None if dropped.contains(&path) => {}
+                    None => dropped.push(path),
+                }
+            }
+        }
+        (Value::Array(raw), Value::Array(value)) => {
+            let path = format!("{path}[]");
+            for (raw, value) in raw.iter().zip(value) {
+                collect_dropped(dropped, &path, raw, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn collect_extra(unknown: &mut Vec<String>, path: &str, extra: &Map<String, Value>) {
+    unknown.extend(extra.keys().map(|key| format!("{path}.{key}")));
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-#[serde(deny_unknown_fields)]
 pub enum SDKMessageTyped {
     Assistant(SDKAssistantMessage),
     User(SDKUserMessage),
     Result(SDKResultMessage),
     System(SDKSystemMessage),
     StreamEvent(SDKPartialAssistantMessage),
+    /// A message type added by a newer CLI, or a known one that doesn't parse.
+    #[serde(untagged)]
+    Unknown(Value),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SDKAssistantMessage {
     pub uuid: String,
-    pub message: Value,
+    pub message: anthropic::Message,
     pub parent_tool_use_id: Option<String>,
 }
 
@@ -66,6 +176,28 @@ pub enum UserContent {
     Vec(Vec<ContentBlockParam>),
 }
 
+impl UserContent {
+    /// The `tool_result` blocks, in order.
+    pub fn tool_results(&self) -> Vec<ToolResultBlockParam> {
+        let UserContent::Vec(blocks) = self else {
+            return vec![];
+        };
+        blocks
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlockParam::ToolResult(result) => Some(result.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl From<&str> for UserContent {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_owned())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SDKUserMessageReplay {
     pub message: Value,
@@ -73,21 +205,55 @@ pub struct SDKUserMessageReplay {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
 pub struct SDKPermissionDenial {
     pub tool_name: String,
     pub tool_use_id: String,
     pub tool_input: Value,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Only a subtype this crate doesn't know is [`Unknown`](Self::Unknown). A known subtype
+/// that doesn't parse, e.g. after the CLI changed `usage`, is an error, so the message
+/// becomes [`SDKMessageTyped::Unknown`].
+#[derive(Serialize, Debug)]
 #[serde(tag = "subtype")]
 #[serde(rename_all = "snake_case")]
-#[serde(deny_unknown_fields)]
 pub enum SDKResultMessage {
     Success(SDKResultSuccessMessage),
     ErrorMaxTurns(SDKResultErrorMessage),
     ErrorDuringExecution(SDKResultErrorMessage),
+    #[serde(untagged)]
+    Unknown(Value),
+}
+
+impl<'de> Deserialize<'de> for SDKResultMessage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let mut value = Value::deserialize(deserializer)?;
+        let subtype = match value["subtype"].as_str() {
+            Some(subtype @ ("success" | "error_max_turns" | "error_during_execution")) => {
+                subtype.to_owned()
+            }
+            _ => return Ok(Self::Unknown(value)),
+        };
+        if let Some(map) = value.as_object_mut() {
+            map.remove("subtype");
+        }
+        let parse_err = |err| D::Error::custom(format!("Invalid result/{subtype}: {err}"));
+        let msg = match subtype.as_str() {
+            "success" => Self::Success(serde_json::from_value(value).map_err(parse_err)?),
+            "error_max_turns" => {
+                Self::ErrorMaxTurns(serde_json::from_value(value).map_err(parse_err)?)
+            }
+            _ => Self::ErrorDuringExecution(serde_json::from_value(value).map_err(parse_err)?),
+        };
+        Ok(msg)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -99,10 +265,12 @@ pub struct SDKResultSuccessMessage {
     pub num_turns: u32,
     pub result: String,
     pub total_cost_usd: f64,
-    pub usage: Value,
+    pub usage: anthropic::Usage,
     #[serde(rename = "modelUsage")]
     pub model_usage: std::collections::HashMap<String, ModelUsage>,
     pub permission_denials: Vec<SDKPermissionDenial>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -113,13 +281,15 @@ pub struct SDKResultErrorMessage {
     pub is_error: bool,
     pub num_turns: u32,
     pub total_cost_usd: f64,
-    pub usage: Value,
+    pub usage: anthropic::Usage,
     #[serde(rename = "modelUsage")]
     pub model_usage: std::collections::HashMap<String, ModelUsage>,
     pub permission_denials: Vec<SDKPermissionDenial>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ModelUsage {
     pub input_tokens: u64,
@@ -131,13 +301,50 @@ pub struct ModelUsage {
     pub cost_usd: f64,
 }
 
+impl ModelUsage {
+    pub fn add(&mut self, other: &ModelUsage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_read_input_tokens += other.cache_read_input_tokens;
+        self.cache_creation_input_tokens += other.cache_creation_input_tokens;
+        self.web_search_requests += other.web_search_requests;
+        self.cost_usd += other.cost_usd;
+    }
+
+    /// `self - other`, saturating at zero.
+    pub fn sub(&self, other: &ModelUsage) -> ModelUsage {
+        ModelUsage {
+            input_tokens: self.input_tokens.saturating_sub(other.input_tokens),
+            output_tokens: self.output_tokens.saturating_sub(other.output_tokens),
+            cache_read_input_tokens: self
+                .cache_read_input_tokens
+                .saturating_sub(other.cache_read_input_tokens),
+            cache_creation_input_tokens: self
+                .cache_creation_input_tokens
+                .saturating_sub(other.cache_creation_input_tokens),
+            web_search_requests: self
+                .web_search_requests
+                .saturating_sub(other.web_search_requests),
+            cost_usd: (self.cost_usd - other.cost_usd).max(0.0),
+        }
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.input_tokens
+            + self.output_tokens
+            + self.cache_read_input_tokens
+            + self.cache_creation_input_tokens
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "subtype")]
 #[serde(rename_all = "snake_case")]
-#[serde(deny_unknown_fields)]
 pub enum SDKSystemMessage {
     Init(SDKSystemInitMessage),
     CompactBoundary(SDKCompactBoundaryMessage),
+    #[serde(untagged)]
+    Unknown(Value),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -153,6 +360,8 @@ pub struct SDKSystemInitMessage {
     pub permission_mode: PermissionMode,
     pub slash_commands: Vec<String>,
     pub output_style: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -185,17 +394,29 @@ pub enum ApiKeySource {
     None,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MCPServerStatus {
     pub name: String,
-    pub status: String,
+    pub status: MCPServerConnectionStatus,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MCPServerConnectionStatus {
+    Connected,
+    Failed,
+    NeedsAuth,
+    Pending,
+    #[serde(other)]
+    Unknown,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SDKPartialAssistantMessage {
     pub uuid: String,
-    pub event: Value,
+    pub event: anthropic::MessageStreamEvent,
     pub parent_tool_use_id: Option<String>,
 }
 
@@ -203,13 +424,16 @@ pub struct SDKPartialAssistantMessage {
 pub struct SDKCompactBoundaryMessage {
     pub uuid: String,
     pub compact_metadata: CompactMetadata,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-#[serde(deny_unknown_fields)]
 pub struct CompactMetadata {
     pub trigger: CompactMetadataTrigger,
     pub pre_tokens: u64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -240,4 +464,123 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_parse_unknown() -> anyhow::Result<()> {
+        let msg: SDKMessage = serde_json::from_value(serde_json::json!({
+            "type": "result",
+            "subtype": "error_max_budget",
+            "session_id": "s1",
+            "uuid": "u1"
+        }))?;
+        assert!(matches!(
+            msg.typed,
+            SDKMessageTyped::Result(SDKResultMessage::Unknown(..))
+        ));
+        assert_eq!(
+            msg.unknown_fields(),
+            ["result.subtype=\"error_max_budget\""]
+        );
+
+        let value = serde_json::json!({
+            "type": "system",
+            "subtype": "compact_boundary",
+            "session_id": "s1",
+            "uuid": "u1",
+            "compact_metadata": { "trigger": "auto", "pre_tokens": 1000, "post_tokens": 100 },
+            "parent_uuid": "u0"
+        });
+        let msg: SDKMessage = serde_json::from_value(value.clone())?;
+        assert_eq!(
+            msg.unknown_fields(),
+            ["system.parent_uuid", "system.compact_metadata.post_tokens"]
+        );
+        assert_eq!(serde_json::to_value(&msg)?, value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_invalid_result() -> anyhow::Result<()> {
+        let mut value = serde_json::json!({
+            "type": "result",
+            "subtype": "success",
+            "session_id": "s1",
+            "uuid": "u1",
+            "duration_ms": 1,
+            "duration_api_ms": 1,
+            "is_error": false,
+            "num_turns": 1,
+            "result": "done",
+            "total_cost_usd": 0.5,
+            "usage": { "input_tokens": 1, "output_tokens": 1 },
+            "modelUsage": {},
+            "permission_denials": []
+        });
+        let msg: SDKMessage = serde_json::from_value(value.clone())?;
+        assert!(msg.unknown_fields().is_empty());
+        assert_eq!(serde_json::to_value(&msg)?, value);
+
+        value["usage"] = serde_json::json!({ "input_tokens": "many" });
+        let result: Result<SDKResultMessage, _> = serde_json::from_value(value.clone());
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("result/success"), "{err}");
+        let msg: SDKMessage = serde_json::from_value(value)?;
+        assert!(matches!(msg.typed, SDKMessageTyped::Unknown(..)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dropped_fields() -> anyhow::Result<()> {
+        let raw = serde_json::json!({
+            "type": "assistant",
+            "session_id": "s1",
+            "uuid": "u1",
+            "parent_tool_use_id": null,
+            "message": {
+                "id": "msg_01",
+                "type": "message",
+                "role": "assistant",
+                "model": "claude-sonnet-4-5",
+                "content": [
+                    { "type": "text", "text": "hi", "citations": null },
+                    { "type": "tool_use", "id": "toolu_01", "name": "Bash", "input": { "command": "ls" } }
+                ],
+                "stop_reason": null,
+                "stop_sequence": null,
+                "usage": { "input_tokens": 1, "output_tokens": 1, "service_tier": "standard" }
+            }
+        });
+        let msg: SDKMessage = serde_json::from_value(raw.clone())?;
+        assert!(msg.dropped_fields(&raw).is_empty());
+
+        let mut raw = raw;
+        raw["message"]["usage"]["inference_geo"] = "us".into();
+        raw["message"]["content"][0]["new_field"] = 1.into();
+        let msg: SDKMessage = serde_json::from_value(raw.clone())?;
+        assert_eq!(
+            msg.dropped_fields(&raw),
+            [
+                "assistant.message.content[].new_field",
+                "assistant.message.usage.inference_geo"
+            ]
+        );
+
+        let raw = serde_json::json!({
+            "type": "user",
+            "session_id": "s1",
+            "uuid": "u2",
+            "parent_tool_use_id": null,
+            "message": {
+                "role": "user",
+                "content": [{ "type": "tool_result", "tool_use_id": "toolu_01", "content": "ok", "is_error": false }]
+            },
+            "is_synthetic": true
+        });
+        let msg: SDKMessage = serde_json::from_value(raw.clone())?;
+        assert_eq!(msg.dropped_fields(&raw), ["user.is_synthetic"]);
+
+        Ok(())
+    }
 }
@@ -1,8 +1,15 @@
-use std::{collections::HashMap, fmt::Debug, path::PathBuf};
+use std::{collections::HashMap, fmt::Debug, path::PathBuf, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
-use crate::types::{CanUseToolCallBackDyn, PermissionMode, can_use_tool::PermissionUpdate};
+use crate::{
+    abort::AbortController,
+    mcp::McpServerConfig,
+    types::{
+        CanUseToolCallBackDyn, HookCallbackMatcher, HookEvent, PermissionMode,
+        can_use_tool::PermissionUpdate,
+    },
+};
 
 pub type Dict<T> = HashMap<String, T>;
 
@@ -12,12 +19,21 @@ pub trait DebugCallBack: Debug + Send {
 
 #[derive(Debug, Default)]
 pub struct ClaudeCodeOptions {
-    pub abort_controller: Option<Unsupported>,
+    pub abort_controller: Option<AbortController>,
+    /// How long an aborted query may take to finish the interrupted turn before the CLI is
+    /// killed. Defaults to [`DEFAULT_ABORT_GRACE_PERIOD`](crate::cli::DEFAULT_ABORT_GRACE_PERIOD).
+    pub abort_grace_period: Option<Duration>,
     pub additional_directories: Option<Vec<String>>,
     pub allowed_tools: Option<Vec<String>>,
     pub append_system_prompt: Option<String>,
     pub can_use_tool: Option<Box<dyn CanUseToolCallBackDyn>>,
+    /// Sets `CLAUDE_CONFIG_DIR` for this process, so the CLI reads its settings, credentials
+    /// and sessions from there instead of `~/.claude`.
+    pub config_dir: Option<PathBuf>,
     pub r#continue: Option<bool>,
+    /// How long to wait for the CLI to answer a control request like `interrupt`.
+    /// Defaults to [`DEFAULT_CONTROL_REQUEST_TIMEOUT`](crate::cli::DEFAULT_CONTROL_REQUEST_TIMEOUT).
+    pub control_request_timeout: Option<Duration>,
     pub custom_system_prompt: Option<String>,
     pub cwd: Option<PathBuf>,
     pub disallowed_tools: Option<Vec<String>>,
@@ -26,22 +42,61 @@ pub struct ClaudeCodeOptions {
     pub executable_args: Option<Vec<String>>,
     pub extra_args: Option<HashMap<String, Option<String>>>,
     pub fallback_model: Option<String>,
+    /// With `resume` or `continue`, start a new session from the old one instead of
+    /// appending to it. The new ID is in [`QueryStream::session_id`].
+    ///
+    /// [`QueryStream::session_id`]: crate::cli::QueryStream::session_id
+    pub fork_session: Option<bool>,
 
-    /// unsupported yet
-    pub hooks: Option<Unsupported>,
+    pub hooks: Option<HashMap<HookEvent, Vec<HookCallbackMatcher>>>,
 
     pub include_partial_messages: Option<bool>,
 
+    /// How long to wait for the CLI to answer `initialize` in streaming mode.
+    /// Defaults to [`DEFAULT_INIT_TIMEOUT`](crate::cli::DEFAULT_INIT_TIMEOUT).
+    pub init_timeout: Option<Duration>,
+
+    /// Stops the query once it has cost more than this, see [`BudgetExceeded`].
+    /// The CLI only reports the cost at the end of a turn. Within a turn it's estimated
+    /// after every assistant message with [`estimate_cost_usd`], which can be off for
+    /// long context or server tool use, so a turn may overshoot the limit a little.
+    ///
+    /// [`BudgetExceeded`]: crate::cost::BudgetExceeded
+    /// [`estimate_cost_usd`]: crate::cost::estimate_cost_usd
+    pub max_budget_usd: Option<f64>,
     /// It's not used in official typescript sdk
     pub max_thinking_tokens: Option<Unsupported>,
 
+    /// Stops the query once it has used more tokens than this, cache reads and writes
+    /// included. Checked after every assistant message.
+    pub max_total_tokens: Option<u64>,
+
     pub max_turns: Option<u32>,
-    pub mcp_servers: Option<Unsupported>,
+    pub mcp_servers: Option<Dict<McpServerConfig>>,
     pub model: Option<String>,
+    /// Capacity of the queue between the CLI's stdout and the stream. When it's full, up to
+    /// as many messages again are held back so that control responses behind them still get
+    /// through, then reading stdout pauses until the consumer catches up. Unbounded if not set.
+    /// Must not be 0.
+    pub output_capacity: Option<usize>,
+    /// How to handle messages from the CLI that this crate doesn't fully know.
+    pub parse_mode: Option<ParseMode>,
     pub path_to_claude_code_executable: Option<PathBuf>,
     pub permission_mode: Option<PermissionMode>,
     pub permission_prompt_tool_name: Option<String>,
     pub resume: Option<String>,
+    /// With `resume`, only restore the session up to this message UUID.
+    pub resume_session_at: Option<String>,
+    /// JS runtimes to try in order when `executable` is not set.
+    /// Defaults to [`RUNTIME_ENV`](crate::runtime::RUNTIME_ENV), then node, bun and deno.
+    pub runtime_preference: Option<Vec<Executable>>,
+    /// Extra settings for this process only, passed as `--settings`. Either a path to a JSON
+    /// file or a JSON string. They take precedence over the user and project settings.
+    pub settings: Option<String>,
+    /// How long [`QueryStream::shutdown`](crate::cli::QueryStream::shutdown) waits for the CLI
+    /// to exit before each escalation. Defaults to
+    /// [`DEFAULT_SHUTDOWN_TIMEOUT`](crate::cli::DEFAULT_SHUTDOWN_TIMEOUT).
+    pub shutdown_timeout: Option<Duration>,
     pub stderr: Option<Box<dyn DebugCallBack>>,
     pub strict_mcp_config: Option<bool>,
 }
@@ -49,6 +104,16 @@ pub struct ClaudeCodeOptions {
 #[derive(Debug)]
 pub enum Unsupported {}
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Keep unknown fields and message types, and keep the session running on parse errors.
+    #[default]
+    Tolerant,
+    /// Any unknown field, unknown message type or parse error ends the session.
+    /// Meant for tests that guard the typed model against new CLI releases.
+    Strict,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct CandUseToolParamOptions {
     pub suggestions: Vec<PermissionUpdate>,
@@ -56,7 +121,7 @@ pub struct CandUseToolParamOptions {
 
 pub type CanUseTool = Box<dyn Fn(&str) -> bool + Send + Sync>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Executable {
     Bun,
     Deno,
@@ -117,6 +117,12 @@ pub enum ToolUseParams {
 #[derive(Serialize, Debug)]
 pub struct McpToolName(String);
 
+impl McpToolName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 impl<'de> Deserialize<'de> for McpToolName {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+use crate::types::{
+    FileEditOperation, GrepOutputMode, SDKMessage, SDKMessageTyped, TodoItem, ToolUseParams,
+    UserContent,
+    anthropic::{self, ToolResultBlockParam, ToolUseBlock},
+};
+
+/// The structured output of a tool call, the `toolUseResult` of a session log entry.
+///
+/// It isn't tagged, so it's parsed by the name of the tool with [`ToolUseResult::parse`].
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum ToolUseResult {
+    Bash(BashResult),
+    Read(FileReadResult),
+    Edit(FileEditResult),
+    MultiEdit(FileMultiEditResult),
+    Write(FileWriteResult),
+    Grep(GrepResult),
+    Glob(GlobResult),
+    TodoWrite(TodoWriteResult),
+    WebFetch(WebFetchResult),
+    WebSearch(WebSearchResult),
+    /// Other tools, errors (usually a plain string) and outputs that don't parse.
+    Other(Value),
+}
+
+impl ToolUseResult {
+    pub fn parse(tool_name: &str, value: Value) -> Self {
+        fn typed<T: DeserializeOwned>(
+            value: &Value,
+            f: fn(T) -> ToolUseResult,
+        ) -> Option<ToolUseResult> {
+            T::deserialize(value).ok().map(f)
+        }
+
+        let result = match tool_name {
+            "Bash" => typed(&value, Self::Bash),
+            "Read" => typed(&value, Self::Read),
+            "Edit" => typed(&value, Self::Edit),
+            "MultiEdit" => typed(&value, Self::MultiEdit),
+            "Write" => typed(&value, Self::Write),
+            "Grep" => typed(&value, Self::Grep),
+            "Glob" => typed(&value, Self::Glob),
+            "TodoWrite" => typed(&value, Self::TodoWrite),
+            "WebFetch" => typed(&value, Self::WebFetch),
+            "WebSearch" => typed(&value, Self::WebSearch),
+            _ => None,
+        };
+        result.unwrap_or(Self::Other(value))
+    }
+
+    /// The diff of a file change. Empty for other tools.
+    pub fn structured_patch(&self) -> &[StructuredPatchHunk] {
+        match self {
+            Self::Edit(result) => &result.structured_patch,
+            Self::MultiEdit(result) => &result.structured_patch,
+            Self::Write(result) => &result.structured_patch,
+            _ => &[],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BashResult {
+    pub stdout: String,
+    pub stderr: String,
+    pub interrupted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is_image: Option<bool>,
+    /// Explains a non-zero exit code that isn't an error, e.g. `grep` finding nothing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub return_code_interpretation: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_task_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum FileReadResult {
+    Text { file: TextFile },
+    Image { file: ImageFile },
+    Notebook { file: NotebookFile },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TextFile {
+    pub file_path: String,
+    pub content: String,
+    pub num_lines: u64,
+    pub start_line: u64,
+    pub total_lines: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageFile {
+    pub base64: String,
+    /// The MIME type, e.g. `image/png`.
+    pub r#type: String,
+    pub original_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookFile {
+    pub file_path: String,
+    pub cells: Vec<Value>,
+}
+
+/// A hunk of a unified diff. `lines` start with ` `, `-` or `+`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StructuredPatchHunk {
+    pub old_start: u64,
+    pub old_lines: u64,
+    pub new_start: u64,
+    pub new_lines: u64,
+    pub lines: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileEditResult {
+    pub file_path: String,
+    pub old_string: String,
+    pub new_string: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_file: Option<String>,
+    pub structured_patch: Vec<StructuredPatchHunk>,
+    /// Whether the user changed the edit before accepting it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_modified: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replace_all: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileMultiEditResult {
+    pub file_path: String,
+    pub edits: Vec<FileEditOperation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_file_contents: Option<String>,
+    pub structured_patch: Vec<StructuredPatchHunk>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_modified: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FileWriteResult {
+    pub r#type: FileWriteKind,
+    pub file_path: String,
+    pub content: String,
+    /// Empty when a file is created.
+    #[serde(default)]
+    pub structured_patch: Vec<StructuredPatchHunk>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileWriteKind {
+    Create,
+    Update,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GrepResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<GrepOutputMode>,
+    pub filenames: Vec<String>,
+    pub num_files: u64,
+    /// The matching lines in `content` mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_lines: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_matches: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applied_limit: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GlobResult {
+    pub filenames: Vec<String>,
+    pub duration_ms: u64,
+    pub num_files: u64,
+    pub truncated: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TodoWriteResult {
+    pub old_todos: Vec<TodoItem>,
+    pub new_todos: Vec<TodoItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebFetchResult {
+    pub bytes: u64,
+    pub code: u16,
+    pub code_text: String,
+    /// The answer to the prompt about the page.
+    pub result: String,
+    pub duration_ms: u64,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WebSearchResult {
+    pub query: String,
+    pub results: Vec<WebSearchResultItem>,
+    pub duration_seconds: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum WebSearchResultItem {
+    Links {
+        tool_use_id: String,
+        content: Vec<WebSearchLink>,
+    },
+    /// Claude's comments between the searches.
+    Text(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebSearchLink {
+    pub title: String,
+    pub url: String,
+}
+
+/// A tool call with its result.
+#[derive(Debug)]
+pub struct ToolCall {
+    pub tool_use: ToolUseBlock,
+    pub result: ToolResultBlockParam,
+    /// Only available in session logs.
+    pub output: Option<ToolUseResult>,
+}
+
+impl ToolCall {
+    /// Typed input of the built-in tools.
+    pub fn params(&self) -> Option<ToolUseParams> {
+        self.tool_use.params()
+    }
+}
+
+/// Joins the tool calls of assistant messages to their results in the following user messages.
+#[derive(Debug, Default)]
+pub struct ToolCallJoiner {
+    pending: HashMap<String, ToolUseBlock>,
+}
+
+impl ToolCallJoiner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_assistant(&mut self, message: &anthropic::Message) {
+        for tool_use in message.tool_uses() {
+            self.pending.insert(tool_use.id.clone(), tool_use.clone());
+        }
+    }
+
+    /// Returns the calls answered by the message. `tool_use_result` is the `toolUseResult`
+    /// of a session log entry. The CLI writes one result per message, so it's only used when
+    /// the message has a single result.
+    pub fn push_user(
+        &mut self,
+        content: &UserContent,
+        tool_use_result: Option<&Value>,
+    ) -> Vec<ToolCall> {
+        let results = content.tool_results();
+        let tool_use_result = tool_use_result.filter(|_| results.len() == 1);
+        results
+            .into_iter()
+            .filter_map(|result| {
+                let tool_use = self.pending.remove(&result.tool_use_id)?;
+                let output = tool_use_result
+                    .map(|value| ToolUseResult::parse(&tool_use.name, value.clone()));
+                Some(ToolCall {
+                    tool_use,
+                    result,
+                    output,
+                })
+            })
+            .collect()
+    }
+
+    /// Feeds a message of a [`QueryStream`](crate::cli::QueryStream).
+    pub fn push_message(&mut self, msg: &SDKMessage) -> Vec<ToolCall> {
+        match &msg.typed {
+            SDKMessageTyped::Assistant(assistant) => {
+                self.push_assistant(&assistant.message);
+                vec![]
+            }
+            SDKMessageTyped::User(user) => self.push_user(&user.message.content, None),
+            _ => vec![],
+        }
+    }
+
+    /// Tool calls without a result yet.
+    pub fn pending(&self) -> impl Iterator<Item = &ToolUseBlock> {
+        self.pending.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_join() -> anyhow::Result<()> {
+        let assistant: anthropic::Message = serde_json::from_value(json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5",
+            "content": [{
+                "type": "tool_use",
+                "id": "toolu_1",
+                "name": "Edit",
+                "input": { "file_path": "/a.rs", "old_string": "a", "new_string": "b" }
+            }],
+            "stop_reason": "tool_use",
+            "stop_sequence": null,
+            "usage": { "input_tokens": 1, "output_tokens": 1 }
+        }))?;
+        let content: UserContent = serde_json::from_value(json!([{
+            "type": "tool_result",
+            "tool_use_id": "toolu_1",
+            "content": "The file /a.rs has been updated."
+        }]))?;
+        let tool_use_result = json!({
+            "filePath": "/a.rs",
+            "oldString": "a",
+            "newString": "b",
+            "originalFile": "a\n",
+            "structuredPatch": [{
+                "oldStart": 1, "oldLines": 1, "newStart": 1, "newLines": 1,
+                "lines": ["-a", "+b"]
+            }],
+            "userModified": false,
+            "replaceAll": false
+        });
+
+        let mut joiner = ToolCallJoiner::new();
+        joiner.push_assistant(&assistant);
+        assert_eq!(joiner.pending().count(), 1);
+        let calls = joiner.push_user(&content, Some(&tool_use_result));
+        assert_eq!(calls.len(), 1);
+        assert_eq!(joiner.pending().count(), 0);
+
+        let call = &calls[0];
+        assert!(matches!(call.params(), Some(ToolUseParams::Edit { .. })));
+        assert_eq!(call.result.text(), "The file /a.rs has been updated.");
+        let output = call.output.as_ref().unwrap();
+        assert!(matches!(output, ToolUseResult::Edit(..)));
+        assert_eq!(output.structured_patch()[0].lines, ["-a", "+b"]);
+        assert_eq!(serde_json::to_value(output)?, tool_use_result);
+
+        let error = ToolUseResult::parse("Read", json!("Error: File does not exist."));
+        assert!(matches!(error, ToolUseResult::Other(..)));
+        Ok(())
+    }
+}
@@ -0,0 +1,778 @@
+use std::{
+    sync::Arc,
+    task::{Poll, ready},
+    time::Duration,
+};
+
+use cc_sdk::{
+    abort::AbortController,
+    cli::{
+        ClaudeStreamError, ControlEvent, ControlRequestKind, InitError, PromptGenerator,
+        QueryStream, control_request_id,
+    },
+    client::ClaudeClient,
+    cost::BudgetExceeded,
+    mcp::SdkMcpServer,
+    query,
+    testing::{self, FAKE_SESSION_ID, FakeClaude, ScriptStep},
+    types::{
+        APIUserMessage, APIUserMessageRole, CanUseToolCallBack, ClaudeCodeOptions,
+        HookCallbackMatcher, HookEvent, HookEventInput, HookOutput, PermissionAllow,
+        PermissionMode, PermissionResult, PermissionUpdate, SDKMessageTyped, SDKResultMessage,
+        SDKUserMessage, ToolUseParams,
+    },
+};
+use serde_json::json;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tokio_stream::StreamExt;
+
+const FAKE_CLAUDE: &str = env!("CARGO_BIN_EXE_fake-claude");
+
+struct PromptGen {
+    receiver: UnboundedReceiver<String>,
+}
+
+impl PromptGenerator for PromptGen {
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<SDKUserMessage>> {
+        let Some(prompt) = ready!(self.get_mut().receiver.poll_recv(cx)) else {
+            return Poll::Ready(None);
+        };
+        Poll::Ready(Some(SDKUserMessage {
+            uuid: None,
+            message: APIUserMessage {
+                content: Arc::new(prompt.into()),
+                role: APIUserMessageRole::User,
+            },
+            parent_tool_use_id: None,
+        }))
+    }
+}
+
+async fn start(
+    script: FakeClaude,
+    mut options: ClaudeCodeOptions,
+) -> (QueryStream, UnboundedSender<String>) {
+    let _script = script.apply(FAKE_CLAUDE, &mut options).unwrap();
+    let (tx, rx) = unbounded_channel();
+    let stream = query(PromptGen { receiver: rx }, options).await.unwrap();
+    (stream, tx)
+}
+
+async fn wait_result(stream: &mut QueryStream) -> SDKResultMessage {
+    while let Some(msg) = stream.next().await {
+        if let SDKMessageTyped::Result(result) = msg.unwrap().typed {
+            return result;
+        }
+    }
+    panic!("stream ended without result")
+}
+
+#[derive(Debug)]
+struct AllowAll;
+
+impl CanUseToolCallBack for AllowAll {
+    async fn call(
+        &mut self,
+        tool_use: ToolUseParams,
+        _suggestions: Option<Vec<PermissionUpdate>>,
+    ) -> anyhow::Result<Arc<PermissionResult>> {
+        let request_id = control_request_id();
+        anyhow::ensure!(
+            request_id.is_some_and(|id| id.starts_with("fake_req_")),
+            "The control request ID is not set"
+        );
+        Ok(Arc::new(PermissionResult::Allow(PermissionAllow {
+            updated_input: tool_use.into(),
+            updated_permissions: None,
+        })))
+    }
+}
+
+#[derive(Debug)]
+struct NeverAnswer;
+
+impl CanUseToolCallBack for NeverAnswer {
+    async fn call(
+        &mut self,
+        _tool_use: ToolUseParams,
+        _suggestions: Option<Vec<PermissionUpdate>>,
+    ) -> anyhow::Result<Arc<PermissionResult>> {
+        std::future::pending().await
+    }
+}
+
+fn bash_permission_request() -> serde_json::Value {
+    json!({
+        "subtype": "can_use_tool",
+        "tool_name": "Bash",
+        "input": { "command": "ls" },
+        "permission_suggestions": null
+    })
+}
+
+#[tokio::test]
+async fn test_conversation() {
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .assistant_text("Hello from fake claude")
+        .result("done");
+    let (mut stream, tx) = start(script, Default::default()).await;
+    assert!(stream.claude_version().unwrap().is_supported());
+    tx.send("hi".to_string()).unwrap();
+
+    let msg = stream.next().await.unwrap().unwrap();
+    let SDKMessageTyped::Assistant(assistant) = msg.typed else {
+        panic!("expect assistant message, got {msg:?}");
+    };
+    assert_eq!(assistant.message.text(), "Hello from fake claude");
+    assert!(matches!(
+        wait_result(&mut stream).await,
+        SDKResultMessage::Success(..)
+    ));
+}
+
+#[tokio::test]
+async fn test_can_use_tool() {
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .step(ScriptStep::ControlRequest {
+            request: bash_permission_request(),
+            expect: Some(json!({
+                "subtype": "success",
+                "response": { "behavior": "allow", "updatedInput": { "command": "ls" } }
+            })),
+        })
+        .result("done");
+    let options = ClaudeCodeOptions {
+        can_use_tool: Some(AllowAll.boxed()),
+        ..Default::default()
+    };
+    let (mut stream, tx) = start(script, options).await;
+    tx.send("run ls".to_string()).unwrap();
+
+    assert!(matches!(
+        wait_result(&mut stream).await,
+        SDKResultMessage::Success(..)
+    ));
+}
+
+#[tokio::test]
+async fn test_cancel_can_use_tool() {
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .step(ScriptStep::CancelControlRequest {
+            request: bash_permission_request(),
+        })
+        .result("interrupted");
+    let options = ClaudeCodeOptions {
+        can_use_tool: Some(NeverAnswer.boxed()),
+        ..Default::default()
+    };
+    let (mut stream, tx) = start(script, options).await;
+    let mut events = stream.take_control_events().unwrap();
+    tx.send("run ls".to_string()).unwrap();
+
+    wait_result(&mut stream).await;
+    let ControlEvent::RequestCancelled { kind, .. } = events.recv().await.unwrap();
+    assert_eq!(kind, ControlRequestKind::CanUseTool);
+}
+
+#[tokio::test]
+async fn test_bounded_output() {
+    let mut script = FakeClaude::streaming().recv_user();
+    for i in 0..10 {
+        script = script.assistant_text(&format!("message {i}"));
+    }
+    let options = ClaudeCodeOptions {
+        output_capacity: Some(2),
+        ..Default::default()
+    };
+    let (mut stream, tx) = start(script.result("done"), options).await;
+    tx.send("hi".to_string()).unwrap();
+
+    // Let the reader fill the queue while nobody consumes it.
+    let filled = async {
+        while stream.output_queue_stats().depth < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), filled)
+        .await
+        .expect("the output queue never filled up");
+    assert_eq!(stream.output_queue_stats().depth, 2);
+
+    for i in 0..10 {
+        let msg = stream.next().await.unwrap().unwrap();
+        let SDKMessageTyped::Assistant(assistant) = msg.typed else {
+            panic!("expect assistant message, got {msg:?}");
+        };
+        assert_eq!(assistant.message.text(), format!("message {i}"));
+    }
+    wait_result(&mut stream).await;
+
+    let stats = stream.output_queue_stats();
+    assert_eq!(stats.high_water_mark, 2);
+    assert_eq!(stats.total, 11);
+}
+
+#[tokio::test]
+async fn test_interrupt_with_full_output() {
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .assistant_text("message 0")
+        .assistant_text("message 1")
+        .assistant_text("message 2")
+        .step(ScriptStep::RecvControl {
+            subtype: "interrupt".to_string(),
+            response: None,
+            error: None,
+        })
+        .result("interrupted");
+    let options = ClaudeCodeOptions {
+        output_capacity: Some(2),
+        control_request_timeout: Some(Duration::from_secs(3)),
+        ..Default::default()
+    };
+    let (mut stream, tx) = start(script, options).await;
+    tx.send("hi".to_string()).unwrap();
+
+    let filled = async {
+        while stream.output_queue_stats().depth < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), filled)
+        .await
+        .expect("the output queue never filled up");
+
+    // The control response is read even though the output queue is full.
+    stream.interrupt().await.unwrap();
+
+    for i in 0..3 {
+        let msg = stream.next().await.unwrap().unwrap();
+        let SDKMessageTyped::Assistant(assistant) = msg.typed else {
+            panic!("expect assistant message, got {msg:?}");
+        };
+        assert_eq!(assistant.message.text(), format!("message {i}"));
+    }
+    wait_result(&mut stream).await;
+}
+
+#[tokio::test]
+async fn test_crash() {
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .step(ScriptStep::Stderr("out of memory".to_string()))
+        .step(ScriptStep::Exit(1));
+    let (mut stream, tx) = start(script, Default::default()).await;
+    tx.send("hi".to_string()).unwrap();
+
+    let mut exited = None;
+    while let Some(msg) = stream.next().await {
+        if let Err(err) = msg {
+            exited = Some(err);
+        }
+    }
+    let Some(ClaudeStreamError::ProcessExited {
+        code, stderr_tail, ..
+    }) = exited
+    else {
+        panic!("expect ProcessExited, got {exited:?}");
+    };
+    assert_eq!(code, Some(1));
+    assert!(stderr_tail.contains("out of memory"));
+}
+
+#[tokio::test]
+async fn test_control_request_response() {
+    let script = FakeClaude::streaming()
+        .step(ScriptStep::RecvControl {
+            subtype: "set_permission_mode".to_string(),
+            response: None,
+            error: None,
+        })
+        .step(ScriptStep::RecvControl {
+            subtype: "set_model".to_string(),
+            response: None,
+            error: Some("Unknown model: gpt".to_string()),
+        })
+        .step(ScriptStep::IgnoreControl {
+            subtype: "interrupt".to_string(),
+        })
+        .assistant_text("cancelled");
+    let options = ClaudeCodeOptions {
+        control_request_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let (mut stream, _tx) = start(script, options).await;
+
+    stream
+        .set_permission_mode(PermissionMode::AcceptEdits)
+        .await
+        .unwrap();
+    let err = stream.set_model("gpt".to_string()).await.unwrap_err();
+    assert!(err.to_string().contains("Unknown model: gpt"));
+    let err = stream.interrupt().await.unwrap_err();
+    assert!(err.to_string().contains("timed out"));
+
+    // The fake only sends this after it got the cancel of the timed out interrupt.
+    let msg = tokio::time::timeout(Duration::from_secs(3), stream.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(matches!(msg.typed, SDKMessageTyped::Assistant(..)));
+}
+
+#[tokio::test]
+async fn test_shutdown() {
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .assistant_text("working on it")
+        .step(ScriptStep::RecvControl {
+            subtype: "interrupt".to_string(),
+            response: None,
+            error: None,
+        });
+    let (stream, tx) = start(script, Default::default()).await;
+    tx.send("hi".to_string()).unwrap();
+
+    tokio::time::timeout(Duration::from_secs(3), stream.shutdown())
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_shutdown_terminate() {
+    let script = FakeClaude::streaming().step(ScriptStep::SleepMs(30_000));
+    let options = ClaudeCodeOptions {
+        shutdown_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let (stream, _tx) = start(script, options).await;
+
+    tokio::time::timeout(Duration::from_secs(3), stream.shutdown())
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_abort() {
+    // The CLI never answers the interrupt, so it's killed after the grace period.
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .assistant_text("working")
+        .step(ScriptStep::SleepMs(30_000));
+    let abort = AbortController::new();
+    let options = ClaudeCodeOptions {
+        abort_controller: Some(abort.clone()),
+        abort_grace_period: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let (mut stream, tx) = start(script, options).await;
+    tx.send("hi".to_string()).unwrap();
+    // Abort once the CLI has the prompt, or the interrupt may reach it first.
+    stream.next().await.unwrap().unwrap();
+    abort.abort();
+
+    let last = tokio::time::timeout(Duration::from_secs(3), async {
+        let mut last = None;
+        while let Some(msg) = stream.next().await {
+            last = Some(msg);
+        }
+        last
+    })
+    .await
+    .unwrap();
+    assert!(
+        matches!(last, Some(Err(ClaudeStreamError::Aborted))),
+        "{last:?}"
+    );
+
+    // Aborting while the CLI hasn't answered initialize doesn't wait for the init timeout.
+    let script = FakeClaude::new().step(ScriptStep::SleepMs(30_000));
+    let abort = AbortController::new();
+    let options = ClaudeCodeOptions {
+        abort_controller: Some(abort.clone()),
+        init_timeout: Some(Duration::from_secs(30)),
+        ..Default::default()
+    };
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        abort.abort();
+    });
+    let err = tokio::time::timeout(Duration::from_secs(3), start_err(script, options))
+        .await
+        .unwrap();
+    assert!(matches!(err, InitError::Aborted), "{err}");
+}
+
+async fn start_err(script: FakeClaude, mut options: ClaudeCodeOptions) -> InitError {
+    let _script = script.apply(FAKE_CLAUDE, &mut options).unwrap();
+    let (_tx, rx) = unbounded_channel();
+    let Err(err) = query(PromptGen { receiver: rx }, options).await else {
+        panic!("expect initialize to fail");
+    };
+    err.downcast().unwrap()
+}
+
+#[tokio::test]
+async fn test_init_error() {
+    let script = FakeClaude::new().step(ScriptStep::SleepMs(30_000));
+    let options = ClaudeCodeOptions {
+        init_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let err = start_err(script, options).await;
+    assert!(matches!(err, InitError::Timeout { .. }), "{err}");
+
+    let script = FakeClaude::new()
+        .step(ScriptStep::Stderr("Invalid API key".to_string()))
+        .step(ScriptStep::Exit(1));
+    let err = start_err(script, Default::default()).await;
+    let InitError::ProcessExited {
+        code, stderr_tail, ..
+    } = err
+    else {
+        panic!("expect ProcessExited, got {err}");
+    };
+    assert_eq!(code, Some(1));
+    assert!(stderr_tail.contains("Invalid API key"));
+}
+
+#[tokio::test]
+async fn test_client() {
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .assistant_text("first answer")
+        .result("first")
+        .step(ScriptStep::RecvControl {
+            subtype: "set_model".to_string(),
+            response: None,
+            error: None,
+        })
+        .recv_user()
+        .assistant_text("second answer")
+        .result("second");
+    let mut options = ClaudeCodeOptions::default();
+    let _script = script.apply(FAKE_CLAUDE, &mut options).unwrap();
+    let mut client = ClaudeClient::connect(options).await.unwrap();
+
+    for (prompt, answer) in [("hi", "first answer"), ("again", "second answer")] {
+        if prompt == "again" {
+            client.set_model("sonnet").await.unwrap();
+        }
+        client.send(prompt).unwrap();
+        let messages: Vec<_> = client.receive_response().collect().await;
+        assert_eq!(messages.len(), 2);
+        let SDKMessageTyped::Assistant(assistant) = &messages[0].as_ref().unwrap().typed else {
+            panic!("expect assistant message, got {:?}", messages[0]);
+        };
+        assert_eq!(assistant.message.text(), answer);
+        assert!(matches!(
+            messages[1].as_ref().unwrap().typed,
+            SDKMessageTyped::Result(..)
+        ));
+    }
+
+    client.disconnect().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_client_unreadable_result() {
+    let mut bad_result = testing::success_result("first");
+    bad_result["usage"] = json!({ "input_tokens": "many" });
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .assistant_text("first answer")
+        .send(bad_result)
+        .recv_user()
+        .assistant_text("second answer")
+        .result("second");
+    let mut options = ClaudeCodeOptions::default();
+    let _script = script.apply(FAKE_CLAUDE, &mut options).unwrap();
+    let mut client = ClaudeClient::connect(options).await.unwrap();
+
+    client.send("hi").unwrap();
+    let messages: Vec<_> = tokio::time::timeout(
+        Duration::from_secs(3),
+        client.receive_response().collect::<Vec<_>>(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(messages.len(), 2);
+    let SDKMessageTyped::Unknown(result) = &messages[1].as_ref().unwrap().typed else {
+        panic!("expect the raw result, got {:?}", messages[1]);
+    };
+    assert_eq!(result["type"], "result");
+
+    // The next turn starts with its own messages.
+    client.send("again").unwrap();
+    let messages: Vec<_> = client.receive_response().collect().await;
+    let SDKMessageTyped::Assistant(assistant) = &messages[0].as_ref().unwrap().typed else {
+        panic!("expect assistant message, got {:?}", messages[0]);
+    };
+    assert_eq!(assistant.message.text(), "second answer");
+
+    client.disconnect().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_client_skips_bad_message() {
+    let mut bad_assistant = testing::assistant_text("bad");
+    bad_assistant.as_object_mut().unwrap().remove("session_id");
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .send(bad_assistant)
+        .assistant_text("first answer")
+        .result("first")
+        .recv_user()
+        .assistant_text("second answer")
+        .result("second");
+    let mut options = ClaudeCodeOptions::default();
+    let _script = script.apply(FAKE_CLAUDE, &mut options).unwrap();
+    let mut client = ClaudeClient::connect(options).await.unwrap();
+
+    client.send("hi").unwrap();
+    let messages: Vec<_> = tokio::time::timeout(
+        Duration::from_secs(3),
+        client.receive_response().collect::<Vec<_>>(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(messages.len(), 3);
+    assert!(matches!(
+        messages[0],
+        Err(ClaudeStreamError::CannotDeserialize(..))
+    ));
+    assert!(matches!(
+        messages[2].as_ref().unwrap().typed,
+        SDKMessageTyped::Result(..)
+    ));
+
+    // The next turn gets its own result, not the one left from the first turn.
+    client.send("again").unwrap();
+    let messages: Vec<_> = client.receive_response().collect().await;
+    let SDKMessageTyped::Assistant(assistant) = &messages[0].as_ref().unwrap().typed else {
+        panic!("expect assistant message, got {:?}", messages[0]);
+    };
+    assert_eq!(assistant.message.text(), "second answer");
+
+    client.disconnect().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_budget_exceeded() {
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .assistant_text("one")
+        .assistant_text("two")
+        .step(ScriptStep::RecvControl {
+            subtype: "interrupt".to_string(),
+            response: None,
+            error: None,
+        })
+        .result("interrupted");
+    let options = ClaudeCodeOptions {
+        max_total_tokens: Some(3),
+        ..Default::default()
+    };
+    let (mut stream, tx) = start(script, options).await;
+    tx.send("hi".to_string()).unwrap();
+
+    let mut exceeded = None;
+    let mut results = 0;
+    let collect = async {
+        while let Some(msg) = stream.next().await {
+            match msg {
+                Ok(msg) if matches!(msg.typed, SDKMessageTyped::Result(..)) => results += 1,
+                Ok(_) => {}
+                Err(ClaudeStreamError::BudgetExceeded(err)) => exceeded = Some(err),
+                Err(err) => panic!("unexpected error: {err}"),
+            }
+        }
+    };
+    // Stdin is closed, so the CLI exits after the interrupted turn.
+    tokio::time::timeout(Duration::from_secs(3), collect)
+        .await
+        .unwrap();
+    assert_eq!(
+        exceeded,
+        Some(BudgetExceeded::Tokens { spent: 4, limit: 3 })
+    );
+    assert_eq!(results, 1);
+}
+
+#[tokio::test]
+async fn test_budget_exceeded_mid_turn() {
+    // The fake model is priced like the most expensive one, $90 per million tokens here.
+    let script = FakeClaude::streaming()
+        .recv_user()
+        .assistant_text("one")
+        .assistant_text("two")
+        .step(ScriptStep::RecvControl {
+            subtype: "interrupt".to_string(),
+            response: None,
+            error: None,
+        })
+        .result("interrupted");
+    let options = ClaudeCodeOptions {
+        max_budget_usd: Some(0.0001),
+        ..Default::default()
+    };
+    let (mut stream, tx) = start(script, options).await;
+    tx.send("hi".to_string()).unwrap();
+
+    let mut exceeded = None;
+    let collect = async {
+        while let Some(msg) = stream.next().await {
+            match msg {
+                Ok(msg) if matches!(msg.typed, SDKMessageTyped::Result(..)) => {
+                    assert!(exceeded.is_some(), "the turn ended before the budget stop");
+                }
+                Ok(_) => {}
+                Err(ClaudeStreamError::BudgetExceeded(err)) => exceeded = Some(err),
+                Err(err) => panic!("unexpected error: {err}"),
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(3), collect)
+        .await
+        .unwrap();
+    assert!(matches!(
+        exceeded,
+        Some(BudgetExceeded::Cost { spent_usd, limit_usd: 0.0001 }) if spent_usd > 0.0001
+    ));
+}
+
+#[tokio::test]
+async fn test_fork_session() {
+    let mut options = ClaudeCodeOptions {
+        fork_session: Some(true),
+        ..Default::default()
+    };
+    let _script = FakeClaude::streaming()
+        .apply(FAKE_CLAUDE, &mut options)
+        .unwrap();
+    let (_tx, rx) = unbounded_channel::<String>();
+    let Err(err) = query(PromptGen { receiver: rx }, options).await else {
+        panic!("fork_session without resume should fail");
+    };
+    assert!(err.to_string().contains("requires resume"));
+
+    let expect_args =
+        |args: &[&str]| ScriptStep::ExpectArgs(args.iter().map(|arg| arg.to_string()).collect());
+    let script = FakeClaude::streaming()
+        .step(expect_args(&["--resume", "original-session"]))
+        .step(expect_args(&["--resume-session-at", "message-uuid"]))
+        .step(expect_args(&["--fork-session"]))
+        .recv_user()
+        .assistant_text("forked")
+        .result("done");
+    let options = ClaudeCodeOptions {
+        resume: Some("original-session".to_string()),
+        resume_session_at: Some("message-uuid".to_string()),
+        fork_session: Some(true),
+        ..Default::default()
+    };
+    let (mut stream, tx) = start(script, options).await;
+    assert_eq!(stream.session_id(), None);
+    tx.send("hi".to_string()).unwrap();
+
+    wait_result(&mut stream).await;
+    assert_eq!(stream.session_id(), Some(FAKE_SESSION_ID));
+}
+
+#[tokio::test]
+async fn test_control_request_tolerant() {
+    let script = FakeClaude::streaming()
+        .recv_user()
+        // A new field doesn't stop the request from being served.
+        .step(ScriptStep::ControlRequest {
+            request: json!({
+                "subtype": "mcp_message",
+                "server_name": "tools",
+                "message": { "jsonrpc": "2.0", "id": 1, "method": "tools/list" },
+                "agent_id": "main"
+            }),
+            expect: Some(json!({
+                "subtype": "success",
+                "response": { "mcp_response": { "id": 1, "result": { "tools": [] } } }
+            })),
+        })
+        // A new subtype gets an error, the session goes on.
+        .step(ScriptStep::ControlRequest {
+            request: json!({ "subtype": "new_feature", "value": 1 }),
+            expect: Some(json!({ "subtype": "error" })),
+        })
+        .assistant_text("hello")
+        .result("done");
+
+    let server = SdkMcpServer::new("tools", "1.0.0");
+    let options = ClaudeCodeOptions {
+        mcp_servers: Some([("tools".to_string(), server.into())].into()),
+        ..Default::default()
+    };
+    let (mut stream, tx) = start(script, options).await;
+    tx.send("hi".to_string()).unwrap();
+
+    let result = tokio::time::timeout(Duration::from_secs(3), wait_result(&mut stream))
+        .await
+        .unwrap();
+    assert!(matches!(result, SDKResultMessage::Success(..)));
+}
+
+#[tokio::test]
+async fn test_hook_callback_tolerant() {
+    let input = json!({
+        "session_id": FAKE_SESSION_ID,
+        "transcript_path": "/tmp/fake.jsonl",
+        "cwd": "/tmp",
+        "hook_event_name": "PreCompact",
+        "trigger": "scheduled"
+    });
+    let script = FakeClaude::streaming()
+        .recv_user()
+        // An unknown event value and a new envelope field still reach the hook.
+        .step(ScriptStep::ControlRequest {
+            request: json!({
+                "subtype": "hook_callback",
+                "callback_id": "hook_0",
+                "input": input,
+                "tool_use_id": null,
+                "agent_id": "main"
+            }),
+            expect: Some(json!({ "subtype": "success", "response": { "continue": true } })),
+        })
+        // A request that can't be parsed gets an error, the session goes on.
+        .step(ScriptStep::ControlRequest {
+            request: json!({ "subtype": "hook_callback", "callback_id": "hook_0" }),
+            expect: Some(json!({ "subtype": "error" })),
+        })
+        .assistant_text("hello")
+        .result("done");
+
+    let matcher = HookCallbackMatcher::new(None).hook_fn(|input, _| async move {
+        assert!(matches!(input.event, HookEventInput::Unknown(..)));
+        Ok(HookOutput {
+            r#continue: Some(true),
+            ..Default::default()
+        })
+    });
+    let options = ClaudeCodeOptions {
+        hooks: Some([(HookEvent::PreCompact, vec![matcher])].into()),
+        ..Default::default()
+    };
+    let (mut stream, tx) = start(script, options).await;
+    tx.send("hi".to_string()).unwrap();
+
+    let result = tokio::time::timeout(Duration::from_secs(3), wait_result(&mut stream))
+        .await
+        .unwrap();
+    assert!(matches!(result, SDKResultMessage::Success(..)));
+}
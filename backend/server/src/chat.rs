@@ -1,15 +1,20 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Display,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, OnceLock},
     time::Duration,
 };
 
-use anyhow::{Context, Result};
-use cc_sdk::types::{
-    APIUserMessage, ClaudeCodeOptions, PermissionMode, PermissionResult, SDKMessage,
-    anthropic::ContentBlockParam,
+use anyhow::{Context, Result, anyhow};
+use cc_sdk::{
+    channel::{QueueReceiver, QueueSender},
+    mcp::{self, McpServerConfig},
+    policy::PolicyCallback,
+    types::{
+        APIUserMessage, CanUseToolCallBack, ClaudeCodeOptions, PermissionMode, PermissionResult,
+        SDKMessage, SDKUserMessage, anthropic::ContentBlockParam,
+    },
 };
 use chrono::{DateTime, Utc};
 use derive_more::Display;
@@ -17,7 +22,7 @@ use serde::{Deserialize, Serialize};
 use tokio::{
     io::AsyncWriteExt,
     sync::{
-        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+        mpsc::{UnboundedSender, unbounded_channel},
         oneshot,
     },
 };
@@ -25,7 +30,7 @@ use tracing::{debug, info, warn};
 
 use crate::{
     BizResult, biz_ok,
-    claude::{CanUseTool, ClaudeCli, ClaudeCliMessage, PromptGen},
+    claude::{CanUseTool, ClaudeCli, ClaudeCliMessage, ClaudeReceiver, PromptGen},
     ensure_biz,
     message::{
         CanUseToolParams, ChatId, ClaudeSystemInfo, ClientMessage, ServerError, ServerMessage,
@@ -33,16 +38,44 @@ use crate::{
     },
     prompt_hub::PromptHub,
     resume,
-    setting::get_current_setting,
+    setting::{get_config_dir, get_current_setting},
 };
 
-static MAILBOX_SENDER: OnceLock<UnboundedSender<ChatManagerMessage>> = OnceLock::new();
+/// Messages from a Claude CLI waiting to be forwarded. Reading the CLI pauses when it's full.
+const CLI_OUTPUT_CAPACITY: usize = 256;
+
+/// How long to wait for a CLI to start. It's started outside of the manager loop, so a stuck
+/// one only holds up its own chat.
+const CLI_INIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Messages waiting for the manager. Senders wait when it's full, so a busy manager slows
+/// down reading the CLIs instead of buffering their output without limit.
+pub const MANAGER_MAILBOX_CAPACITY: usize = 1024;
+
+pub type ManagerMailbox = QueueSender<ChatManagerMessage>;
+
+static MAILBOX_SENDER: OnceLock<ManagerMailbox> = OnceLock::new();
 
-pub fn set_manager_mailbox(tx: UnboundedSender<ChatManagerMessage>) {
+pub fn set_manager_mailbox(tx: ManagerMailbox) {
     let _ = MAILBOX_SENDER.set(tx);
 }
 
-pub fn get_manager_mailbox() -> UnboundedSender<ChatManagerMessage> {
+/// Shuts down all Claude CLI sessions gracefully, so they stay resumable.
+pub async fn shutdown() {
+    let Some(mailbox) = MAILBOX_SENDER.get() else {
+        return;
+    };
+    let (responder, done) = oneshot::channel();
+    if mailbox
+        .send(ChatManagerMessage::Shutdown { responder })
+        .await
+        .is_ok()
+    {
+        let _ = done.await;
+    }
+}
+
+pub fn get_manager_mailbox() -> ManagerMailbox {
     MAILBOX_SENDER
         .get()
         .expect("You must invoke set_manager_mailbox first")
@@ -57,6 +90,10 @@ pub enum CacheMessage {
     SystemInfo(Arc<ClaudeSystemInfo>),
     CanUseTool(Arc<CanUseToolParams>),
     PermissionResp(Arc<PermissionResult>),
+    /// Replayed after its `CanUseTool`, so that the dialog is closed again.
+    PermissionWithdrawn {
+        request_id: String,
+    },
 }
 
 #[derive(Clone, Serialize)]
@@ -92,13 +129,22 @@ pub struct SessionInfo {
     pub last_user_input: Option<Arc<String>>,
 }
 
+/// Adjusts the options of every Claude CLI the manager starts, e.g. to run a fake CLI in tests.
+pub type CliOptionsHook = Arc<dyn Fn(&mut ClaudeCodeOptions) -> Result<()> + Send + Sync>;
+
 pub struct ChatManager {
     prompt_hub: Arc<PromptHub>,
-    mailbox: UnboundedReceiver<ChatManagerMessage>,
+    cli_options_hook: Option<CliOptionsHook>,
+    mailbox: QueueReceiver<ChatManagerMessage>,
     connections: HashMap<ConnId, WsSender>,
     cli_sessions: HashMap<CliId, CliSession>,
     chat_to_conn: HashMap<ChatId, ConnId>,
     chat_to_cli: HashMap<ChatId, CliId>,
+    /// Chats whose CLI is still starting, including those waiting in `starting_sessions`.
+    starting_chats: HashSet<ChatId>,
+    /// Sessions being resumed from logs, with the starts that wait to attach to them.
+    starting_sessions: HashMap<String, Vec<(StartChatOptions, StartChatResponder)>>,
+    shutting_down: bool,
 }
 
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Display)]
@@ -142,9 +188,34 @@ pub enum ChatManagerMessage {
     },
     StartChat {
         options: StartChatOptions,
-        responder: oneshot::Sender<BizResult<Vec<MessageRecord>, StartChatError>>,
+        responder: StartChatResponder,
     },
+    /// A CLI started for `StartChat` has finished the `initialize` handshake, or failed to.
+    CliStarted(Box<CliStarted>),
     CleanSessions,
+    /// Stop all CLIs gracefully before the server exits.
+    Shutdown {
+        responder: oneshot::Sender<()>,
+    },
+}
+
+type StartChatResponder = oneshot::Sender<BizResult<Vec<MessageRecord>, StartChatError>>;
+
+/// The outcome of a CLI start, handed back to the manager to register its session.
+pub struct CliStarted {
+    chat_id: ChatId,
+    resume: Option<String>,
+    responder: StartChatResponder,
+    result: BizResult<StartedCli, StartChatError>,
+}
+
+struct StartedCli {
+    work_dir: PathBuf,
+    history: Vec<MessageRecord>,
+    mail_addr: ClaudeCliMailbox,
+    mailbox: ClaudeReceiver,
+    prompt_tx: UnboundedSender<SDKUserMessage>,
+    stream: cc_sdk::cli::QueryStream,
 }
 
 #[derive(Hash, Eq, PartialEq, Clone, Copy, Debug, Display)]
@@ -158,14 +229,33 @@ impl CliId {
 }
 
 impl ChatManager {
-    pub fn new(mailbox: UnboundedReceiver<ChatManagerMessage>, prompt_hub: Arc<PromptHub>) -> Self {
+    pub fn new(mailbox: QueueReceiver<ChatManagerMessage>, prompt_hub: Arc<PromptHub>) -> Self {
         Self {
             prompt_hub,
+            cli_options_hook: None,
             mailbox,
             connections: Default::default(),
             cli_sessions: Default::default(),
             chat_to_conn: Default::default(),
             chat_to_cli: Default::default(),
+            starting_chats: Default::default(),
+            starting_sessions: Default::default(),
+            shutting_down: false,
+        }
+    }
+
+    pub fn with_cli_options(
+        mut self,
+        hook: impl Fn(&mut ClaudeCodeOptions) -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.cli_options_hook = Some(Arc::new(hook));
+        self
+    }
+
+    fn apply_cli_options_hook(&self, options: &mut ClaudeCodeOptions) -> Result<()> {
+        match &self.cli_options_hook {
+            Some(hook) => hook(options),
+            None => Ok(()),
         }
     }
 
@@ -176,7 +266,10 @@ impl ChatManager {
             let mailbox = get_manager_mailbox();
             loop {
                 cleanup_interval.tick().await;
-                mailbox.send(ChatManagerMessage::CleanSessions).unwrap();
+                mailbox
+                    .send(ChatManagerMessage::CleanSessions)
+                    .await
+                    .unwrap();
             }
         });
 
@@ -210,18 +303,22 @@ impl ChatManager {
                 self.handle_get_active_sessions(work_dir, responder);
             }
             ChatManagerMessage::StartChat { options, responder } => {
-                let result = self.handle_start_chat(options).await;
-                let _ = responder.send(result);
+                self.handle_start_chat(options, responder);
+            }
+            ChatManagerMessage::CliStarted(started) => {
+                self.handle_cli_started(started);
             }
             ChatManagerMessage::CleanSessions => {
                 self.cleanup_inactive_sessions();
             }
+            ChatManagerMessage::Shutdown { responder } => {
+                self.handle_shutdown(responder);
+            }
             ChatManagerMessage::GetClaudeInfo {
                 work_dir,
                 responder,
             } => {
-                let result = self.handle_get_claude_info(work_dir).await;
-                let _ = responder.send(result);
+                self.handle_get_claude_info(work_dir, responder);
             }
         }
 
@@ -262,16 +359,30 @@ impl ChatManager {
         }
     }
 
-    async fn handle_get_claude_info(&mut self, work_dir: PathBuf) -> Result<ClaudeSystemInfo> {
-        let options = ClaudeCodeOptions {
+    /// Starts a CLI to ask for its commands and models, without blocking the manager.
+    fn handle_get_claude_info(
+        &self,
+        work_dir: PathBuf,
+        responder: oneshot::Sender<Result<ClaudeSystemInfo>>,
+    ) {
+        let mut options = ClaudeCodeOptions {
             cwd: Some(work_dir.clone()),
+            init_timeout: Some(CLI_INIT_TIMEOUT),
             ..Default::default()
         };
-        let (_prompt, stream) = build_stream(None, options).await?.unwrap();
-        let commands = stream.supported_commands()?;
-        let models = stream.supported_models()?;
-
-        Ok(ClaudeSystemInfo { commands, models })
+        if let Err(err) = self.apply_cli_options_hook(&mut options) {
+            let _ = responder.send(Err(err));
+            return;
+        }
+        tokio::spawn(async move {
+            let result = async {
+                let (_prompt, stream) = build_stream(None, options).await?.unwrap();
+                let commands = stream.supported_commands()?;
+                let models = stream.supported_models()?;
+                Ok(ClaudeSystemInfo { commands, models })
+            };
+            let _ = responder.send(result.await);
+        });
     }
 
     fn record_user_permission_resp(
@@ -340,7 +451,8 @@ impl ChatManager {
     fn stop_cli(&mut self, cli_id: CliId) {
         if let Some(session) = self.cli_sessions.remove(&cli_id) {
             info!(session_id = ?session.session_id, %cli_id, chat = ?session.chat, "Stop cli session");
-            let _ = session.mail_addr.send(ClaudeCliMessage::Stop);
+            let (done, _) = oneshot::channel();
+            let _ = session.mail_addr.send(ClaudeCliMessage::Stop(done));
             if let Some(chat) = session.chat {
                 self.remove_chat(&chat.id);
             }
@@ -394,6 +506,9 @@ impl ChatManager {
                             warn!(%result, "Impossible to send permission response");
                             continue;
                         }
+                        CacheMessage::PermissionWithdrawn { request_id } => {
+                            ServerMessageData::PermissionWithdrawn { request_id }
+                        }
                     };
                     if let Err(err) = ws.send_msg(ServerMessage {
                         chat_id: chat_id.clone(),
@@ -448,12 +563,17 @@ impl ChatManager {
         self.connections.get(&conn_id).cloned()
     }
 
-    async fn build_claude_cli(
+    /// Starts the CLI in a task of its own and hands it back with `CliStarted`, so the
+    /// manager never waits for the `initialize` handshake.
+    /// With `resume_from_logs`, the history of the resumed session is loaded from its logs.
+    fn spawn_claude_cli(
         &mut self,
         options: StartChatOptions,
-    ) -> BizResult<(), StartChatError> {
+        resume_from_logs: bool,
+        responder: StartChatResponder,
+    ) {
         let (claude_tx, claude_rx) = unbounded_channel();
-        let can_use_tool = CanUseTool::new(claude_tx.clone());
+        let mut can_use_tool = CanUseTool::new(claude_tx.clone()).boxed();
 
         let StartChatOptions {
             work_dir,
@@ -462,40 +582,145 @@ impl ChatManager {
             chat_id,
             config_name,
         } = options;
+        self.starting_chats.insert(chat_id.clone());
 
-        let cli_options = ClaudeCodeOptions {
-            can_use_tool: Some(Box::new(can_use_tool)),
+        let setting = get_current_setting();
+        if let Some(policy) = setting.permission_policy() {
+            can_use_tool = PolicyCallback::new(policy.clone(), &work_dir)
+                .with_fallback(can_use_tool)
+                .boxed();
+        }
+
+        let mut cli_options = ClaudeCodeOptions {
+            can_use_tool: Some(can_use_tool),
             resume: resume.clone(),
             cwd: Some(work_dir.clone()),
             permission_mode: mode,
+            output_capacity: Some(CLI_OUTPUT_CAPACITY),
+            init_timeout: Some(CLI_INIT_TIMEOUT),
+            max_budget_usd: setting.max_budget_usd(),
+            max_total_tokens: setting.max_total_tokens(),
             ..Default::default()
         };
-        let (tx, stream) = ensure_biz!(build_stream(config_name, cli_options).await?);
+        let hook_result = self.apply_cli_options_hook(&mut cli_options);
+
+        tokio::spawn(async move {
+            let result = async {
+                hook_result?;
+                let history = match (&resume, resume_from_logs) {
+                    (Some(session_id), true) => load_history(&work_dir, session_id).await?,
+                    _ => vec![],
+                };
+                let (prompt_tx, stream) =
+                    ensure_biz!(build_stream(config_name, cli_options).await?);
+                biz_ok!(StartedCli {
+                    work_dir,
+                    history,
+                    mail_addr: claude_tx,
+                    mailbox: claude_rx,
+                    prompt_tx,
+                    stream,
+                })
+            }
+            .await;
+
+            let started = CliStarted {
+                chat_id,
+                resume,
+                responder,
+                result,
+            };
+            let _ = get_manager_mailbox()
+                .send(ChatManagerMessage::CliStarted(Box::new(started)))
+                .await;
+        });
+    }
+
+    fn handle_cli_started(&mut self, started: Box<CliStarted>) {
+        let CliStarted {
+            chat_id,
+            resume,
+            responder,
+            result,
+        } = *started;
+        self.starting_chats.remove(&chat_id);
+        let waiters = resume
+            .as_ref()
+            .and_then(|session_id| self.starting_sessions.remove(session_id))
+            .unwrap_or_default();
+
+        match result {
+            Ok(Ok(cli)) if self.shutting_down => {
+                info!(chat_id, "Stop a cli started during shutdown");
+                tokio::spawn(cli.stream.shutdown());
+                let _ = responder.send(Err(anyhow!("Server is shutting down")));
+            }
+            Ok(Ok(cli)) if responder.is_closed() && waiters.is_empty() => {
+                info!(chat_id, "Stop a cli nobody waits for");
+                tokio::spawn(cli.stream.shutdown());
+            }
+            Ok(Ok(cli)) => self.register_cli(chat_id, resume, cli, responder),
+            Ok(Err(err)) => {
+                let _ = responder.send(Ok(Err(err)));
+            }
+            Err(err) => {
+                let _ = responder.send(Err(err));
+            }
+        }
 
+        // Attach to the session just started, or retry if it failed
+        for (options, responder) in waiters {
+            self.starting_chats.remove(&options.chat_id);
+            self.handle_start_chat(options, responder);
+        }
+    }
+
+    fn register_cli(
+        &mut self,
+        chat_id: ChatId,
+        resume: Option<String>,
+        cli: StartedCli,
+        responder: StartChatResponder,
+    ) {
+        let StartedCli {
+            work_dir,
+            history,
+            mail_addr,
+            mailbox,
+            prompt_tx,
+            stream,
+        } = cli;
         let cli_id = CliId::next();
 
         let manager_mailbox = get_manager_mailbox();
-        let claude = ClaudeCli::new(cli_id.clone(), claude_rx, manager_mailbox, tx);
+        let claude = ClaudeCli::new(cli_id, mailbox, manager_mailbox, prompt_tx);
         claude.spawn(stream);
 
+        // The chat moves to the new CLI. The old one stays resumable until it's cleaned up.
+        if let Some(old_cli_id) = self.chat_to_cli.get(&chat_id)
+            && let Some(session) = self.cli_sessions.get_mut(old_cli_id)
+        {
+            session.chat = None;
+        }
+
         // Create session record
         let session = CliSession {
             session_id: resume,
-            work_dir: work_dir.clone(),
+            work_dir,
             created_at: Utc::now(),
             last_activity: Utc::now(),
-            messages: Vec::new(),
+            messages: history.clone(),
             chat: Some(SessionChat {
                 id: chat_id.clone(),
                 lag_count: 0,
             }),
-            mail_addr: claude_tx,
+            mail_addr,
         };
 
         self.cli_sessions.insert(cli_id, session);
         self.chat_to_cli.insert(chat_id, cli_id);
 
-        biz_ok!(())
+        let _ = responder.send(Ok(Ok(history)));
     }
 
     async fn handle_cli_message(&mut self, cli_id: CliId, data: ServerMessageData) {
@@ -551,6 +776,12 @@ impl ChatManager {
             ServerMessageData::CanUseTool(params) => CacheMessage::CanUseTool(Arc::clone(params)),
             ServerMessageData::ServerError(_) => return,
             ServerMessageData::ChatRemoved => return,
+            ServerMessageData::PermissionWithdrawn { request_id } => {
+                CacheMessage::PermissionWithdrawn {
+                    request_id: request_id.clone(),
+                }
+            }
+            ServerMessageData::ControlResult(_) => return,
         };
 
         session.messages.push(MessageRecord {
@@ -598,28 +829,34 @@ impl ChatManager {
         })
     }
 
-    async fn handle_start_chat(
-        &mut self,
-        options: StartChatOptions,
-    ) -> BizResult<Vec<MessageRecord>, StartChatError> {
+    fn handle_start_chat(&mut self, options: StartChatOptions, responder: StartChatResponder) {
         let chat_id = options.chat_id.clone();
+        if self.shutting_down {
+            let _ = responder.send(Err(anyhow!("Server is shutting down")));
+            return;
+        }
+        if self.starting_chats.contains(&chat_id) {
+            debug!(chat_id, "Chat is already starting");
+            let _ = responder.send(Ok(Err(StartChatError::ChatStarting)));
+            return;
+        }
 
         match &options.resume {
             None => {
                 debug!(chat_id, "New chat");
                 // Case 1: New session
-                ensure_biz!(self.build_claude_cli(options).await?);
-                Ok(Ok(vec![]))
+                self.spawn_claude_cli(options, false, responder);
             }
-            Some(session_id) => self.resume_session(session_id.clone(), options).await,
+            Some(session_id) => self.resume_session(session_id.clone(), options, responder),
         }
     }
 
-    async fn resume_session(
+    fn resume_session(
         &mut self,
         session_id: String,
         options: StartChatOptions,
-    ) -> BizResult<Vec<MessageRecord>, StartChatError> {
+        responder: StartChatResponder,
+    ) {
         let session_id = session_id.clone();
         let chat_id = options.chat_id.clone();
         if let Some(cli_id) = self.cli_id_by_session_id(&session_id) {
@@ -647,26 +884,43 @@ impl ChatManager {
             }
 
             let messages = session.messages.clone();
-            Ok(Ok(messages))
+            let _ = responder.send(Ok(Ok(messages)));
         } else {
-            // Case 3: Resume inactive session from file
-            debug!(chat_id, session_id, "Resume closed session");
-
-            let logs = resume::load_session(&options.work_dir, &session_id).await?;
-            let mut messages = vec![];
-            for log in logs.logs {
-                if let Some(msg) = resume::log_to_message_record(log)? {
-                    messages.push(msg);
-                }
+            if let Some(waiters) = self.starting_sessions.get_mut(&session_id) {
+                // Case 3: Resume a session that is still starting, once it has started
+                debug!(chat_id, session_id, "Wait for starting session");
+                self.starting_chats.insert(chat_id);
+                waiters.push((options, responder));
+                return;
             }
 
-            ensure_biz!(self.build_claude_cli(options).await?);
-            let cli_id = self.chat_to_cli.get(&chat_id).unwrap();
-            let session = self.cli_sessions.get_mut(cli_id).unwrap();
-            session.messages = messages.clone();
+            // Case 4: Resume inactive session from file
+            debug!(chat_id, session_id, "Resume closed session");
+            self.starting_sessions.insert(session_id, vec![]);
+            self.spawn_claude_cli(options, true, responder);
+        }
+    }
 
-            Ok(Ok(messages))
+    /// Stops every CLI without removing the chats from clients, so they can resume after restart.
+    fn handle_shutdown(&mut self, responder: oneshot::Sender<()>) {
+        // CLIs still starting are stopped once they have started
+        self.shutting_down = true;
+        let mut stopped = vec![];
+        for (cli_id, session) in self.cli_sessions.drain() {
+            info!(session_id = ?session.session_id, %cli_id, "Shut down cli session");
+            let (done, rx) = oneshot::channel();
+            if session.mail_addr.send(ClaudeCliMessage::Stop(done)).is_ok() {
+                stopped.push(rx);
+            }
         }
+        self.chat_to_cli.clear();
+
+        tokio::spawn(async move {
+            for rx in stopped {
+                let _ = rx.await;
+            }
+            let _ = responder.send(());
+        });
     }
 
     fn cleanup_inactive_sessions(&mut self) {
@@ -687,9 +941,21 @@ impl ChatManager {
     }
 }
 
+async fn load_history(work_dir: &Path, session_id: &str) -> Result<Vec<MessageRecord>> {
+    let logs = resume::load_session(work_dir, session_id).await?;
+    let mut messages = vec![];
+    for log in logs.logs {
+        if let Some(msg) = resume::log_to_message_record(log)? {
+            messages.push(msg);
+        }
+    }
+
+    Ok(messages)
+}
+
 async fn build_stream(
     config_name: Option<String>,
-    cli_options: ClaudeCodeOptions,
+    mut cli_options: ClaudeCodeOptions,
 ) -> BizResult<
     (
         UnboundedSender<cc_sdk::types::SDKUserMessage>,
@@ -699,21 +965,34 @@ async fn build_stream(
 > {
     let (tx, rx) = unbounded_channel();
     let prompt_gen = PromptGen::new(rx);
-    let stream = if let Some(name) = config_name {
-        let cli_cmd = cc_sdk::query(prompt_gen, cli_options);
-        let result = replace_claude_config(&name, cli_cmd).await?;
-        ensure_biz!(result)?
-    } else {
-        cc_sdk::query(prompt_gen, cli_options).await?
-    };
+    if let Some(name) = config_name {
+        let settings = ensure_biz!(write_profile_settings(&name).await?);
+        cli_options.settings = Some(settings.to_string_lossy().into_owned());
+        cli_options.mcp_servers = profile_mcp_servers(&name)?;
+    }
+    let stream = cc_sdk::query(prompt_gen, cli_options).await?;
 
     Ok(Ok((tx, stream)))
 }
 
-async fn replace_claude_config<F: Future>(
-    name: &str,
-    cli_cmd: F,
-) -> BizResult<F::Output, StartChatError> {
+fn profile_mcp_servers(name: &str) -> Result<Option<HashMap<String, McpServerConfig>>> {
+    let setting = get_current_setting();
+    let Some(servers) = setting
+        .get_claude_setting(name)
+        .and_then(|config| config.mcp_servers())
+    else {
+        return Ok(None);
+    };
+
+    let servers = mcp::parse_mcp_servers(servers.clone())
+        .with_context(|| format!("Invalid mcp_servers in claude config: {name}"))?;
+
+    Ok(Some(servers))
+}
+
+/// Writes the profile's settings to a file of its own, to be passed to the CLI with
+/// `--settings`. A file keeps secrets like API keys out of the process list.
+async fn write_profile_settings(name: &str) -> BizResult<PathBuf, StartChatError> {
     let setting = get_current_setting();
 
     debug!(name, "Using custom claude config");
@@ -722,22 +1001,58 @@ async fn replace_claude_config<F: Future>(
         .ok_or_else(|| StartChatError::ConfigNotFound(name.to_string()));
     let config = ensure_biz!(config);
 
-    let home_dir = dirs::home_dir().context("Failed to get home directory")?;
-    let claude_config_path = home_dir.join(".claude").join("settings.json");
-    let backup_path = home_dir.join(".claude").join("settings.json.niu-code.bak");
-    debug!(?claude_config_path, ?backup_path, "backup claude config");
-
-    tokio::fs::rename(&claude_config_path, &backup_path).await?;
-
-    let json = serde_json::to_string_pretty(&config.setting).unwrap();
-    let mut file = tokio::fs::File::create(&claude_config_path).await?;
-    file.write_all(json.as_bytes()).await?;
-
-    let result = cli_cmd.await;
+    let dir = get_config_dir().join("profiles");
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let file_stem = profile_file_stem(name);
+    let path = dir.join(format!("{file_stem}.settings.json"));
+    // A CLI started earlier may still be reading the old file, so replace it atomically.
+    // Chats with the same profile may start at once, so each call writes its own tmp file.
+    static TMP_SEQ: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+    let seq = TMP_SEQ.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    let tmp_path = dir.join(format!(
+        ".{file_stem}.settings.json.{}-{seq}.tmp",
+        std::process::id()
+    ));
+
+    let json = serde_json::to_string_pretty(config.setting()).unwrap();
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let write = async {
+        let mut file = options.open(&tmp_path).await?;
+        file.write_all(json.as_bytes()).await?;
+        file.flush().await?;
+        drop(file);
+        tokio::fs::rename(&tmp_path, &path).await
+    };
+    if let Err(err) = write.await {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return Err(err.into());
+    }
 
-    tokio::fs::rename(&backup_path, &claude_config_path).await?;
+    Ok(Ok(path))
+}
 
-    Ok(Ok(result))
+/// The profile name made path safe, plus a hash of the raw name so that names sanitized to
+/// the same string (e.g. `a b` and `a_b`) don't share a settings file.
+fn profile_file_stem(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    // FNV-1a, which unlike `DefaultHasher` is stable across builds
+    let hash = name.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{sanitized}-{:08x}", hash as u32)
 }
 
 pub trait WsWriter: Send + 'static + Sync {
@@ -773,7 +1088,7 @@ impl CliSession {
 }
 
 pub struct ChatManagerHandle {
-    mailbox: UnboundedSender<ChatManagerMessage>,
+    mailbox: ManagerMailbox,
 }
 
 impl ChatManagerHandle {
@@ -790,6 +1105,7 @@ impl ChatManagerHandle {
                 work_dir,
                 responder,
             })
+            .await
             .unwrap();
         receiver.await.unwrap()
     }
@@ -801,6 +1117,7 @@ impl ChatManagerHandle {
         let (responder, receiver) = oneshot::channel();
         self.mailbox
             .send(ChatManagerMessage::StartChat { options, responder })
+            .await
             .unwrap();
         debug!("wait for start chat");
         receiver.await.unwrap()
@@ -808,10 +1125,12 @@ impl ChatManagerHandle {
 
     pub async fn get_claude_info(&self, work_dir: PathBuf) -> Result<ClaudeSystemInfo> {
         let (responder, receiver) = oneshot::channel();
-        self.mailbox.send(ChatManagerMessage::GetClaudeInfo {
-            work_dir,
-            responder,
-        })?;
+        self.mailbox
+            .send(ChatManagerMessage::GetClaudeInfo {
+                work_dir,
+                responder,
+            })
+            .await?;
         receiver.await.unwrap()
     }
 }
@@ -831,6 +1150,8 @@ pub struct StartChatOptions {
 pub enum StartChatError {
     ChatNotRegistered,
     ConfigNotFound(String),
+    /// The chat is already starting a CLI.
+    ChatStarting,
 }
 
 impl ChatManagerMessage {
@@ -843,7 +1164,180 @@ impl ChatManagerMessage {
             ChatManagerMessage::GetActiveSessions { .. } => "GetActiveSessions",
             ChatManagerMessage::GetClaudeInfo { .. } => "GetClaudeInfo",
             ChatManagerMessage::StartChat { .. } => "StartChat",
+            ChatManagerMessage::CliStarted(..) => "CliStarted",
             ChatManagerMessage::CleanSessions => "CleanSessions",
+            ChatManagerMessage::Shutdown { .. } => "Shutdown",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cc_sdk::{
+        testing::{FakeClaude, ScriptStep, fake_claude_bin},
+        types::{APIUserMessageRole, SDKMessageTyped},
+    };
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    use super::*;
+    use crate::message::ClientMessageData;
+
+    struct TestWriter(UnboundedSender<ServerMessage>);
+
+    impl WsWriter for TestWriter {
+        fn send_msg(&self, msg: ServerMessage) -> Result<()> {
+            Ok(self.0.send(msg)?)
+        }
+    }
+
+    async fn wait_result(rx: &mut UnboundedReceiver<ServerMessage>) -> Vec<Arc<SDKMessage>> {
+        let mut messages = vec![];
+        while let Some(msg) = rx.recv().await {
+            match msg.data {
+                ServerMessageData::Claude(msg) => {
+                    let done = matches!(msg.typed, SDKMessageTyped::Result(..));
+                    messages.push(msg);
+                    if done {
+                        return messages;
+                    }
+                }
+                ServerMessageData::ServerError(err) => panic!("server error: {}", err.error),
+                _ => {}
+            }
         }
+        panic!("connection closed without result")
+    }
+
+    #[test]
+    fn test_profile_file_stem() {
+        assert_ne!(profile_file_stem("a b"), profile_file_stem("a_b"));
+        assert_eq!(profile_file_stem("a b"), profile_file_stem("a b"));
+        assert!(profile_file_stem("../x").starts_with("___x-"));
+    }
+
+    #[tokio::test]
+    async fn test_chat_with_fake_cli() -> Result<()> {
+        let work_dir = std::env::temp_dir().join(format!("niu-code-chat-{}", std::process::id()));
+        std::fs::create_dir_all(&work_dir)?;
+        let prompt_hub = Arc::new(PromptHub::with_storage_path(
+            work_dir.join("prompts.jsonl"),
+        )?);
+
+        let bin = fake_claude_bin()?;
+        let script = FakeClaude::streaming()
+            .recv_user()
+            .assistant_text("hello")
+            .result("done")
+            .write_script()?;
+        // Never answers `initialize`.
+        let stuck_dir = work_dir.join("stuck");
+        std::fs::create_dir_all(&stuck_dir)?;
+        let stuck_script = FakeClaude::new()
+            .step(ScriptStep::SleepMs(60_000))
+            .write_script()?;
+
+        let (tx, rx) = cc_sdk::channel::channel(Some(MANAGER_MAILBOX_CAPACITY));
+        set_manager_mailbox(tx);
+        let stuck = stuck_dir.clone();
+        let manager = ChatManager::new(rx, prompt_hub).with_cli_options(move |options| {
+            if options.cwd.as_ref() == Some(&stuck) {
+                stuck_script.apply(&bin, options);
+            } else {
+                script.apply(&bin, options);
+            }
+            Ok(())
+        });
+        tokio::spawn(manager.run());
+
+        let mailbox = get_manager_mailbox();
+        // A CLI stuck in its startup doesn't hold up other chats.
+        let (responder, mut stuck_start) = oneshot::channel();
+        mailbox
+            .send(ChatManagerMessage::StartChat {
+                options: StartChatOptions {
+                    chat_id: "stuck".to_string(),
+                    work_dir: stuck_dir,
+                    mode: None,
+                    config_name: None,
+                    resume: None,
+                },
+                responder,
+            })
+            .await?;
+
+        // A chat that is still starting can't start another CLI.
+        let duplicate = ChatManagerHandle::new()
+            .start_chat(StartChatOptions {
+                chat_id: "stuck".to_string(),
+                work_dir: work_dir.clone(),
+                mode: None,
+                config_name: None,
+                resume: None,
+            })
+            .await?;
+        assert!(matches!(duplicate, Err(StartChatError::ChatStarting)));
+
+        let conn_id = ConnId::generate();
+        let (ws_tx, mut ws_rx) = unbounded_channel();
+        mailbox
+            .send(ChatManagerMessage::NewConnect {
+                conn_id,
+                ws_writer: Arc::new(TestWriter(ws_tx)),
+            })
+            .await?;
+        let chat_id = "chat".to_string();
+        mailbox
+            .send(ChatManagerMessage::ClientMessage {
+                conn_id,
+                msg: ClientMessage {
+                    chat_id: chat_id.clone(),
+                    data: ClientMessageData::RegisterChat,
+                },
+            })
+            .await?;
+
+        let handle = ChatManagerHandle::new();
+        let start = handle.start_chat(StartChatOptions {
+            chat_id: chat_id.clone(),
+            work_dir: work_dir.clone(),
+            mode: None,
+            config_name: None,
+            resume: None,
+        });
+        let history = tokio::time::timeout(Duration::from_secs(10), start)
+            .await
+            .context("start chat is blocked")??
+            .unwrap();
+        assert!(history.is_empty());
+
+        mailbox
+            .send(ChatManagerMessage::ClientMessage {
+                conn_id,
+                msg: ClientMessage {
+                    chat_id,
+                    data: ClientMessageData::UserInput(APIUserMessage {
+                        content: Arc::new("hi".to_string().into()),
+                        role: APIUserMessageRole::User,
+                    }),
+                },
+            })
+            .await?;
+
+        let messages = tokio::time::timeout(Duration::from_secs(10), wait_result(&mut ws_rx))
+            .await
+            .context("no result from the fake cli")?;
+        assert!(matches!(
+            messages.first().map(|msg| &msg.typed),
+            Some(SDKMessageTyped::Assistant(..))
+        ));
+
+        let sessions = ChatManagerHandle::new()
+            .active_session_list(work_dir.clone())
+            .await;
+        assert_eq!(sessions.len(), 1);
+        assert!(stuck_start.try_recv().is_err());
+
+        let _ = std::fs::remove_dir_all(&work_dir);
+        Ok(())
     }
 }
@@ -1,9 +1,16 @@
-use std::{sync::Arc, task::ready};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+    task::ready,
+};
 
 use anyhow::{Context, Result};
 use cc_sdk::{
     StreamExt as _,
-    cli::{ClaudeStreamError, PromptGenerator, QueryStream},
+    cli::{
+        ClaudeStreamError, ControlEvent, ControlRequestKind, PromptGenerator, QueryStream,
+        control_request_id,
+    },
     types::{
         APIUserMessage, CanUseToolCallBack, PermissionMode, PermissionResult, SDKMessage,
         SDKUserMessage,
@@ -12,15 +19,15 @@ use cc_sdk::{
 use tokio::{
     select,
     sync::{
-        mpsc::{UnboundedReceiver, UnboundedSender},
+        mpsc::{UnboundedReceiver, UnboundedSender, error::TrySendError},
         oneshot,
     },
 };
 use tracing::{debug, warn};
 
 use crate::{
-    chat::{ChatManagerMessage, CliId},
-    message::{CanUseToolParams, ClaudeSystemInfo, ServerError, ServerMessageData},
+    chat::{ChatManagerMessage, CliId, ManagerMailbox},
+    message::{CanUseToolParams, ClaudeSystemInfo, ControlResult, ServerError, ServerMessageData},
 };
 
 pub type Responder<T> = oneshot::Sender<T>;
@@ -31,8 +38,9 @@ pub enum ClaudeCliMessage {
     PermissionResp(Arc<PermissionResult>),
     SetMode(PermissionMode),
     GetInfo,
-    CanUseTool(CanUseToolParams, CanUseToolReponder),
-    Stop,
+    CanUseTool(Arc<CanUseToolParams>, CanUseToolReponder),
+    /// Shut down the CLI gracefully, then report on the sender.
+    Stop(oneshot::Sender<()>),
     Interrupt,
 }
 
@@ -42,24 +50,73 @@ pub struct ClaudeCli {
     cli_id: CliId,
     mailbox: ClaudeReceiver,
 
-    manager_mailbox: UnboundedSender<ChatManagerMessage>,
+    manager_mailbox: ManagerMailbox,
+    /// Messages waiting for room in the manager's mailbox. Reading the CLI pauses meanwhile,
+    /// but `Interrupt` and `Stop` are still handled.
+    pending: VecDeque<ChatManagerMessage>,
     prompt_box: UnboundedSender<SDKUserMessage>,
-    can_use_tool_responder: Option<CanUseToolReponder>,
+    permission: PendingPermission,
+}
+
+/// The `can_use_tool` request waiting for the user, matched to cancels by request ID.
+///
+/// The cancel event and the request come through different channels, so the cancel may
+/// arrive first. It's remembered until the request shows up.
+#[derive(Default)]
+struct PendingPermission {
+    pending: Option<(String, CanUseToolReponder)>,
+    cancelled: HashSet<String>,
+}
+
+impl PendingPermission {
+    /// Returns false if the request was already cancelled and shouldn't be shown.
+    fn on_request(&mut self, request_id: &str, responder: CanUseToolReponder) -> bool {
+        // Callbacks run one at a time, so older cancels will never see their request.
+        let cancelled = self.cancelled.remove(request_id);
+        self.cancelled.clear();
+        if cancelled {
+            return false;
+        }
+        if self.pending.is_some() {
+            warn!("There's already a can_use_tool_responder!");
+        }
+        self.pending = Some((request_id.to_owned(), responder));
+        true
+    }
+
+    fn take_responder(&mut self) -> Option<CanUseToolReponder> {
+        self.pending.take().map(|(_, responder)| responder)
+    }
+
+    /// Returns true if the request being shown was withdrawn.
+    fn on_cancelled(&mut self, request_id: &str) -> bool {
+        match &self.pending {
+            Some((id, _)) if id == request_id => {
+                self.pending = None;
+                true
+            }
+            _ => {
+                self.cancelled.insert(request_id.to_owned());
+                false
+            }
+        }
+    }
 }
 
 impl ClaudeCli {
     pub fn new(
         cli_id: CliId,
         mailbox: ClaudeReceiver,
-        manager_mailbox: UnboundedSender<ChatManagerMessage>,
+        manager_mailbox: ManagerMailbox,
         prompt_box: UnboundedSender<SDKUserMessage>,
     ) -> Self {
         Self {
             cli_id,
             mailbox,
             manager_mailbox,
+            pending: VecDeque::new(),
             prompt_box,
-            can_use_tool_responder: None,
+            permission: PendingPermission::default(),
         }
     }
 
@@ -73,14 +130,26 @@ impl ClaudeCli {
 
     async fn run(mut self, mut stream: QueryStream) -> anyhow::Result<()> {
         debug!("ClaudeCli serving");
+        let mut control_events = stream
+            .take_control_events()
+            .context("Control events already taken")?;
         loop {
             select! {
-                Some(msg) = stream.next() => {
+                sent = send_pending(&self.manager_mailbox, &mut self.pending), if !self.pending.is_empty() => {
+                    sent?;
+                }
+                Some(msg) = stream.next(), if self.pending.is_empty() => {
                     self.handle_claude_msg(msg);
                 }
+                Some(event) = control_events.recv() => {
+                    self.handle_control_event(event);
+                }
                 Some(msg) = self.mailbox.recv() => {
-                    if msg.is_stop() {
-                        stream.stop();
+                    if let ClaudeCliMessage::Stop(done) = msg {
+                        if let Err(err) = stream.shutdown().await {
+                            warn!(?err, "Failed to shut down Claude cli");
+                        }
+                        let _ = done.send(());
                         return Ok(());
                     }
                     self.handle_msg(&stream, msg).await?;
@@ -96,13 +165,16 @@ impl ClaudeCli {
                 self.prompt_box.send(self.build_prompt(input))?;
             }
             ClaudeCliMessage::PermissionResp(msg) => {
-                if let Some(responder) = self.can_use_tool_responder.take() {
+                if let Some(responder) = self.permission.take_responder() {
                     let _ = responder.send(msg); // It cannot fail
                 }
             }
             ClaudeCliMessage::SetMode(mode) => {
                 debug!("set model: {mode:?}");
-                stream.set_permission_mode(mode)?;
+                let controller = stream.controller();
+                self.spawn_control_request("set_mode", async move {
+                    controller.set_permission_mode(mode).await
+                });
             }
             ClaudeCliMessage::GetInfo => {
                 let commands = stream.supported_commands()?;
@@ -112,17 +184,21 @@ impl ClaudeCli {
                 self.forward_claude_msg(info);
             }
             ClaudeCliMessage::CanUseTool(parms, responder) => {
-                self.forward_claude_msg(Arc::new(parms));
-                if self.can_use_tool_responder.is_some() {
-                    warn!("There's already a can_use_tool_responder!");
+                if !self.permission.on_request(&parms.request_id, responder) {
+                    debug!("can_use_tool request is already cancelled");
+                    return Ok(());
                 }
-                self.can_use_tool_responder = Some(responder);
+                self.forward_claude_msg(parms);
             }
-            ClaudeCliMessage::Stop => {
+            ClaudeCliMessage::Stop(..) => {
                 unreachable!()
             }
             ClaudeCliMessage::Interrupt => {
-                stream.interrupt().context("Interrupt error")?;
+                let controller = stream.controller();
+                self.spawn_control_request(
+                    "interrupt",
+                    async move { controller.interrupt().await },
+                );
             }
         }
 
@@ -137,20 +213,87 @@ impl ClaudeCli {
         }
     }
 
+    /// Waits for the CLI's answer without blocking the actor, then reports it to the client.
+    fn spawn_control_request<F>(&self, request: &'static str, fut: F)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        let cli_id = self.cli_id;
+        let manager_mailbox = self.manager_mailbox.clone();
+        tokio::spawn(async move {
+            let error = match fut.await {
+                Ok(()) => None,
+                Err(err) => {
+                    warn!(?err, request, "Control request failed");
+                    Some(format!("{err:#}"))
+                }
+            };
+            let _ = manager_mailbox
+                .send(ChatManagerMessage::CliMessage {
+                    cli_id,
+                    data: ServerMessageData::ControlResult(ControlResult {
+                        request: request.to_string(),
+                        error,
+                    }),
+                })
+                .await;
+        });
+    }
+
+    /// Queues the message for the manager. It waits in `pending` while the mailbox is full.
     fn forward_claude_msg<T>(&mut self, msg: T)
     where
         ServerMessageData: From<T>,
     {
         debug!("send claude msg to manager");
-        let _ = self.manager_mailbox.send(ChatManagerMessage::CliMessage {
-            cli_id: self.cli_id.clone(),
+        let msg = ChatManagerMessage::CliMessage {
+            cli_id: self.cli_id,
             data: ServerMessageData::from(msg),
-        });
+        };
+        if !self.pending.is_empty() {
+            self.pending.push_back(msg);
+            return;
+        }
+        match self.manager_mailbox.try_send(msg) {
+            Ok(()) => {}
+            Err(TrySendError::Full(msg)) => {
+                debug!("manager mailbox is full, pause reading the cli");
+                self.pending.push_back(msg);
+            }
+            Err(TrySendError::Closed(_)) => warn!("manager mailbox closed"),
+        }
     }
 
     fn handle_claude_msg(&mut self, msg: Result<SDKMessage, ClaudeStreamError>) {
         self.forward_claude_msg(msg);
     }
+
+    fn handle_control_event(&mut self, event: ControlEvent) {
+        debug!(?event, "handle control event");
+        match event {
+            ControlEvent::RequestCancelled {
+                request_id,
+                kind: ControlRequestKind::CanUseTool,
+            } => {
+                if self.permission.on_cancelled(&request_id) {
+                    self.forward_claude_msg(ServerMessageData::PermissionWithdrawn { request_id });
+                }
+            }
+            ControlEvent::RequestCancelled { .. } => {}
+        }
+    }
+}
+
+/// Sends the first pending message once the mailbox has room. Cancel safe.
+async fn send_pending(
+    mailbox: &ManagerMailbox,
+    pending: &mut VecDeque<ChatManagerMessage>,
+) -> Result<()> {
+    let permit = mailbox.reserve().await.context("manager mailbox closed")?;
+    if let Some(msg) = pending.pop_front() {
+        permit.send(msg);
+    }
+    Ok(())
 }
 
 impl From<Result<SDKMessage, ClaudeStreamError>> for ServerMessageData {
@@ -184,10 +327,11 @@ impl CanUseToolCallBack for CanUseTool {
         let (tx, rx) = oneshot::channel();
         self.ask_box
             .send(ClaudeCliMessage::CanUseTool(
-                CanUseToolParams {
+                Arc::new(CanUseToolParams {
+                    request_id: control_request_id().unwrap_or_default(),
                     tool_use,
                     suggestions,
-                },
+                }),
                 tx,
             ))
             .context("System error: client dead")?;
@@ -226,13 +370,33 @@ impl ClaudeCliMessage {
             ClaudeCliMessage::SetMode(..) => "SetMode",
             ClaudeCliMessage::GetInfo => "GetInfo",
             ClaudeCliMessage::CanUseTool(..) => "CanUseTool",
-            ClaudeCliMessage::Stop => "Stop",
+            ClaudeCliMessage::Stop(..) => "Stop",
             ClaudeCliMessage::Interrupt => "Interrupt",
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_permission() {
+        let mut permission = PendingPermission::default();
+        let (tx, _rx) = oneshot::channel();
+        assert!(permission.on_request("req_1", tx));
+        assert!(!permission.on_cancelled("req_0"));
+        assert!(permission.on_cancelled("req_1"));
+        assert!(permission.take_responder().is_none());
+
+        // The cancel arrives before the request.
+        assert!(!permission.on_cancelled("req_2"));
+        let (tx, _rx) = oneshot::channel();
+        assert!(!permission.on_request("req_2", tx));
+        assert!(permission.take_responder().is_none());
 
-    #[must_use]
-    pub fn is_stop(&self) -> bool {
-        matches!(self, Self::Stop)
+        let (tx, _rx) = oneshot::channel();
+        assert!(permission.on_request("req_3", tx));
+        assert!(permission.take_responder().is_some());
     }
 }
@@ -1,7 +1,7 @@
 use anyhow::Result;
-use tokio::sync::mpsc::unbounded_channel;
+use cc_sdk::channel;
 
-use crate::chat::{ChatManager, set_manager_mailbox};
+use crate::chat::{ChatManager, MANAGER_MAILBOX_CAPACITY, set_manager_mailbox};
 
 pub mod chat;
 pub mod claude;
@@ -22,7 +22,7 @@ pub async fn init() -> Result<()> {
     // 初始化提示词中心
     let prompt_hub = prompt_hub::init()?;
 
-    let (tx, rx) = unbounded_channel();
+    let (tx, rx) = channel::channel(Some(MANAGER_MAILBOX_CAPACITY));
     set_manager_mailbox(tx);
     let manager = ChatManager::new(rx, prompt_hub);
     manager.run().await;
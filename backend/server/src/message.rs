@@ -42,6 +42,11 @@ pub enum ServerMessageData {
     ServerError(ServerError),
     SystemInfo(Arc<ClaudeSystemInfo>),
     CanUseTool(Arc<CanUseToolParams>),
+    /// The pending `CanUseTool` request was cancelled by Claude, e.g. after an interrupt.
+    PermissionWithdrawn {
+        request_id: String,
+    },
+    ControlResult(ControlResult),
     ChatRemoved,
 }
 
@@ -50,8 +55,17 @@ pub struct ServerError {
     pub error: String,
 }
 
+/// Whether a `set_mode` or `interrupt` from the client took effect.
+#[derive(Serialize, Clone)]
+pub struct ControlResult {
+    pub request: String,
+    pub error: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct CanUseToolParams {
+    /// The control request ID, see [`cc_sdk::cli::control_request_id`].
+    pub request_id: String,
     pub tool_use: cc_sdk::types::ToolUseParams,
     pub suggestions: Option<Vec<cc_sdk::types::PermissionUpdate>>,
 }
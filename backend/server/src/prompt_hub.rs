@@ -60,11 +60,8 @@ impl PromptHub {
             }
             cc_sdk::types::UserContent::Vec(msgs) => {
                 for msg in msgs {
-                    match msg {
-                        cc_sdk::types::anthropic::ContentBlockParam::Text(block) => {
-                            self.add_prompt(block.text.clone(), work_dir.clone());
-                        }
-                        cc_sdk::types::anthropic::ContentBlockParam::Other(..) => {}
+                    if let cc_sdk::types::anthropic::ContentBlockParam::Text(block) = msg {
+                        self.add_prompt(block.text.clone(), work_dir.clone());
                     }
                 }
             }
@@ -127,7 +124,11 @@ impl PromptHub {
                 .context("Failed to create .niu-code directory")?;
         }
 
-        let storage_path = niu_code_dir.join("prompts.jsonl");
+        Self::with_storage_path(niu_code_dir.join("prompts.jsonl"))
+    }
+
+    /// A hub that keeps its history in `storage_path` instead of the config dir.
+    pub fn with_storage_path(storage_path: PathBuf) -> Result<Self> {
         info!("PromptHub storage path: {:?}", storage_path);
 
         let mut hub = Self {
@@ -2,7 +2,6 @@ use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
 use std::{
     ffi::OsStr,
     path::{Path, PathBuf},
@@ -12,7 +11,7 @@ use tokio::{
     fs::{File, read_dir},
     io::{AsyncBufReadExt, BufReader},
 };
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
     chat::{CacheMessage, MessageRecord},
@@ -190,16 +189,29 @@ pub fn log_to_message_record(log: ClaudeLogTypes) -> anyhow::Result<Option<Messa
         }
         ClaudeLogTypes::Assistant(log) => {
             let timestamp = parse_timestamp(&log.timestamp)?;
-            let msg: Value = serde_json::from_value(log.message.clone())?;
+            // A message that still doesn't fit the API shape is kept as it is, so the
+            // history the client replays has no holes.
+            let typed = match serde_json::from_value(log.message.clone()) {
+                Ok(msg) => SDKMessageTyped::Assistant(SDKAssistantMessage {
+                    uuid: log.uuid.clone(),
+                    message: msg,
+                    parent_tool_use_id: None,
+                }),
+                Err(err) => {
+                    warn!(%err, uuid = log.uuid, "Keep an assistant log that can't be parsed as raw JSON");
+                    SDKMessageTyped::Unknown(serde_json::json!({
+                        "type": "assistant",
+                        "uuid": log.uuid,
+                        "message": log.message,
+                        "parent_tool_use_id": null,
+                    }))
+                }
+            };
             Ok(Some(MessageRecord {
                 timestamp,
                 message: CacheMessage::Claude(Arc::new(SDKMessage {
                     session_id: log.session_id.clone(),
-                    typed: SDKMessageTyped::Assistant(SDKAssistantMessage {
-                        uuid: log.uuid.clone(),
-                        message: msg,
-                        parent_tool_use_id: None,
-                    }),
+                    typed,
                 })),
             }))
         }
@@ -250,6 +262,56 @@ mod tests {
         assert_eq!(result, expected_path);
     }
 
+    #[test]
+    fn test_keep_malformed_assistant_log() {
+        let log = |message: serde_json::Value| -> ClaudeLogTypes {
+            serde_json::from_value(serde_json::json!({
+                "type": "assistant",
+                "parentUuid": null,
+                "isSidechain": false,
+                "userType": "external",
+                "cwd": "/tmp",
+                "sessionId": "session",
+                "version": "2.0.0",
+                "gitBranch": null,
+                "message": message,
+                "uuid": "uuid",
+                "timestamp": "2025-10-01T00:00:00.000Z"
+            }))
+            .unwrap()
+        };
+
+        let typed = |log: ClaudeLogTypes| {
+            let record = log_to_message_record(log).unwrap().unwrap();
+            let CacheMessage::Claude(msg) = record.message else {
+                panic!("expected a claude message");
+            };
+            Arc::into_inner(msg).unwrap().typed
+        };
+
+        let synthetic = log(serde_json::json!({ "role": "assistant", "content": "synthetic" }));
+        assert!(matches!(typed(synthetic), SDKMessageTyped::Assistant(..)));
+
+        let malformed = log(serde_json::json!({ "role": "assistant", "content": 42 }));
+        let SDKMessageTyped::Unknown(raw) = typed(malformed) else {
+            panic!("expected the raw message");
+        };
+        assert_eq!(raw["type"], "assistant");
+        assert_eq!(raw["message"]["content"], 42);
+
+        let valid = log(serde_json::json!({
+            "id": "msg_01",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-5",
+            "content": [{ "type": "text", "text": "hi" }],
+            "stop_reason": null,
+            "stop_sequence": null,
+            "usage": { "input_tokens": 1, "output_tokens": 1 }
+        }));
+        assert!(matches!(typed(valid), SDKMessageTyped::Assistant(..)));
+    }
+
     #[tokio::test]
     async fn test_load_session_infos() {
         let input_path = Path::new("/data/home/sen/code/projects/ai/test-project");
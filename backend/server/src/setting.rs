@@ -3,6 +3,7 @@ use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 
 use arc_swap::ArcSwap;
+use cc_sdk::policy::Policy;
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -12,12 +13,25 @@ use tokio::time::sleep;
 #[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct Setting {
     claude_settings: Vec<ClaudeSetting>,
+    /// Decides routine tool calls automatically. Calls it leaves to `ask` go to the user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    permission_policy: Option<Policy>,
+    /// Stops a chat once its CLI process has cost more than this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_budget_usd: Option<f64>,
+    /// Stops a chat once its CLI process has used more tokens than this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_total_tokens: Option<u64>,
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct ClaudeSetting {
     pub name: String,
     pub setting: Value,
+    /// MCP servers attached to every session using this profile, in `--mcp-config` format.
+    /// Both the bare server map and the `{"mcpServers": {...}}` form of `.mcp.json` work.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mcp_servers: Option<Value>,
 }
 
 impl ClaudeSetting {
@@ -28,6 +42,10 @@ impl ClaudeSetting {
     pub fn setting(&self) -> &Value {
         &self.setting
     }
+
+    pub fn mcp_servers(&self) -> Option<&Value> {
+        self.mcp_servers.as_ref()
+    }
 }
 
 impl Setting {
@@ -40,6 +58,18 @@ impl Setting {
             .iter()
             .find(|setting| setting.name == name)
     }
+
+    pub fn permission_policy(&self) -> Option<&Policy> {
+        self.permission_policy.as_ref()
+    }
+
+    pub fn max_budget_usd(&self) -> Option<f64> {
+        self.max_budget_usd
+    }
+
+    pub fn max_total_tokens(&self) -> Option<u64> {
+        self.max_total_tokens
+    }
 }
 
 impl Default for Setting {
@@ -54,7 +84,11 @@ impl Default for Setting {
             claude_settings: vec![ClaudeSetting {
                 name: "ccr".to_string(),
                 setting: ccr,
+                mcp_servers: None,
             }],
+            permission_policy: None,
+            max_budget_usd: None,
+            max_total_tokens: None,
         }
     }
 }
@@ -3,15 +3,16 @@ use std::{ops::ControlFlow, sync::Arc};
 use actix_web::{Error, HttpRequest, HttpResponse, rt, web};
 use actix_ws::ProtocolError;
 use anyhow::{Context, Result, bail};
+use cc_sdk::channel::{self, QueueReceiver, QueueSender};
 use tokio::{
     select,
-    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    sync::{Notify, mpsc::error::TrySendError},
 };
 use tokio_stream::StreamExt;
 use tracing::{debug, warn};
 
 use crate::{
-    chat::{ChatManagerMessage, ConnId, WsWriter, get_manager_mailbox},
+    chat::{ChatManagerMessage, ConnId, ManagerMailbox, WsWriter, get_manager_mailbox},
     message::{ClientMessage, ServerMessage},
 };
 
@@ -27,7 +28,7 @@ pub async fn ws_handler(req: HttpRequest, stream: web::Payload) -> Result<HttpRe
 
     // start task but don't wait for it
     rt::spawn(async move {
-        let ws = WsEndpoint::new(stream, session);
+        let ws = WsEndpoint::new(stream, session).await;
         match ws {
             Ok(ws) => {
                 if let Err(err) = ws.run().await {
@@ -43,42 +44,65 @@ pub async fn ws_handler(req: HttpRequest, stream: web::Payload) -> Result<HttpRe
     Ok(res)
 }
 
+/// Messages waiting to be written to a client. A client that falls this far behind is
+/// disconnected, and the messages still queued for it are dropped. Registering the chat
+/// again only replays the messages sent after that, the full history needs a new
+/// `start_chat` of the session.
+const WS_MAILBOX_CAPACITY: usize = 1024;
+
 struct WsEndpoint {
     conn_id: ConnId,
-    mailbox: UnboundedReceiver<ServerMessage>,
-    manager_mailbox: UnboundedSender<ChatManagerMessage>,
+    mailbox: QueueReceiver<ServerMessage>,
+    overflow: Arc<Notify>,
+    manager_mailbox: ManagerMailbox,
     stream: AggregatedMessageStream,
     session: actix_ws::Session,
 }
 
 struct WsMessageAdapter {
-    tx: UnboundedSender<ServerMessage>,
+    tx: QueueSender<ServerMessage>,
+    overflow: Arc<Notify>,
 }
 
 impl WsWriter for WsMessageAdapter {
     fn send_msg(&self, msg: ServerMessage) -> Result<()> {
-        self.tx.send(msg).context("ws conection closed")?;
-        Ok(())
+        match self.tx.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.overflow.notify_one();
+                bail!("ws connection is lagging")
+            }
+            Err(TrySendError::Closed(_)) => bail!("ws conection closed"),
+        }
     }
 }
 
 impl WsEndpoint {
-    fn new(stream: AggregatedMessageStream, session: actix_ws::Session) -> anyhow::Result<Self> {
+    async fn new(
+        stream: AggregatedMessageStream,
+        session: actix_ws::Session,
+    ) -> anyhow::Result<Self> {
         debug!("build endpoint");
         let conn_id = ConnId::generate();
         let manager_mailbox = get_manager_mailbox();
-        let (tx, rx) = unbounded_channel();
+        let (tx, rx) = channel::channel(Some(WS_MAILBOX_CAPACITY));
+        let overflow = Arc::new(Notify::new());
 
         manager_mailbox
             .send(ChatManagerMessage::NewConnect {
                 conn_id: conn_id,
-                ws_writer: Arc::new(WsMessageAdapter { tx }),
+                ws_writer: Arc::new(WsMessageAdapter {
+                    tx,
+                    overflow: overflow.clone(),
+                }),
             })
+            .await
             .context("manager is dead")?;
 
         Ok(Self {
             conn_id,
             mailbox: rx,
+            overflow,
             manager_mailbox,
             stream,
             session,
@@ -97,7 +121,8 @@ impl WsEndpoint {
             .manager_mailbox
             .send(ChatManagerMessage::ConnectionClosed {
                 conn_id: self.conn_id,
-            });
+            })
+            .await;
 
         result
     }
@@ -113,6 +138,12 @@ impl WsEndpoint {
                 Some(msg) = self.mailbox.recv() => {
                     self.handle_msg(msg).await?;
                 }
+                _ = self.overflow.notified() => {
+                    let stats = self.mailbox.metrics().stats();
+                    warn!(conn_id = %self.conn_id, ?stats, "ws connection is lagging, closing it");
+                    let _ = self.session.clone().close(None).await;
+                    break;
+                }
             }
         }
 
@@ -134,7 +165,8 @@ impl WsEndpoint {
             self.manager_mailbox
                 .send(ChatManagerMessage::ConnectionClosed {
                     conn_id: self.conn_id,
-                })?;
+                })
+                .await?;
             return Ok(ControlFlow::Break(()));
         };
         let msg = msg.context("invalid client msg")?;
@@ -145,7 +177,7 @@ impl WsEndpoint {
                     conn_id: self.conn_id,
                     msg,
                 };
-                self.manager_mailbox.send(msg)?;
+                self.manager_mailbox.send(msg).await?;
             }
             AggregatedMessage::Ping(bytes) => self.session.pong(&bytes).await?,
             AggregatedMessage::Close(close_reason) => {
@@ -157,7 +189,7 @@ impl WsEndpoint {
                     conn_id: self.conn_id,
                     msg,
                 };
-                self.manager_mailbox.send(msg)?;
+                self.manager_mailbox.send(msg).await?;
             }
             AggregatedMessage::Pong(..) => {}
         }
@@ -84,6 +84,11 @@ impl BizError {
         err: None,
     };
 
+    const CHAT_STARTING: BizError = BizError {
+        code: "chat/starting",
+        err: None,
+    };
+
     fn with_context<T: Display>(mut self, context: T) -> BizError {
         self.err = match self.err {
             Some(err) => {
@@ -65,6 +65,7 @@ impl From<StartChatError> for BizError {
         match value {
             StartChatError::ChatNotRegistered => BizError::CHAT_NOT_REGISTGERD,
             StartChatError::ConfigNotFound(v) => BizError::CONFIG_NOT_FOUND.with_context(v),
+            StartChatError::ChatStarting => BizError::CHAT_STARTING,
         }
     }
 }
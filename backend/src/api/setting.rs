@@ -8,12 +8,20 @@ use crate::api::{ApiError, ApiOkResponse};
 #[derive(Deserialize, Serialize, Clone)]
 pub struct Setting {
     claude_settings: Vec<ClaudeSetting>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    permission_policy: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_budget_usd: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_total_tokens: Option<u64>,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ClaudeSetting {
     name: String,
     setting: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mcp_servers: Option<Value>,
 }
 
 pub async fn get_setting() -> Result<ApiOkResponse<Setting>, ApiError> {
@@ -26,10 +34,22 @@ pub async fn get_setting() -> Result<ApiOkResponse<Setting>, ApiError> {
         .map(|cs| ClaudeSetting {
             name: cs.name().to_string(),
             setting: cs.setting().clone(),
+            mcp_servers: cs.mcp_servers().cloned(),
         })
         .collect();
 
-    let setting = Setting { claude_settings };
+    let permission_policy = current_setting
+        .permission_policy()
+        .map(serde_json::to_value)
+        .transpose()
+        .context("Failed to serialize permission policy")?;
+
+    let setting = Setting {
+        claude_settings,
+        permission_policy,
+        max_budget_usd: current_setting.max_budget_usd(),
+        max_total_tokens: current_setting.max_total_tokens(),
+    };
 
     Ok(ApiOkResponse::new(setting))
 }
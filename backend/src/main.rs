@@ -1,11 +1,17 @@
+use std::time::Duration;
+
 use actix_web::{App, HttpServer};
 use anyhow::Result;
+use cc_sdk::runtime::{SUPPORTED_CLAUDE_VERSIONS, claude_version};
 use tokio::signal;
-use tracing::{Level, info};
+use tracing::{Level, info, warn};
 
 mod api;
 mod embedded;
 
+/// How long Ctrl-C waits for the Claude CLIs to stop before exiting anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(15);
+
 #[actix_web::main]
 async fn main() -> Result<()> {
     if version_command() {
@@ -16,13 +22,19 @@ async fn main() -> Result<()> {
     init()?;
 
     // Check if claude-code CLI is installed
-    check_claude_cli();
+    check_claude_cli().await;
 
     tokio::spawn(server::init());
 
     tokio::spawn(async {
         signal::ctrl_c().await.expect("failed to listen for event");
         info!("exiting...");
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, server::chat::shutdown())
+            .await
+            .is_err()
+        {
+            warn!("Claude CLIs didn't stop in {SHUTDOWN_TIMEOUT:?}, exiting anyway");
+        }
         std::process::exit(0);
     });
 
@@ -56,14 +68,23 @@ fn println_version() {
     println!("v{}", version);
 }
 
-/// Check if claude-code CLI is installed and accessible
-pub fn check_claude_cli() {
-    match which::which("claude") {
-        Ok(path) => {
-            info!("Found claude-code CLI at: {:?}", path);
+/// Check if claude-code CLI is installed, accessible and supported
+pub async fn check_claude_cli() {
+    match claude_version(&Default::default()).await {
+        Ok(version) if version.is_supported() => {
+            info!("Found claude-code CLI {}", version.version);
+        }
+        Ok(version) => {
+            tracing::warn!(
+                "⚠️  Claude Code CLI {} is not supported, expect >= {} and < {}",
+                version.version,
+                SUPPORTED_CLAUDE_VERSIONS.start,
+                SUPPORTED_CLAUDE_VERSIONS.end
+            );
+            tracing::warn!("⚠️  Chat sessions may break on protocol changes.");
         }
         Err(e) => {
-            tracing::warn!("⚠️  Claude Code CLI not found: {}", e);
+            tracing::warn!("⚠️  Claude Code CLI not usable: {:#}", e);
             tracing::warn!("⚠️  Please install it with: npm install -g @anthropic-ai/claude-code");
             tracing::warn!("⚠️  The application will start but chat functionality may not work.");
         }
@@ -60,6 +60,12 @@ export function useMessageHandler() {
       case 'can_use_tool':
         handleToolPermission(message)
         break
+      case 'permission_withdrawn':
+        chatManager.clearPendingToolUseRequest(message.chat_id, message.data.request_id)
+        break
+      case 'control_result':
+        handleControlResult(message)
+        break
       case 'server_error':
         handleErrorMessage(message)
         break
@@ -140,6 +146,21 @@ export function useMessageHandler() {
     }
   }
 
+  // 处理 set_mode / interrupt 的执行结果
+  async function handleControlResult(message: ServerMessage) {
+    if (message.data.kind !== 'control_result') return
+
+    if (message.data.error) {
+      const error = errorHandler.createClientError(
+        'SYSTEM_ERROR',
+        `${message.data.request} failed: ${message.data.error}`
+      )
+      await errorHandler.handle(error)
+    } else {
+      console.log('Control request succeeded:', message.data.request)
+    }
+  }
+
   // 处理错误消息
   async function handleErrorMessage(message: ServerMessage) {
     console.error('Server error:', message)
@@ -44,6 +44,12 @@ export function useResume() {
         data: { kind: 'can_use_tool', ...message.CanUseTool }
       }
       messageHandler.processMessage(serverMessage)
+    } else if ('PermissionWithdrawn' in message) {
+      const serverMessage: ServerMessage = {
+        chat_id: chatId,
+        data: { kind: 'permission_withdrawn', ...message.PermissionWithdrawn }
+      }
+      messageHandler.processMessage(serverMessage)
     }
   }
 
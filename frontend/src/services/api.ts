@@ -8,11 +8,15 @@ import { errorHandler } from './errorHandler'
 export interface ClaudeSetting {
   name: string
   setting: Record<string, unknown>
+  mcp_servers?: Record<string, unknown>
 }
 
 export interface Setting {
   claude_settings: ClaudeSetting[]
   default_config?: string // Name of the default config to use for new chats
+  permission_policy?: Record<string, unknown> // Rules that approve or deny tool calls automatically
+  max_budget_usd?: number // Stops a chat once it has cost more than this
+  max_total_tokens?: number // Stops a chat once it has used more tokens than this
 }
 
 /**
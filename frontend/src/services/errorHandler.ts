@@ -149,6 +149,12 @@ export class ErrorHandler {
           message: err || '未找到指定的 Claude 配置，请检查配置名称',
           severity: ErrorSeverity.WARNING
         }
+      case 'chat/starting':
+        return {
+          title: '会话启动中',
+          message: '会话正在启动，请稍候',
+          severity: ErrorSeverity.INFO
+        }
     }
   }
 
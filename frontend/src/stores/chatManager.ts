@@ -122,6 +122,16 @@ export const useChatManager = defineStore("chat-manager", {
       chat?.setPendingRequest(request)
     },
 
+    clearPendingToolUseRequest(chatId: string, requestId?: string) {
+      const chat = this.getChat(chatId)
+      if (!chat) return
+      // 只清除被撤回的那个请求
+      if (requestId && chat.pendingRequest?.request_id && chat.pendingRequest.request_id !== requestId) {
+        return
+      }
+      chat.pendingRequest = undefined
+    },
+
     getChat(chatId: string): ChatState | undefined {
       return this.chats.find((chat) => chat.chatId == chatId)
     },
@@ -1,7 +1,8 @@
 // === 后端错误码（与 backend/src/api.rs 一一对应）===
 export const BizErrorCode = [
   'chat/not-registerd',
-  'chat/config-not-found'
+  'chat/config-not-found',
+  'chat/starting'
 ] as const
 
 export const SystemErrorCode = [
@@ -31,6 +31,8 @@ export type ServerMessageData =
     | { kind: 'server_error', error: string }
     | { kind: 'system_info' } & ClaudeSystemInfo
     | { kind: 'can_use_tool' } & ToolPermissionRequest
+    | { kind: 'permission_withdrawn', request_id: string }
+    | { kind: 'control_result', request: string, error: string | null }
     | { kind: 'chat_removed' }
 
 export interface ClaudeSystemInfo {
@@ -39,6 +41,7 @@ export interface ClaudeSystemInfo {
 }
 
 export interface ToolPermissionRequest {
+    request_id?: string,
     suggestions?: PermissionUpdate[],
     tool_use: ToolUseParams
 }
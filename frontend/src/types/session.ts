@@ -24,3 +24,4 @@ export type CacheMessage =
   | { SystemInfo: ClaudeSystemInfo }
   | { CanUseTool: ToolPermissionRequest }
   | { PermissionResp: PermissionResult }
+  | { PermissionWithdrawn: { request_id: string } }